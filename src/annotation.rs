@@ -0,0 +1,187 @@
+use serde::{Serialize, Serializer};
+
+use std::fmt;
+use std::io::Write;
+
+use crate::felt::Felt;
+use crate::parser::LookupUsageState;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, clap::ValueEnum)]
+pub enum AnnotationFormat {
+    /// free-form lines, not covering all words
+    #[default]
+    Text,
+    /// JSON Lines, one record per word
+    Jsonl,
+}
+
+impl AnnotationFormat {
+    /// Extension of the annotation file.
+    pub fn get_extension(&self) -> &'static str {
+        match self {
+            AnnotationFormat::Text => "anno",
+            AnnotationFormat::Jsonl => "jsonl",
+        }
+    }
+}
+
+/// Interpretation of a word by the parser.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    DeploymentsLength,
+    DeployedAddress,
+    DeployedClassHash,
+    ConstructorCalldataLength,
+    ConstructorArgument,
+    ContractCount,
+    Address,
+    Packed,
+    ClassHash,
+    StorageKey,
+    StorageValue,
+    DeclarationCount,
+    DeclaredClassHash,
+    CompiledClassHash,
+    /// word of a section specific to the format
+    Other,
+    /// zero after the parsed data
+    Padding,
+}
+
+#[derive(Serialize)]
+pub struct Annotation<'a> {
+    /// index of the word in the parsed sequence
+    pub offset: usize,
+    #[serde(serialize_with = "serialize_hex")]
+    pub raw: &'a dyn fmt::LowerHex,
+    pub role: Role,
+    /// index of the enclosing contract update
+    pub contract: Option<usize>,
+    /// lookup usage state after interpreting the word
+    pub lookup_state: LookupUsageState,
+    /// address or storage key, with aliases resolved
+    pub resolved: Option<Felt>,
+}
+
+fn serialize_hex<S>(raw: &&dyn fmt::LowerHex, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    serializer.serialize_str(&format!("{:#x}", raw))
+}
+
+pub struct Annotator {
+    format: AnnotationFormat,
+    writer: Box<dyn Write>,
+}
+
+impl Annotator {
+    pub fn new(format: AnnotationFormat, writer: Box<dyn Write>) -> Self {
+        Self { format, writer }
+    }
+
+    /// Annotator discarding all annotations.
+    pub fn none() -> Self {
+        Self::new(AnnotationFormat::Text, Box::new(std::io::empty()))
+    }
+
+    /// Writes `annotation`, or (in the text format) just `text`, if
+    /// any.
+    pub fn annotate(
+        &mut self,
+        annotation: &Annotation,
+        text: Option<fmt::Arguments>,
+    ) -> eyre::Result<()> {
+        match self.format {
+            AnnotationFormat::Text => {
+                if let Some(text) = text {
+                    writeln!(self.writer, "{}", text)?;
+                }
+            }
+            AnnotationFormat::Jsonl => {
+                serde_json::to_writer(&mut self.writer, annotation)?;
+                writeln!(self.writer)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use num_bigint::BigUint;
+    use serde_json::Value;
+
+    use std::io::Write;
+    use std::sync::{Arc, Mutex};
+
+    use super::{AnnotationFormat, Annotator};
+    use crate::da_format::FormatVersion;
+    use crate::felt::Felt;
+    use crate::lookup::START_INDEX;
+    use crate::parser::StateUpdateParser;
+    use crate::test_util::primed_lookup;
+
+    #[derive(Clone, Default)]
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn jsonl() {
+        let lookup = primed_lookup();
+
+        // 0x1 contract with one update, then an aliased contract with
+        // an aliased key, no declarations and padding
+        let words: Vec<u64> = vec![2, 1, 1, 100, 5, START_INDEX, 1, START_INDEX, 7, 0, 0];
+        let seq = words.into_iter().map(BigUint::from);
+        let buf = SharedBuf::default();
+        let annotator = Annotator::new(AnnotationFormat::Jsonl, Box::new(buf.clone()));
+        let format = FormatVersion::V0_13_1.get_format();
+        let state_diff = StateUpdateParser::parse(seq, format, lookup, annotator).unwrap();
+        assert_eq!(state_diff.contract_updates[1].address, Felt::from(0xabc));
+
+        let raw = String::from_utf8(buf.0.lock().unwrap().clone()).unwrap();
+        let records: Vec<Value> = raw
+            .lines()
+            .map(|ln| serde_json::from_str(ln).unwrap())
+            .collect();
+        assert_eq!(records.len(), 11);
+        assert_eq!(
+            records[3],
+            serde_json::json!({
+                "offset": 3,
+                "raw": "0x64",
+                "role": "storage_key",
+                "contract": 0,
+                "lookup_state": "One",
+                "resolved": "0x64",
+            })
+        );
+        assert_eq!(
+            records[5],
+            serde_json::json!({
+                "offset": 5,
+                "raw": "0x80",
+                "role": "address",
+                "contract": 1,
+                "lookup_state": "On",
+                "resolved": "0xabc",
+            })
+        );
+        assert_eq!(records[7]["resolved"], "0xabc");
+        assert_eq!(records[9]["role"], "declaration_count");
+        assert_eq!(records[9]["contract"], Value::Null);
+        assert_eq!(records[10]["role"], "padding");
+    }
+}
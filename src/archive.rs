@@ -0,0 +1,178 @@
+use alloy::{
+    primitives::FixedBytes,
+    rpc::types::{Log, Transaction},
+};
+use num_bigint::BigUint;
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::dump::{uncond_dump, uncond_load};
+use crate::error::{Error, Result};
+
+const LOG_SUFFIX: &str = ".log.json";
+const TX_SUFFIX: &str = ".tx.json";
+const PAGES_EXTENSION: &str = "pages";
+
+/// Saves (and loads) the Ethereum data needed to process state
+/// updates - logs, transactions and calldata memory pages - so that
+/// (together with saved blobs) they can be reprocessed offline.
+pub struct Archive {
+    cache_dir: PathBuf,
+}
+
+impl Archive {
+    pub fn new(cache_dir: PathBuf) -> Self {
+        Self { cache_dir }
+    }
+
+    pub fn save_log(&self, log: &Log) -> Result<()> {
+        let (Some(block_no), Some(log_index)) = (log.block_number, log.log_index) else {
+            return Err(Error::Usage("saved log must be mined".to_string()));
+        };
+        let name = format!("{}-{}{}", block_no, log_index, LOG_SUFFIX);
+        let j = serde_json::to_string(log).map_err(io::Error::from)?;
+        fs::write(self.cache_dir.join(name), j)?;
+        Ok(())
+    }
+
+    /// Returns saved logs from blocks `from_block` to `to_block`
+    /// (inclusive), in chain order.
+    pub fn load_logs(&self, from_block: u64, to_block: u64) -> Result<Vec<Log>> {
+        let mut entries = self
+            .list_logs()?
+            .into_iter()
+            .filter(|(pos, _)| (from_block <= pos.0) && (pos.0 <= to_block))
+            .collect::<Vec<_>>();
+        entries.sort_by_key(|(pos, _)| *pos);
+
+        let mut logs = Vec::with_capacity(entries.len());
+        for (_, path) in entries {
+            let raw = fs::read_to_string(&path)?;
+            let log = serde_json::from_str(&raw).map_err(|e| invalid_data(&path, e))?;
+            logs.push(log);
+        }
+
+        Ok(logs)
+    }
+
+    /// Returns the highest block number with a saved log.
+    pub fn last_block(&self) -> Result<Option<u64>> {
+        let last = self.list_logs()?.into_iter().map(|(pos, _)| pos.0).max();
+        Ok(last)
+    }
+
+    /// Removes saved logs from block `block_no` onwards.
+    pub fn remove_logs_from(&self, block_no: u64) -> Result<()> {
+        for (pos, path) in self.list_logs()? {
+            if pos.0 >= block_no {
+                fs::remove_file(path)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn save_tx(&self, tx: &Transaction) -> Result<()> {
+        let j = serde_json::to_string(tx).map_err(io::Error::from)?;
+        fs::write(self.make_tx_path(tx.inner.tx_hash()), j)?;
+        Ok(())
+    }
+
+    pub fn load_tx(&self, tx_hash: &FixedBytes<32>) -> Result<Transaction> {
+        let path = self.make_tx_path(tx_hash);
+        let raw = fs::read_to_string(&path)
+            .map_err(|e| io::Error::new(e.kind(), format!("tx {} not saved: {}", tx_hash, e)))?;
+        let tx = serde_json::from_str(&raw).map_err(|e| invalid_data(&path, e))?;
+        Ok(tx)
+    }
+
+    /// Saves the memory pages of calldata state update `tx_hash`,
+    /// concatenated.
+    pub fn save_pages(&self, tx_hash: &FixedBytes<32>, seq: &[BigUint]) -> Result<()> {
+        let path = self.make_pages_path(tx_hash);
+        uncond_dump(seq, &path).map_err(|e| io::Error::other(format!("{:?}: {:#}", path, e)))?;
+        Ok(())
+    }
+
+    pub fn load_pages(&self, tx_hash: &FixedBytes<32>) -> Result<Vec<BigUint>> {
+        let path = self.make_pages_path(tx_hash);
+        if !path.exists() {
+            let message = format!("memory pages of tx {} not saved", tx_hash);
+            return Err(io::Error::new(io::ErrorKind::NotFound, message).into());
+        }
+
+        let seq = uncond_load(&path).map_err(|e| invalid_data(&path, e))?;
+        Ok(seq)
+    }
+
+    fn make_tx_path(&self, tx_hash: &FixedBytes<32>) -> PathBuf {
+        let name = format!("{}{}", tx_hash, TX_SUFFIX);
+        self.cache_dir.join(name)
+    }
+
+    fn make_pages_path(&self, tx_hash: &FixedBytes<32>) -> PathBuf {
+        let name = format!("{}.{}", tx_hash, PAGES_EXTENSION);
+        self.cache_dir.join(name)
+    }
+
+    fn list_logs(&self) -> Result<Vec<((u64, u64), PathBuf)>> {
+        let mask = self.cache_dir.join(format!("*{}", LOG_SUFFIX));
+        let mask_str = mask
+            .to_str()
+            .ok_or_else(|| io::Error::other("invalid cache dir"))?;
+        let mut entries = Vec::new();
+        for raw_entry in glob::glob(mask_str).map_err(Error::from_glob)? {
+            let entry = raw_entry.map_err(Error::from_glob)?;
+            match parse_log_name(&entry) {
+                Some(pos) => entries.push((pos, entry)),
+                None => tracing::warn!("ignoring {:?}", entry),
+            }
+        }
+
+        Ok(entries)
+    }
+}
+
+// saved files are only expected to be unreadable when edited (or
+// truncated)
+fn invalid_data(path: &Path, err: impl std::fmt::Display) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("{:?}: {:#}", path, err))
+}
+
+fn parse_log_name(path: &Path) -> Option<(u64, u64)> {
+    let name = path.file_name()?.to_str()?;
+    let stem = name.strip_suffix(LOG_SUFFIX)?;
+    let (block, index) = stem.split_once('-')?;
+    Some((block.parse().ok()?, index.parse().ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use alloy::primitives::FixedBytes;
+
+    use std::io;
+    use std::path::PathBuf;
+
+    use super::{Archive, parse_log_name};
+    use crate::error::Error;
+
+    #[test]
+    fn log_name() {
+        let path = PathBuf::from("cache/19433007-271.log.json");
+        assert_eq!(parse_log_name(&path), Some((19433007, 271)));
+        let bad = PathBuf::from("cache/19433007.log.json");
+        assert_eq!(parse_log_name(&bad), None);
+    }
+
+    #[test]
+    fn not_saved() {
+        let archive = Archive::new(std::env::temp_dir());
+        let tx_hash = FixedBytes::<32>::repeat_byte(0xab);
+        let err = archive.load_pages(&tx_hash).unwrap_err();
+        assert!(matches!(&err, Error::Io(e) if e.kind() == io::ErrorKind::NotFound));
+        let err = archive.load_tx(&tx_hash).unwrap_err();
+        assert!(matches!(&err, Error::Io(e) if e.kind() == io::ErrorKind::NotFound));
+    }
+}
@@ -1,29 +1,101 @@
+use alloy::{
+    eips::eip4844::{env_settings::EnvKzgSettings, kzg_to_versioned_hash},
+    primitives::FixedBytes,
+};
 use eyre::anyhow;
 use num_bigint::BigUint;
-use num_traits::{Num, ToPrimitive};
+use num_traits::ToPrimitive;
+
+use crate::error::{Error, Result};
 
 pub const FIELD_ELEMENTS_PER_BLOB: usize = 4096;
 
-pub fn parse_str_to_blob_data(contents: &str) -> eyre::Result<Vec<BigUint>> {
+const BYTES_PER_FIELD_ELEMENT: usize = 32;
+
+const BYTES_PER_COMMITMENT: usize = 48;
+
+/// BLS12-381 scalar field modulus, big-endian
+const BLS_MODULUS: [u8; BYTES_PER_FIELD_ELEMENT] = [
+    0x73, 0xed, 0xa7, 0x53, 0x29, 0x9d, 0x7d, 0x48, 0x33, 0x39, 0xd8, 0x08, 0x09, 0xa1, 0xd8, 0x05,
+    0x53, 0xbd, 0xa4, 0x02, 0xff, 0xfe, 0x5b, 0xfe, 0xff, 0xff, 0xff, 0xff, 0x00, 0x00, 0x00, 0x01,
+];
+
+pub fn parse_str_to_blob_bytes(contents: &str) -> eyre::Result<Vec<u8>> {
     let trimmed = contents.trim();
     let blob_hex = trimmed.strip_prefix("0x").unwrap_or(trimmed);
     // blobs have fixed size
-    if blob_hex.len() != FIELD_ELEMENTS_PER_BLOB * 64 {
+    if blob_hex.len() != FIELD_ELEMENTS_PER_BLOB * BYTES_PER_FIELD_ELEMENT * 2 {
         return Err(anyhow!(
             "expected {} hex chars, got {}",
-            FIELD_ELEMENTS_PER_BLOB * 64,
+            FIELD_ELEMENTS_PER_BLOB * BYTES_PER_FIELD_ELEMENT * 2,
             blob_hex.len()
         ));
     }
 
-    let mut data = Vec::new();
-    for i in 0..FIELD_ELEMENTS_PER_BLOB {
-        let d = BigUint::from_str_radix(&blob_hex[i * 64..(i + 1) * 64], 16)
-            .map_err(|_| anyhow!("invalid hex integer"))?;
-        data.push(d);
+    hex::decode(blob_hex).map_err(|_| anyhow!("invalid hex integer"))
+}
+
+pub fn blob_bytes_to_data(blob: &[u8]) -> Vec<BigUint> {
+    blob.chunks(BYTES_PER_FIELD_ELEMENT)
+        .map(BigUint::from_bytes_be)
+        .collect()
+}
+
+/// Inverse of `blob_bytes_to_data`, for canonical elements.
+pub fn blob_data_to_bytes(data: &[BigUint]) -> Vec<u8> {
+    let mut blob = Vec::with_capacity(data.len() * BYTES_PER_FIELD_ELEMENT);
+    for el in data.iter() {
+        let raw = el.to_bytes_be();
+        blob.resize(blob.len() + BYTES_PER_FIELD_ELEMENT - raw.len(), 0);
+        blob.extend_from_slice(&raw);
+    }
+
+    blob
+}
+
+/// Checks that `blob` consists of canonical field elements and
+/// matches both the `commitment` claimed by its source and
+/// `versioned_hash` from the transaction carrying it.
+pub fn verify_blob(blob: &[u8], commitment: &[u8], versioned_hash: &FixedBytes<32>) -> Result<()> {
+    let fail = |message: String| Error::BlobIntegrity {
+        versioned_hash: *versioned_hash,
+        message,
+    };
+    if commitment.len() != BYTES_PER_COMMITMENT {
+        return Err(fail(format!("commitment has {} bytes", commitment.len())));
+    }
+
+    // lexicographic comparison of big-endian byte arrays orders them
+    // numerically
+    for (i, el) in blob.chunks(BYTES_PER_FIELD_ELEMENT).enumerate() {
+        if el >= BLS_MODULUS.as_slice() {
+            return Err(fail(format!("blob element {} not below BLS modulus", i)));
+        }
+    }
+
+    let kzg_blob =
+        c_kzg::Blob::from_bytes(blob).map_err(|e| fail(format!("invalid blob: {:?}", e)))?;
+    let computed =
+        c_kzg::KzgCommitment::blob_to_kzg_commitment(&kzg_blob, EnvKzgSettings::Default.get())
+            .map_err(|e| fail(format!("can't compute commitment: {:?}", e)))?;
+    let computed_bytes = computed.to_bytes();
+    if computed_bytes.as_slice() != commitment {
+        return Err(fail(format!(
+            "blob doesn't match commitment 0x{}",
+            hex::encode(commitment)
+        )));
+    }
+
+    let computed_hash = kzg_to_versioned_hash(computed_bytes.as_slice());
+    if computed_hash != *versioned_hash {
+        return Err(fail(format!(
+            "commitment 0x{} has versioned hash {}",
+            hex::encode(commitment),
+            computed_hash
+        )));
     }
 
-    Ok(data)
+    Ok(())
 }
 
 pub fn parse_usize(value: &BigUint) -> eyre::Result<usize> {
@@ -31,3 +103,48 @@ pub fn parse_usize(value: &BigUint) -> eyre::Result<usize> {
         .to_usize()
         .ok_or_else(|| anyhow!("Value exceeds usize::MAX"))
 }
+
+#[cfg(test)]
+mod tests {
+    use alloy::{eips::eip4844::kzg_to_versioned_hash, primitives::FixedBytes};
+
+    use super::{BLS_MODULUS, FIELD_ELEMENTS_PER_BLOB, verify_blob};
+    use crate::error::Error;
+
+    fn zero_commitment() -> Vec<u8> {
+        let mut commitment = vec![0u8; 48];
+        commitment[0] = 0xc0;
+        commitment
+    }
+
+    #[test]
+    fn zero_blob() {
+        let blob = vec![0u8; FIELD_ELEMENTS_PER_BLOB * 32];
+        let commitment = zero_commitment();
+        let versioned_hash = kzg_to_versioned_hash(&commitment);
+        verify_blob(&blob, &commitment, &versioned_hash).unwrap();
+        assert!(verify_blob(&blob, &commitment, &FixedBytes::<32>::ZERO).is_err());
+    }
+
+    #[test]
+    fn wrong_commitment() {
+        let mut blob = vec![0u8; FIELD_ELEMENTS_PER_BLOB * 32];
+        blob[31] = 1;
+        let commitment = zero_commitment();
+        let versioned_hash = kzg_to_versioned_hash(&commitment);
+        assert!(verify_blob(&blob, &commitment, &versioned_hash).is_err());
+    }
+
+    #[test]
+    fn non_canonical_element() {
+        let mut blob = vec![0u8; FIELD_ELEMENTS_PER_BLOB * 32];
+        blob[32..64].copy_from_slice(&BLS_MODULUS);
+        let commitment = zero_commitment();
+        let versioned_hash = kzg_to_versioned_hash(&commitment);
+        let err = verify_blob(&blob, &commitment, &versioned_hash).unwrap_err();
+        assert!(matches!(
+            err,
+            Error::BlobIntegrity { message, .. } if message == "blob element 1 not below BLS modulus"
+        ));
+    }
+}
@@ -0,0 +1,314 @@
+use num_bigint::BigUint;
+
+use std::ops::{Add, Mul, Neg, Sub};
+
+/// BLS12-381 scalar field modulus, little-endian 64-bit limbs
+const MODULUS: [u64; 4] = [
+    0xffffffff00000001,
+    0x53bda402fffe5bfe,
+    0x3339d80809a1d805,
+    0x73eda753299d7d48,
+];
+
+/// -MODULUS^-1 mod 2^64
+const INV: u64 = 0xfffffffeffffffff;
+
+/// 2^256 mod MODULUS, i.e. 1 in Montgomery form
+const R: [u64; 4] = [
+    0x00000001fffffffe,
+    0x5884b7fa00034802,
+    0x998c4fefecbc4ff5,
+    0x1824b159acc5056f,
+];
+
+/// 2^512 mod MODULUS, for conversion into Montgomery form
+const R2: [u64; 4] = [
+    0xc999e990f3f29c6d,
+    0x2b6cedcb87925c23,
+    0x05d314967254398f,
+    0x0748d9d99f59ff11,
+];
+
+#[inline(always)]
+fn adc(a: u64, b: u64, carry: u64) -> (u64, u64) {
+    let t = (a as u128) + (b as u128) + (carry as u128);
+    (t as u64, (t >> 64) as u64)
+}
+
+#[inline(always)]
+fn sbb(a: u64, b: u64, borrow: u64) -> (u64, u64) {
+    let t = (a as u128).wrapping_sub((b as u128) + ((borrow >> 63) as u128));
+    (t as u64, (t >> 64) as u64)
+}
+
+#[inline(always)]
+fn mac(a: u64, b: u64, c: u64, carry: u64) -> (u64, u64) {
+    let t = (a as u128) + (b as u128) * (c as u128) + (carry as u128);
+    (t as u64, (t >> 64) as u64)
+}
+
+/// Element of the BLS12-381 scalar field (which blobs consist of),
+/// kept in Montgomery form in fixed-size limbs, so that arithmetic
+/// doesn't allocate.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Fr([u64; 4]);
+
+impl Fr {
+    pub const ZERO: Fr = Fr([0; 4]);
+    pub const ONE: Fr = Fr(R);
+
+    pub fn from_u64(n: u64) -> Self {
+        Fr([n, 0, 0, 0]) * Fr(R2)
+    }
+
+    /// Converts a big-endian integer, returning `None` if it isn't
+    /// below the modulus.
+    pub fn from_be_bytes(bytes: &[u8; 32]) -> Option<Self> {
+        let mut limbs = [0u64; 4];
+        for (i, chunk) in bytes.rchunks(8).enumerate() {
+            limbs[i] = u64::from_be_bytes(chunk.try_into().unwrap());
+        }
+
+        let (_, borrow) = sub_limbs(&limbs, &MODULUS);
+        if borrow == 0 {
+            None
+        } else {
+            Some(Fr(limbs) * Fr(R2))
+        }
+    }
+
+    pub fn to_be_bytes(&self) -> [u8; 32] {
+        let limbs = self.to_canonical();
+        let mut bytes = [0u8; 32];
+        for (i, chunk) in bytes.rchunks_mut(8).enumerate() {
+            chunk.copy_from_slice(&limbs[i].to_be_bytes());
+        }
+
+        bytes
+    }
+
+    pub fn from_biguint(n: &BigUint) -> Option<Self> {
+        let raw = n.to_bytes_be();
+        if raw.len() > 32 {
+            return None;
+        }
+
+        let mut bytes = [0u8; 32];
+        bytes[32 - raw.len()..].copy_from_slice(&raw);
+        Self::from_be_bytes(&bytes)
+    }
+
+    /// Converts an integer of any size, reducing it modulo the
+    /// modulus.
+    pub fn from_biguint_mod(n: &BigUint) -> Self {
+        Self::from_biguint(n).unwrap_or_else(|| {
+            let modulus = BigUint::from_slice(
+                &MODULUS
+                    .iter()
+                    .flat_map(|limb| [*limb as u32, (limb >> 32) as u32])
+                    .collect::<Vec<_>>(),
+            );
+            Self::from_biguint(&(n % modulus)).unwrap()
+        })
+    }
+
+    pub fn to_biguint(&self) -> BigUint {
+        BigUint::from_bytes_be(&self.to_be_bytes())
+    }
+
+    pub fn square(&self) -> Self {
+        *self * *self
+    }
+
+    pub fn pow(&self, exponent: u64) -> Self {
+        let mut res = Fr::ONE;
+        for i in (0..64).rev() {
+            res = res.square();
+            if (exponent >> i) & 1 == 1 {
+                res = res * *self;
+            }
+        }
+
+        res
+    }
+
+    /// Multiplicative inverse (by Fermat's little theorem); zero for
+    /// zero.
+    pub fn invert(&self) -> Self {
+        // MODULUS - 2
+        let exponent = [MODULUS[0] - 2, MODULUS[1], MODULUS[2], MODULUS[3]];
+        let mut res = Fr::ONE;
+        for limb in exponent.iter().rev() {
+            for i in (0..64).rev() {
+                res = res.square();
+                if (limb >> i) & 1 == 1 {
+                    res = res * *self;
+                }
+            }
+        }
+
+        res
+    }
+
+    fn to_canonical(self) -> [u64; 4] {
+        let l = self.0;
+        Self::reduce([l[0], l[1], l[2], l[3], 0, 0, 0, 0]).0
+    }
+
+    // Montgomery reduction of a product; the result is below the
+    // modulus
+    fn reduce(t: [u64; 8]) -> Self {
+        let mut t = t;
+        let mut carry2 = 0;
+        for i in 0..4 {
+            let k = t[i].wrapping_mul(INV);
+            let mut carry = 0;
+            for j in 0..4 {
+                (t[i + j], carry) = mac(t[i + j], k, MODULUS[j], carry);
+            }
+            (t[i + 4], carry2) = adc(t[i + 4], carry2, carry);
+        }
+
+        // the modulus is below 2^255, so the result fits in 4 limbs
+        // and is below twice the modulus
+        Fr([t[4], t[5], t[6], t[7]]).sub_modulus()
+    }
+
+    fn sub_modulus(self) -> Self {
+        let (diff, borrow) = sub_limbs(&self.0, &MODULUS);
+        if borrow == 0 { Fr(diff) } else { self }
+    }
+}
+
+fn sub_limbs(a: &[u64; 4], b: &[u64; 4]) -> ([u64; 4], u64) {
+    let mut diff = [0u64; 4];
+    let mut borrow = 0;
+    for i in 0..4 {
+        (diff[i], borrow) = sbb(a[i], b[i], borrow);
+    }
+
+    (diff, borrow)
+}
+
+impl Add for Fr {
+    type Output = Fr;
+
+    fn add(self, rhs: Fr) -> Fr {
+        let mut sum = [0u64; 4];
+        let mut carry = 0;
+        for (i, limb) in sum.iter_mut().enumerate() {
+            (*limb, carry) = adc(self.0[i], rhs.0[i], carry);
+        }
+
+        // no carry, since the modulus is below 2^255
+        Fr(sum).sub_modulus()
+    }
+}
+
+impl Sub for Fr {
+    type Output = Fr;
+
+    fn sub(self, rhs: Fr) -> Fr {
+        let (diff, borrow) = sub_limbs(&self.0, &rhs.0);
+        if borrow == 0 {
+            return Fr(diff);
+        }
+
+        let mut res = [0u64; 4];
+        let mut carry = 0;
+        for (i, limb) in res.iter_mut().enumerate() {
+            (*limb, carry) = adc(diff[i], MODULUS[i], carry);
+        }
+
+        Fr(res)
+    }
+}
+
+impl Neg for Fr {
+    type Output = Fr;
+
+    fn neg(self) -> Fr {
+        Fr::ZERO - self
+    }
+}
+
+impl Mul for Fr {
+    type Output = Fr;
+
+    fn mul(self, rhs: Fr) -> Fr {
+        let mut t = [0u64; 8];
+        for i in 0..4 {
+            let mut carry = 0;
+            for j in 0..4 {
+                (t[i + j], carry) = mac(t[i + j], self.0[i], rhs.0[j], carry);
+            }
+            t[i + 4] = carry;
+        }
+
+        Fr::reduce(t)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use num_bigint::BigUint;
+    use num_traits::{One, Zero};
+
+    use std::str::FromStr;
+
+    use super::Fr;
+
+    fn modulus() -> BigUint {
+        BigUint::from_str(
+            "52435875175126190479447740508185965837690552500527637822603658699938581184513",
+        )
+        .unwrap()
+    }
+
+    fn samples() -> Vec<BigUint> {
+        let p = modulus();
+        vec![
+            BigUint::zero(),
+            BigUint::one(),
+            BigUint::from(0xdeadbeefu32),
+            &p - 1u32,
+            &p >> 1,
+            BigUint::from_str(
+                "39033254847818212395286706435128746857159659164139250548781411570340225835782",
+            )
+            .unwrap(),
+        ]
+    }
+
+    #[test]
+    fn conversion() {
+        for n in samples() {
+            assert_eq!(Fr::from_biguint(&n).unwrap().to_biguint(), n);
+        }
+        assert!(Fr::from_biguint(&modulus()).is_none());
+        assert_eq!(Fr::from_biguint_mod(&(modulus() + 3u32)), Fr::from_u64(3));
+        assert_eq!(Fr::from_u64(7).to_biguint(), BigUint::from(7u32));
+        assert_eq!(Fr::ONE, Fr::from_u64(1));
+    }
+
+    #[test]
+    fn arithmetic() {
+        let p = modulus();
+        for a in samples() {
+            for b in samples() {
+                let x = Fr::from_biguint(&a).unwrap();
+                let y = Fr::from_biguint(&b).unwrap();
+                assert_eq!((x + y).to_biguint(), (&a + &b) % &p);
+                assert_eq!((x - y).to_biguint(), (&p + &a - &b) % &p);
+                assert_eq!((x * y).to_biguint(), (&a * &b) % &p);
+            }
+
+            let x = Fr::from_biguint(&a).unwrap();
+            assert_eq!((-x + x), Fr::ZERO);
+            if !a.is_zero() {
+                assert_eq!(x * x.invert(), Fr::ONE);
+            }
+            assert_eq!(x.pow(5).to_biguint(), a.modpow(&BigUint::from(5u32), &p));
+        }
+    }
+}
@@ -0,0 +1,382 @@
+use redb::{
+    Database, ReadTransaction, ReadableTable, TableDefinition, TableError, WriteTransaction,
+};
+
+use std::sync::Arc;
+
+use crate::error::{Error, Result};
+use crate::felt::Felt;
+use crate::state_diff::{ClassChange, StateDiff};
+
+/// Position of a state update: Ethereum block number and an index
+/// within the block (log index, or repeat count of dumped updates).
+pub type UpdatePosition = (u64, u64);
+
+// keyed by (contract address, update position)
+const CLASS_TABLE: TableDefinition<(&[u8] /* Felt */, u64, u64), &[u8] /* Felt */> =
+    TableDefinition::new("contract_class");
+
+// keys of CLASS_TABLE in update order, for rollback
+const CLASS_CHANGE_TABLE: TableDefinition<(u64, u64, &[u8] /* Felt */), ()> =
+    TableDefinition::new("contract_class_change");
+
+// position of the first processed update, and whether it started at
+// the network's genesis
+const ORIGIN_TABLE: TableDefinition<(), (u64, u64, bool)> =
+    TableDefinition::new("contract_class_origin");
+
+/// Persistent index of contracts' class hashes, kept as they change
+/// by state updates, so that deployments can be told apart from
+/// class replacements. The distinction is only possible when all
+/// updates since the network's genesis have been processed - before
+/// that, replacements of classes of contracts deployed earlier look
+/// like deployments. The index therefore records whether its first
+/// update started at genesis, and otherwise leaves class changes
+/// unclassified.
+pub struct ClassIndex {
+    // changes of the current update, persisted by commit
+    pending: Vec<(Felt, Felt)>,
+    // set when the current update is the first one
+    pending_origin: Option<bool>,
+    cur_position: Option<UpdatePosition>,
+    db: Arc<Database>,
+}
+
+impl ClassIndex {
+    pub fn new(db: Arc<Database>) -> Self {
+        Self {
+            pending: Vec::new(),
+            pending_origin: None,
+            cur_position: None,
+            db,
+        }
+    }
+
+    /// Starts processing an update at `position`, discarding any
+    /// uncommitted changes.
+    pub fn set_position(&mut self, position: UpdatePosition) {
+        self.pending.clear();
+        self.pending_origin = None;
+        self.cur_position = Some(position);
+    }
+
+    /// Returns whether the index covers all updates since the
+    /// network's genesis, or `None` if no update has been processed.
+    pub fn is_from_genesis(&self) -> Result<Option<bool>> {
+        let opt_origin = self.get_origin()?;
+        Ok(opt_origin.map(|(_, from_genesis)| from_genesis))
+    }
+
+    /// Returns the class hash of the contract at `address` before
+    /// the update at `before`, or the latest one when `before` is
+    /// `None`.
+    pub fn get(&self, address: &Felt, before: Option<UpdatePosition>) -> Result<Option<Felt>> {
+        let txn = self.db.begin_read()?;
+        let before = before.unwrap_or((u64::MAX, u64::MAX));
+        let opt_last = read_class_hash(&txn, address, before)?;
+        Ok(opt_last.map(|(_, class_hash)| class_hash))
+    }
+
+    /// Classifies class hash changes of `state_diff` (which must be
+    /// parsed from the update at the current position) and keeps
+    /// them to be persisted by `commit`. `at_genesis` tells whether
+    /// the update starts at the network's genesis - which only
+    /// matters for the first update, deciding whether this and all
+    /// later updates are classified.
+    pub fn apply(&mut self, state_diff: &mut StateDiff, at_genesis: bool) -> Result<()> {
+        let position = self.get_cur_position()?;
+        let from_genesis = match self.get_origin()? {
+            Some((origin, from_genesis)) if origin < position => from_genesis,
+            _ => {
+                self.pending_origin = Some(at_genesis);
+                at_genesis
+            }
+        };
+        for cu in state_diff.contract_updates.iter_mut() {
+            if let Some(class_hash) = cu.new_class_hash {
+                if from_genesis {
+                    let class_change = match self.get(&cu.address, Some(position))? {
+                        Some(previous_class_hash) => ClassChange::Replaced {
+                            previous_class_hash,
+                        },
+                        None => ClassChange::Deployed,
+                    };
+                    cu.class_change = Some(class_change);
+                }
+                self.pending.push((cu.address, class_hash));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Persists changes of the current update as part of `txn` -
+    /// which should also record the update's processing. Changes
+    /// already persisted (by previous processing of the same update)
+    /// are simply overwritten.
+    pub fn commit(&mut self, txn: &WriteTransaction) -> Result<()> {
+        if let Some(from_genesis) = self.pending_origin.take() {
+            let (block_no, index) = self.get_cur_position()?;
+            let mut origin = txn.open_table(ORIGIN_TABLE)?;
+            origin.insert((), (block_no, index, from_genesis))?;
+        }
+
+        let pending = std::mem::take(&mut self.pending);
+        if pending.is_empty() {
+            return Ok(());
+        }
+
+        let (block_no, index) = self.get_cur_position()?;
+        let mut table = txn.open_table(CLASS_TABLE)?;
+        let mut changes = txn.open_table(CLASS_CHANGE_TABLE)?;
+        for (address, class_hash) in pending.into_iter() {
+            let bytes = address.to_be_bytes();
+            let value = class_hash.to_be_bytes();
+            table.insert((bytes.as_slice(), block_no, index), value.as_slice())?;
+            changes.insert((block_no, index, bytes.as_slice()), ())?;
+        }
+
+        Ok(())
+    }
+
+    /// Removes changes of updates from Ethereum block `block_no`
+    /// onwards.
+    pub fn roll_back(&mut self, block_no: u64) -> Result<()> {
+        self.pending.clear();
+        self.pending_origin = None;
+        let txn = self.db.begin_write()?;
+        let mut n = 0;
+        {
+            let mut origin = txn.open_table(ORIGIN_TABLE)?;
+            let rolled_back = origin
+                .get(())?
+                .is_some_and(|found| found.value().0 >= block_no);
+            if rolled_back {
+                origin.remove(())?;
+            }
+        }
+        {
+            let mut changes = txn.open_table(CLASS_CHANGE_TABLE)?;
+            let mut table = txn.open_table(CLASS_TABLE)?;
+            let doomed = changes.extract_from_if((block_no, 0, [].as_slice()).., |_, _| true)?;
+            for res in doomed {
+                let (key, _) = res?;
+                let (block_no, index, address) = key.value();
+                table.remove((address, block_no, index))?;
+                n += 1;
+            }
+        }
+        txn.commit()?;
+
+        tracing::info!(
+            "{} contract class change(s) from block {} rolled back",
+            n,
+            block_no
+        );
+        Ok(())
+    }
+
+    fn get_origin(&self) -> Result<Option<(UpdatePosition, bool)>> {
+        let txn = self.db.begin_read()?;
+        let origin = match txn.open_table(ORIGIN_TABLE) {
+            Err(TableError::TableDoesNotExist(_)) => {
+                return Ok(None);
+            }
+            Err(err) => {
+                return Err(err.into());
+            }
+            Ok(origin) => origin,
+        };
+        let opt_origin = origin.get(())?.map(|found| {
+            let (block_no, index, from_genesis) = found.value();
+            ((block_no, index), from_genesis)
+        });
+        Ok(opt_origin)
+    }
+
+    // callers must start processing an update before classifying it
+    fn get_cur_position(&self) -> Result<UpdatePosition> {
+        self.cur_position
+            .ok_or_else(|| Error::Usage("update position not set".to_string()))
+    }
+}
+
+/// Returns the class hash of the contract at `address` set last
+/// before the update at `before`, with the position of the update
+/// setting it.
+pub(crate) fn read_class_hash(
+    txn: &ReadTransaction,
+    address: &Felt,
+    before: UpdatePosition,
+) -> Result<Option<(UpdatePosition, Felt)>> {
+    let table = match txn.open_table(CLASS_TABLE) {
+        Err(TableError::TableDoesNotExist(_)) => {
+            return Ok(None);
+        }
+        Err(err) => {
+            return Err(err.into());
+        }
+        Ok(table) => table,
+    };
+    let bytes = address.to_be_bytes();
+    let (block_no, index) = before;
+    let start = (bytes.as_slice(), 0, 0);
+    let end = (bytes.as_slice(), block_no, index);
+    match table.range(start..end)?.next_back() {
+        Some(res) => {
+            let (key, value) = res?;
+            let (_, block_no, index) = key.value();
+            let class_hash = Felt::from_be_slice(value.value())
+                .map_err(|e| redb::Error::Corrupted(e.to_string()))?;
+            Ok(Some(((block_no, index), class_hash)))
+        }
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use redb::Database;
+    use serde_json::json;
+
+    use super::ClassIndex;
+    use crate::error::Error;
+    use crate::felt::Felt;
+    use crate::state_diff::{ClassChange, StateDiff};
+    use crate::test_util::{apply_and_commit, make_contract_update, make_state_diff, memory_db};
+
+    fn make_class_diff(classes: &[(u64, u64)]) -> StateDiff {
+        let contract_updates = classes
+            .iter()
+            .map(|(address, class_hash)| make_contract_update(*address, 0, Some(*class_hash), &[]))
+            .collect();
+        make_state_diff(contract_updates, Vec::new())
+    }
+
+    fn process(
+        index: &mut ClassIndex,
+        db: &Database,
+        position: (u64, u64),
+        at_genesis: bool,
+        classes: &[(u64, u64)],
+    ) -> StateDiff {
+        let mut state_diff = make_class_diff(classes);
+        let apply = |index: &mut ClassIndex| {
+            index.set_position(position);
+            index.apply(&mut state_diff, at_genesis)
+        };
+        apply_and_commit(index, db, apply, ClassIndex::commit).unwrap();
+        state_diff
+    }
+
+    #[test]
+    fn classify_and_roll_back() {
+        let db = memory_db();
+        let mut index = ClassIndex::new(db.clone());
+        let first = process(&mut index, &db, (10, 0), true, &[(1, 100), (2, 200)]);
+        assert!(
+            first
+                .contract_updates
+                .iter()
+                .all(|cu| cu.class_change == Some(ClassChange::Deployed))
+        );
+
+        let second = process(&mut index, &db, (10, 3), true, &[(2, 201), (3, 300)]);
+        assert_eq!(
+            second.contract_updates[0].class_change,
+            Some(ClassChange::Replaced {
+                previous_class_hash: Felt::from(200)
+            })
+        );
+        assert_eq!(
+            second.contract_updates[1].class_change,
+            Some(ClassChange::Deployed)
+        );
+        let j = second.to_json_state_diff();
+        assert_eq!(j["replaced_classes"][0]["previous_class_hash"], "0xc8");
+        assert_eq!(j["deployed_contracts"][0]["address"], "0x3");
+        assert!(j.get("unclassified_class_changes").is_none());
+
+        // processing an update again classifies it the same way
+        let again = process(&mut index, &db, (10, 3), true, &[(2, 201), (3, 300)]);
+        assert_eq!(again.contract_updates, second.contract_updates);
+
+        process(&mut index, &db, (11, 1), true, &[(2, 202)]);
+        assert_eq!(
+            index.get(&Felt::from(2), None).unwrap(),
+            Some(Felt::from(202))
+        );
+        assert_eq!(
+            index.get(&Felt::from(2), Some((11, 0))).unwrap(),
+            Some(Felt::from(201))
+        );
+
+        index.roll_back(11).unwrap();
+        assert_eq!(
+            index.get(&Felt::from(2), None).unwrap(),
+            Some(Felt::from(201))
+        );
+        index.roll_back(10).unwrap();
+        assert_eq!(index.get(&Felt::from(1), None).unwrap(), None);
+    }
+
+    #[test]
+    fn not_from_genesis() {
+        let db = memory_db();
+        let mut index = ClassIndex::new(db.clone());
+        assert_eq!(index.is_from_genesis().unwrap(), None);
+        let first = process(&mut index, &db, (10, 0), false, &[(1, 100)]);
+        assert_eq!(first.contract_updates[0].class_change, None);
+        assert_eq!(index.is_from_genesis().unwrap(), Some(false));
+        // later updates don't start at genesis anyway
+        let second = process(&mut index, &db, (11, 0), true, &[(1, 101), (2, 200)]);
+        assert!(
+            second
+                .contract_updates
+                .iter()
+                .all(|cu| cu.class_change.is_none())
+        );
+        // but are still saved as JSON, apart from the classified ones
+        let j = second.to_json_state_diff();
+        assert_eq!(j["deployed_contracts"], json!([]));
+        assert_eq!(j["replaced_classes"], json!([]));
+        assert_eq!(
+            j["unclassified_class_changes"],
+            json!([
+                {"address": "0x1", "class_hash": "0x65"},
+                {"address": "0x2", "class_hash": "0xc8"},
+            ])
+        );
+        // which STATE_UPDATE has no place for
+        assert!(matches!(
+            second.to_rpc_state_diff(),
+            Err(Error::Unclassified(address)) if address == Felt::from(1)
+        ));
+
+        // rolling back the first update forgets where the index
+        // started
+        index.roll_back(10).unwrap();
+        assert_eq!(index.is_from_genesis().unwrap(), None);
+        let first = process(&mut index, &db, (10, 0), true, &[(1, 100)]);
+        assert_eq!(
+            first.contract_updates[0].class_change,
+            Some(ClassChange::Deployed)
+        );
+        let second = process(&mut index, &db, (11, 0), false, &[(1, 101)]);
+        assert_eq!(
+            second.contract_updates[0].class_change,
+            Some(ClassChange::Replaced {
+                previous_class_hash: Felt::from(100)
+            })
+        );
+    }
+
+    #[test]
+    fn position_not_set() {
+        let db = memory_db();
+        let mut index = ClassIndex::new(db);
+        let mut state_diff = make_class_diff(&[(1, 100)]);
+        let err = index.apply(&mut state_diff, true).unwrap_err();
+        assert!(matches!(err, Error::Usage(_)));
+    }
+}
@@ -0,0 +1,279 @@
+use redb::{ReadTransaction, TableDefinition, TableError, WriteTransaction};
+use starknet_crypto::{pedersen_hash, poseidon_hash, poseidon_hash_many};
+
+use crate::class_index::UpdatePosition;
+use crate::error::Result;
+use crate::felt::Felt;
+use crate::trie::{NODE_TABLE, TrieHash, TrieWriter, from_core, to_core};
+
+// keyed by (contract address, update position)
+const STORAGE_ROOT_TABLE: TableDefinition<(&[u8] /* Felt */, u64, u64), &[u8] /* Felt */> =
+    TableDefinition::new("contract_storage_root");
+
+// keys of STORAGE_ROOT_TABLE in update order, for rollback
+const STORAGE_ROOT_CHANGE_TABLE: TableDefinition<(u64, u64, &[u8] /* Felt */), ()> =
+    TableDefinition::new("contract_storage_root_change");
+
+// keyed by update position
+const COMMITMENT_TABLE: TableDefinition<(u64, u64), &[u8] /* StateCommitment */> =
+    TableDefinition::new("state_commitment");
+
+/// Roots of the global tries after a state update.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct StateCommitment {
+    /// root of the (Pedersen) trie of contract states
+    pub contracts_root: Felt,
+    /// root of the (Poseidon) trie of compiled class hashes
+    pub classes_root: Felt,
+}
+
+impl StateCommitment {
+    /// Combines the roots into the global state root published by
+    /// `LogStateUpdate`.
+    pub fn global_root(&self) -> Felt {
+        // before Starknet 0.11, there was no classes trie
+        if self.classes_root.is_zero() {
+            return self.contracts_root;
+        }
+
+        let items = [
+            to_core(&short_string("STARKNET_STATE_V0")),
+            to_core(&self.contracts_root),
+            to_core(&self.classes_root),
+        ];
+        from_core(poseidon_hash_many(&items))
+    }
+
+    fn to_bytes(self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(64);
+        bytes.extend_from_slice(&self.contracts_root.to_be_bytes());
+        bytes.extend_from_slice(&self.classes_root.to_be_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() != 64 {
+            let message = format!("state commitment has {} bytes", bytes.len());
+            return Err(redb::Error::Corrupted(message).into());
+        }
+
+        let read_felt = |pos: usize| -> Result<Felt> {
+            let felt = Felt::from_be_slice(&bytes[pos..pos + 32])
+                .map_err(|e| redb::Error::Corrupted(e.to_string()))?;
+            Ok(felt)
+        };
+        Ok(Self {
+            contracts_root: read_felt(0)?,
+            classes_root: read_felt(32)?,
+        })
+    }
+}
+
+/// Contract whose leaf in the contracts trie is changed by a state
+/// update.
+pub(crate) struct ContractLeaf {
+    pub address: Felt,
+    /// class hash and nonce after the update
+    pub class_hash: Felt,
+    pub nonce: u64,
+    /// storage updates, sorted by key
+    pub storage_updates: Vec<(Felt, Felt)>,
+}
+
+fn short_string(s: &str) -> Felt {
+    Felt::from_be_slice(s.as_bytes()).expect("short string too long")
+}
+
+/// Hash of a leaf of the contracts trie.
+pub fn contract_state_hash(class_hash: &Felt, storage_root: &Felt, nonce: u64) -> Felt {
+    let h = pedersen_hash(&to_core(class_hash), &to_core(storage_root));
+    let h = pedersen_hash(&h, &to_core(&Felt::from(nonce)));
+    // contract state hash version
+    let h = pedersen_hash(&h, &starknet_crypto::Felt::ZERO);
+    from_core(h)
+}
+
+/// Hash of a leaf of the classes trie.
+pub fn class_leaf_hash(compiled_class_hash: &Felt) -> Felt {
+    let prefix = to_core(&short_string("CONTRACT_CLASS_LEAF_V0"));
+    from_core(poseidon_hash(prefix, to_core(compiled_class_hash)))
+}
+
+/// Returns the storage root of the contract at `address` before the
+/// update at `before`, with the position of the update setting it.
+pub(crate) fn read_storage_root(
+    txn: &ReadTransaction,
+    address: &Felt,
+    before: UpdatePosition,
+) -> Result<Option<(UpdatePosition, Felt)>> {
+    let table = match txn.open_table(STORAGE_ROOT_TABLE) {
+        Err(TableError::TableDoesNotExist(_)) => {
+            return Ok(None);
+        }
+        Err(err) => {
+            return Err(err.into());
+        }
+        Ok(table) => table,
+    };
+    let address = address.to_be_bytes();
+    let (block_no, index) = before;
+    let start = (address.as_slice(), 0, 0);
+    let end = (address.as_slice(), block_no, index);
+    match table.range(start..end)?.next_back() {
+        Some(res) => {
+            let (key, value) = res?;
+            let (_, block_no, index) = key.value();
+            let root = Felt::from_be_slice(value.value())
+                .map_err(|e| redb::Error::Corrupted(e.to_string()))?;
+            Ok(Some(((block_no, index), root)))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Returns the state commitment after the last update before the one
+/// at `before`, with that update's position.
+pub(crate) fn read_commitment(
+    txn: &ReadTransaction,
+    before: UpdatePosition,
+) -> Result<Option<(UpdatePosition, StateCommitment)>> {
+    let table = match txn.open_table(COMMITMENT_TABLE) {
+        Err(TableError::TableDoesNotExist(_)) => {
+            return Ok(None);
+        }
+        Err(err) => {
+            return Err(err.into());
+        }
+        Ok(table) => table,
+    };
+    match table.range(..before)?.next_back() {
+        Some(res) => {
+            let (key, value) = res?;
+            let commitment = StateCommitment::from_bytes(value.value())?;
+            Ok(Some((key.value(), commitment)))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Updates the global tries by the changes of the update at
+/// `position` and persists the new commitment as part of `txn`.
+/// `snapshot` must not include the update (nor any later one).
+/// `contracts` and `classes` (mapping class hashes to compiled class
+/// hashes) must be sorted by address and class hash, respectively.
+pub(crate) fn update(
+    snapshot: &ReadTransaction,
+    txn: &WriteTransaction,
+    position: UpdatePosition,
+    contracts: &[ContractLeaf],
+    classes: &[(Felt, Felt)],
+) -> Result<StateCommitment> {
+    let old = read_commitment(snapshot, position)?
+        .map(|(_, commitment)| commitment)
+        .unwrap_or_default();
+    let (block_no, index) = position;
+    let mut nodes = txn.open_table(NODE_TABLE)?;
+    let mut roots = txn.open_table(STORAGE_ROOT_TABLE)?;
+    let mut changes = txn.open_table(STORAGE_ROOT_CHANGE_TABLE)?;
+    let mut leaves = Vec::with_capacity(contracts.len());
+    let mut writer = TrieWriter::new(&mut nodes, TrieHash::Pedersen);
+    for contract in contracts.iter() {
+        let old_root = read_storage_root(snapshot, &contract.address, position)?
+            .map(|(_, root)| root)
+            .unwrap_or_default();
+        let storage_root = writer.update(&old_root, &contract.storage_updates)?;
+        if storage_root != old_root {
+            let address = contract.address.to_be_bytes();
+            let value = storage_root.to_be_bytes();
+            roots.insert((address.as_slice(), block_no, index), value.as_slice())?;
+            changes.insert((block_no, index, address.as_slice()), ())?;
+        }
+
+        let leaf = contract_state_hash(&contract.class_hash, &storage_root, contract.nonce);
+        leaves.push((contract.address, leaf));
+    }
+    let contracts_root = writer.update(&old.contracts_root, &leaves)?;
+
+    let leaves: Vec<(Felt, Felt)> = classes
+        .iter()
+        .map(|(class_hash, compiled_class_hash)| {
+            (*class_hash, class_leaf_hash(compiled_class_hash))
+        })
+        .collect();
+    let mut writer = TrieWriter::new(&mut nodes, TrieHash::Poseidon);
+    let classes_root = writer.update(&old.classes_root, &leaves)?;
+
+    let commitment = StateCommitment {
+        contracts_root,
+        classes_root,
+    };
+    let mut table = txn.open_table(COMMITMENT_TABLE)?;
+    table.insert(position, commitment.to_bytes().as_slice())?;
+    Ok(commitment)
+}
+
+/// Removes commitments (and storage roots) of updates from Ethereum
+/// block `block_no` onwards, as part of `txn`. Returns the number of
+/// removed entries.
+pub(crate) fn roll_back(txn: &WriteTransaction, block_no: u64) -> Result<usize> {
+    let mut n = 0;
+    {
+        let mut changes = txn.open_table(STORAGE_ROOT_CHANGE_TABLE)?;
+        let mut table = txn.open_table(STORAGE_ROOT_TABLE)?;
+        let doomed = changes.extract_from_if((block_no, 0, [].as_slice()).., |_, _| true)?;
+        for res in doomed {
+            let (key, _) = res?;
+            let (block_no, index, address) = key.value();
+            table.remove((address, block_no, index))?;
+            n += 1;
+        }
+    }
+    let mut table = txn.open_table(COMMITMENT_TABLE)?;
+    for res in table.extract_from_if((block_no, 0).., |_, _| true)? {
+        res?;
+        n += 1;
+    }
+
+    Ok(n)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::{StateCommitment, class_leaf_hash, contract_state_hash};
+    use crate::felt::Felt;
+
+    // expected values computed by pathfinder 0.24.2: its
+    // `calculate_class_commitment_leaf_hash` and
+    // `StateCommitment::calculate` (of pathfinder-common), and the
+    // contract state hash with the Pedersen hash of pathfinder-crypto
+
+    #[test]
+    fn known_answers() {
+        let felt = |s: &str| Felt::from_str(s).unwrap();
+        assert_eq!(
+            contract_state_hash(
+                &felt("0x10455c752b86932ce552f2b0fe81a880746649b9aee7e0d842bf3f52378f9f8"),
+                &felt("0x4fb440e8ca9b74fc12a22ebffe0bc0658206337897226117276fcda786b4ae7"),
+                3
+            ),
+            felt("0x47b8490eae2bdef524c98904588bb72b60ce73947fdb0ce5199f0b5e34a5df1")
+        );
+        assert_eq!(
+            class_leaf_hash(&felt("0x1a2b3c4d5e6f")),
+            felt("0xa1ab9fc166ca5143b51babed9ac951b4dbb2b17d9e4d1a41765afc15dd7d1")
+        );
+
+        let mut commitment = StateCommitment {
+            contracts_root: felt("0x123456789abcdef"),
+            classes_root: felt("0xfedcba987654321"),
+        };
+        assert_eq!(
+            commitment.global_root(),
+            felt("0x69426e16cebae12a3bae3847b8647254c0c9ec47490d048f0f0f05d9bdfb437")
+        );
+        commitment.classes_root = Felt::ZERO;
+        assert_eq!(commitment.global_root(), commitment.contracts_root);
+    }
+}
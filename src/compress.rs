@@ -0,0 +1,268 @@
+// ported from the compression half of compression.cairo (see
+// decomp.rs)
+
+use eyre::anyhow;
+use num_bigint::BigUint;
+
+use std::collections::HashMap;
+
+use crate::decomp::{
+    BUCKET_N_BITS, BUCKET_PACK_COUNTS, HEADER_ELM_N_BITS, TOTAL_N_BUCKETS, get_bucket_offsets,
+    get_n_elms_per_felt, make_bucket_bounds,
+};
+
+const COMPRESSION_VERSION: usize = 0;
+
+fn pack_in_felt(elms: &[BigUint], elm_bound: &BigUint) -> BigUint {
+    let mut packed = BigUint::ZERO;
+    for elm in elms.iter().rev() {
+        packed = packed * elm_bound + elm;
+    }
+
+    packed
+}
+
+fn pack_in_felts(elms: &[BigUint], elm_bound: &BigUint, n_elms_per_felt: usize) -> Vec<BigUint> {
+    elms.chunks(n_elms_per_felt)
+        .map(|chunk| pack_in_felt(chunk, elm_bound))
+        .collect()
+}
+
+fn pack_usizes(elms: &[usize], elm_bound: usize) -> Vec<BigUint> {
+    let elms: Vec<BigUint> = elms.iter().map(|&elm| BigUint::from(elm)).collect();
+    pack_in_felts(
+        &elms,
+        &BigUint::from(elm_bound),
+        get_n_elms_per_felt(elm_bound),
+    )
+}
+
+#[derive(Default)]
+struct UniqueValueBucket {
+    values: Vec<BigUint>,
+    value_to_index: HashMap<BigUint, usize>,
+}
+
+/// Stateless compression of a sequence of felts, producing the same
+/// output as the sequencer: a header, unique values packed by size,
+/// pointers to repeated values and bucket indices of all elements.
+#[derive(Default)]
+pub struct Compressor {
+    buckets: [UniqueValueBucket; 6],
+    // (bucket index, index in bucket) of the first occurrence
+    repeating_value_locations: Vec<(usize, usize)>,
+    bucket_index_per_elm: Vec<usize>,
+}
+
+impl Compressor {
+    pub fn compress(data: &[BigUint]) -> eyre::Result<Vec<BigUint>> {
+        let mut compressor = Self::default();
+        compressor.update(data)?;
+        compressor.finish()
+    }
+
+    /// Adds `values` to the compressed data.
+    pub fn update(&mut self, values: &[BigUint]) -> eyre::Result<()> {
+        for value in values.iter() {
+            // smallest bucket first
+            let bucket_index = (0..BUCKET_N_BITS.len())
+                .rev()
+                .find(|&i| value.bits() <= BUCKET_N_BITS[i])
+                .ok_or_else(|| anyhow!("value {:#x} too large", value))?;
+            let bucket = &mut self.buckets[bucket_index];
+            if let Some(&index) = bucket.value_to_index.get(value) {
+                self.repeating_value_locations.push((bucket_index, index));
+                self.bucket_index_per_elm.push(TOTAL_N_BUCKETS - 1);
+            } else {
+                bucket
+                    .value_to_index
+                    .insert(value.clone(), bucket.values.len());
+                bucket.values.push(value.clone());
+                self.bucket_index_per_elm.push(bucket_index);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Sizes as stored in the header (after the version): data
+    /// length, unique value bucket lengths and the number of repeated
+    /// values.
+    pub fn get_sizes(&self) -> Vec<usize> {
+        let mut sizes = vec![self.bucket_index_per_elm.len()];
+        sizes.extend(self.buckets.iter().map(|bucket| bucket.values.len()));
+        sizes.push(self.repeating_value_locations.len());
+        sizes
+    }
+
+    pub fn finish(self) -> eyre::Result<Vec<BigUint>> {
+        let sizes = self.get_sizes();
+        let header_bound = 1usize << HEADER_ELM_N_BITS;
+        if sizes[0] >= header_bound {
+            return Err(anyhow!("cannot compress {} elements", sizes[0]));
+        }
+
+        let mut header = vec![COMPRESSION_VERSION];
+        header.extend(sizes.iter());
+        let mut compressed = vec![pack_in_felt(
+            &header.into_iter().map(BigUint::from).collect::<Vec<_>>(),
+            &BigUint::from(header_bound),
+        )];
+
+        let bucket_bounds = make_bucket_bounds();
+        for (i, bucket) in self.buckets.iter().enumerate() {
+            compressed.extend(pack_in_felts(
+                &bucket.values,
+                &bucket_bounds[i],
+                BUCKET_PACK_COUNTS[i],
+            ));
+        }
+
+        let bucket_lengths = &sizes[1..=6];
+        let n_unique_values = bucket_lengths.iter().sum();
+        let bucket_offsets = get_bucket_offsets(bucket_lengths);
+        let pointers: Vec<usize> = self
+            .repeating_value_locations
+            .iter()
+            .map(|&(bucket_index, index)| bucket_offsets[bucket_index] + index)
+            .collect();
+        compressed.extend(pack_usizes(&pointers, n_unique_values));
+        compressed.extend(pack_usizes(&self.bucket_index_per_elm, TOTAL_N_BUCKETS));
+        Ok(compressed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use num_bigint::BigUint;
+    use num_traits::One;
+
+    use crate::decomp::{Decompressor, HEADER_ELM_N_BITS};
+    use crate::dump::uncond_load;
+
+    use super::Compressor;
+
+    fn round_trip(data: &[BigUint]) -> Vec<BigUint> {
+        let compressed = Compressor::compress(data).unwrap();
+        let (decompressed, tail_size) =
+            Decompressor::decompress(compressed.clone().into_iter()).unwrap();
+        assert_eq!(decompressed, data);
+        assert_eq!(tail_size, 0);
+        compressed
+    }
+
+    #[test]
+    fn known_output() {
+        let data: Vec<BigUint> = [1u32, 2, 1].into_iter().map(BigUint::from).collect();
+        let compressed = round_trip(&data);
+        // version, length, 6 bucket lengths, repeats
+        let header = (BigUint::from(3u32) << 20)
+            + (BigUint::from(2u32) << (20 * 7))
+            + (BigUint::one() << (20 * 8));
+        let expected = vec![
+            header,
+            // unique values in the 15-bit bucket
+            BigUint::from(1u32 + (2 << 15)),
+            // pointer to the first 1
+            BigUint::ZERO,
+            // bucket indices (5, 5, repeated) in base 7
+            BigUint::from(5u32 + 5 * 7 + 6 * 49),
+        ];
+        assert_eq!(compressed, expected);
+    }
+
+    #[test]
+    fn edge_cases() {
+        // nothing to compress
+        assert_eq!(round_trip(&[]).len(), 1);
+
+        // every bucket, with repeats in all of them and bucket
+        // boundaries
+        let mut data = Vec::new();
+        for bits in [252u64, 125, 83, 62, 31, 15] {
+            let max = (BigUint::one() << bits) - 1u32;
+            data.push(max.clone());
+            data.push(BigUint::one() << (bits - 1));
+            data.push(max);
+        }
+        data.push(BigUint::ZERO);
+        round_trip(&data);
+
+        // bucket indices and small values filling whole felts
+        let data: Vec<BigUint> = (0..83u32 * 2).map(|i| BigUint::from(i % 40)).collect();
+        round_trip(&data);
+
+        assert!(Compressor::compress(&[BigUint::one() << 252]).is_err());
+    }
+
+    #[test]
+    fn empty_buckets() {
+        // only the largest bucket
+        let big: BigUint = (BigUint::one() << 251u32) + 1u32;
+        let data = vec![big.clone(), big.clone(), big];
+        let mut compressor = Compressor::default();
+        compressor.update(&data).unwrap();
+        assert_eq!(compressor.get_sizes(), vec![3, 1, 0, 0, 0, 0, 0, 2]);
+        round_trip(&data);
+
+        // only the smallest bucket, no repeats
+        let data: Vec<BigUint> = (0..16u32).map(BigUint::from).collect();
+        let mut compressor = Compressor::default();
+        compressor.update(&data).unwrap();
+        assert_eq!(compressor.get_sizes(), vec![16, 0, 0, 0, 0, 0, 16, 0]);
+        round_trip(&data);
+
+        // first and last buckets empty
+        let data: Vec<BigUint> = [1u64 << 20, 1 << 40, 1 << 20]
+            .into_iter()
+            .map(BigUint::from)
+            .collect();
+        let mut compressor = Compressor::default();
+        compressor.update(&data).unwrap();
+        assert_eq!(compressor.get_sizes(), vec![3, 0, 0, 0, 1, 1, 0, 1]);
+        round_trip(&data);
+    }
+
+    #[test]
+    fn max_size() {
+        let max_len: usize = (1 << HEADER_ELM_N_BITS) - 1;
+        let mut data: Vec<BigUint> = (0..max_len)
+            .map(|i| BigUint::from(i as u64 % 70000))
+            .collect();
+        let compressed = round_trip(&data);
+        assert_eq!(
+            compressed[0].clone() % (BigUint::one() << (2 * HEADER_ELM_N_BITS)),
+            BigUint::from(max_len) << HEADER_ELM_N_BITS
+        );
+
+        data.push(BigUint::ZERO);
+        assert!(Compressor::compress(&data).is_err());
+    }
+
+    // Dump a compressed blob (e.g. of Ethereum block 21282183, the
+    // first one on mainnet) with --dump and copy the .seq file to
+    // fixtures/compressed. None is checked in yet, so the test is
+    // ignored rather than passing without checking anything.
+    #[test]
+    #[ignore = "needs a dumped blob in fixtures/compressed"]
+    fn real_blob() {
+        let mask = concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures/compressed/*.seq");
+        let mut n = 0;
+        for raw_entry in glob::glob(mask).unwrap() {
+            let path = raw_entry.unwrap();
+            let seq = uncond_load(&path).unwrap();
+            let (decompressed, tail_size) =
+                Decompressor::decompress(seq.clone().into_iter()).unwrap();
+            let compressed = Compressor::compress(&decompressed).unwrap();
+            assert_eq!(
+                compressed,
+                seq[..seq.len() - tail_size],
+                "{}",
+                path.display()
+            );
+            n += 1;
+        }
+
+        assert!(n > 0, "no blobs in fixtures/compressed");
+    }
+}
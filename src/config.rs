@@ -1,8 +1,17 @@
 use clap::Parser;
+use eyre::ContextCompat;
 use serde::Deserialize;
 
 use std::path::PathBuf;
 
+use crate::annotation::AnnotationFormat;
+use crate::da_format::FormatEntry;
+use crate::felt::Felt;
+use crate::network::NetworkProfile;
+use crate::scan::Confirmation;
+use crate::state_diff::JsonFormat;
+use crate::state_store::StateAt;
+
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 pub struct Cli {
@@ -17,15 +26,14 @@ pub struct Cli {
         long,
         short = 'f',
         value_name = "n",
-        long_help = "First block to search for",
-        default_value = "19427723"
+        long_help = "First block to search for [default: resume after the last processed update, or start at the first blob-era block of the configured network]"
     )]
-    pub from_block: std::num::NonZeroU64,
+    pub from_block: Option<std::num::NonZeroU64>,
     #[arg(
         long,
         short = 'c',
         value_name = "n",
-        long_help = "Number of blocks to check in one `eth_getLogs` call",
+        long_help = "Initial number of blocks to check in one `eth_getLogs` call (grows while no state updates are found, shrinks when the range or its result is rejected as too large; other errors are retried with the same range)",
         default_value = "1"
     )]
     pub block_count: std::num::NonZeroU64,
@@ -57,6 +65,13 @@ pub struct Cli {
         default_value = "false"
     )]
     pub annotate_only: bool,
+    #[arg(
+        long,
+        value_name = "format",
+        long_help = "Format of annotations: free-form text, or JSON Lines with a record per word (including its offset, role, enclosing contract, lookup state and resolved alias)",
+        default_value = "text"
+    )]
+    pub annotation_format: AnnotationFormat,
     #[arg(
         long,
         short = '0',
@@ -74,7 +89,7 @@ pub struct Cli {
     #[arg(
         long,
         short = 's',
-        long_help = "Save downloaded blobs into the cache directory before processing them",
+        long_help = "Save downloaded blobs (and the logs and transactions referring to them) into the cache directory before processing them",
         default_value = "false"
     )]
     pub save: bool,
@@ -85,6 +100,19 @@ pub struct Cli {
         default_value = "false"
     )]
     pub json: bool,
+    #[arg(
+        long,
+        value_name = "format",
+        long_help = "Format of saved JSON: state diff with class change details, or Starknet JSON-RPC STATE_UPDATE (with block hash and state roots)",
+        default_value = "state-diff"
+    )]
+    pub json_format: JsonFormat,
+    #[arg(
+        long,
+        long_help = "Maintain the Merkle-Patricia tries of the reconstructed state and stop when their root differs from the global state root published by a state update (requires processing all state updates since the network's genesis)",
+        default_value = "false"
+    )]
+    pub verify_root: bool,
     #[arg(
         long,
         short = 'u',
@@ -92,13 +120,207 @@ pub struct Cli {
         default_value = "false"
     )]
     pub prune: bool,
+    #[arg(
+        long,
+        short = 'o',
+        long_help = "Instead of connecting to Ethereum and blob servers, replay logs, transactions and blobs previously saved into the cache directory (by the save option)",
+        default_value = "false"
+    )]
+    pub offline: bool,
+    #[arg(
+        long,
+        short = 'F',
+        long_help = "After reaching the chain head, keep polling for new blocks instead of exiting",
+        default_value = "false"
+    )]
+    pub follow: bool,
+    #[arg(
+        long,
+        short = 'n',
+        value_name = "n|finalized",
+        long_help = "Only process state updates from blocks with at least n blocks on top of them, or from finalized blocks",
+        default_value = "0"
+    )]
+    pub confirmations: Confirmation,
+    #[arg(
+        long,
+        value_name = "s",
+        long_help = "Seconds to wait between checks for new blocks when following the chain head",
+        default_value = "12"
+    )]
+    pub poll_interval: u64,
+    #[arg(
+        long,
+        value_name = "n",
+        long_help = "Maximum number of state updates fetched (and transformed) at once, ahead of parsing them in chain order",
+        default_value = "4"
+    )]
+    pub concurrency: std::num::NonZeroUsize,
+    #[arg(
+        long,
+        value_name = "address",
+        long_help = "Instead of processing state updates, print the class hash and nonce of the contract at the address (and, with --storage-key, a value of its storage) from the state reconstructed by previous runs"
+    )]
+    pub query: Option<Felt>,
+    #[arg(long, value_name = "key", long_help = "Storage key printed by --query")]
+    pub storage_key: Option<Felt>,
+    #[arg(
+        long,
+        requires = "storage_key",
+        long_help = "With --query, also print a Merkle proof of the storage value (in the result format of starknet_getStorageProof), verified against the global state root published on Ethereum (requires state processed with --verify-root)",
+        default_value = "false"
+    )]
+    pub prove: bool,
+    #[arg(
+        long,
+        value_name = "n|eth:n|latest",
+        long_help = "State printed by --query: after the state update covering Starknet block n, after state updates from Ethereum blocks up to n, or after all processed state updates",
+        default_value = "latest"
+    )]
+    pub at: StateAt,
+}
+
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BlobSourceKind {
+    #[default]
+    Blobscan,
+    Beacon,
+    Local,
+}
+
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NetworkKind {
+    #[default]
+    Mainnet,
+    Sepolia,
+    Custom,
 }
 
 #[derive(Deserialize)]
 pub struct Config {
+    #[serde(default)]
+    pub network: NetworkKind,
+    /// parameters of `NetworkKind::Custom`
+    pub custom_network: Option<NetworkProfile>,
+    /// overrides data availability formats of the network
+    pub da_formats: Option<Vec<FormatEntry>>,
     pub rpc_url: String,
+    #[serde(default)]
+    pub blob_source: BlobSourceKind,
     pub blob_url_base: String,
+    pub beacon_url: Option<String>,
     pub cache_dir: PathBuf,
     pub db_file: PathBuf,
     pub pathfinder_rpc_url: String,
 }
+
+impl Config {
+    pub fn get_network_profile(&self) -> eyre::Result<NetworkProfile> {
+        let mut profile = match self.network {
+            NetworkKind::Mainnet => NetworkProfile::mainnet(),
+            NetworkKind::Sepolia => NetworkProfile::sepolia(),
+            NetworkKind::Custom => self
+                .custom_network
+                .clone()
+                .context("custom network requires custom_network section")?,
+        };
+        if let Some(da_formats) = &self.da_formats {
+            profile.da_formats = da_formats.clone();
+        }
+
+        Ok(profile)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloy::primitives::address;
+
+    use super::Config;
+    use crate::da_format::{FormatEntry, FormatStart, FormatVersion};
+    use crate::network::NetworkProfile;
+
+    const BASE: &str = r#"
+rpc_url = "http://localhost:8545"
+blob_url_base = "http://localhost:3000/blobs/"
+cache_dir = "cache"
+db_file = "scraped.db"
+pathfinder_rpc_url = "http://localhost:9545/rpc/v0_8"
+"#;
+
+    #[test]
+    fn default_network() {
+        let config: Config = toml::from_str(BASE).unwrap();
+        assert_eq!(
+            config.get_network_profile().unwrap(),
+            NetworkProfile::mainnet()
+        );
+    }
+
+    #[test]
+    fn custom_network() {
+        let raw = format!(
+            r#"network = "custom"
+{}
+[custom_network]
+chain_id = 31337
+core_contract = "0x5FbDB2315678afecb367f032d93F642f64180aa3"
+gps_verifier = "0xe7f1725E7734CE288F8367e1Bb143E90bb3F0512"
+first_blob_block = 1
+
+[[custom_network.da_formats]]
+from_seq_no = 0
+format = "v0_13_3"
+"#,
+            BASE
+        );
+        let config: Config = toml::from_str(&raw).unwrap();
+        let profile = config.get_network_profile().unwrap();
+        assert_eq!(profile.chain_id, 31337);
+        assert_eq!(
+            profile.core_contract,
+            address!("0x5FbDB2315678afecb367f032d93F642f64180aa3")
+        );
+        assert_eq!(profile.memory_page_registry, None);
+        assert_eq!(
+            profile.da_formats,
+            vec![FormatEntry::new(
+                FormatStart::FromSeqNo(0),
+                FormatVersion::V0_13_3
+            )]
+        );
+
+        let incomplete = format!("network = \"custom\"\n{}", BASE);
+        let config: Config = toml::from_str(&incomplete).unwrap();
+        assert!(config.get_network_profile().is_err());
+    }
+
+    #[test]
+    fn da_formats_override() {
+        let raw = format!(
+            r#"network = "sepolia"
+{}
+[[da_formats]]
+from_seq_no = 0
+format = "v0_13_1"
+
+[[da_formats]]
+from_block = 6000000
+format = "v0_13_3"
+"#,
+            BASE
+        );
+        let config: Config = toml::from_str(&raw).unwrap();
+        let registry = config
+            .get_network_profile()
+            .unwrap()
+            .get_format_registry()
+            .unwrap();
+        assert_eq!(
+            registry.get_version(6000000, Some(100000)).unwrap(),
+            FormatVersion::V0_13_3
+        );
+    }
+}
@@ -0,0 +1,618 @@
+use eyre::anyhow;
+use num_bigint::BigUint;
+use num_traits::ToPrimitive;
+use serde::{Deserialize, Deserializer, de};
+
+use std::fmt;
+
+use crate::encode::{encode_class_declarations, encode_contract_updates};
+use crate::error::Result;
+use crate::packing::PackConst;
+use crate::parser::SectionParser;
+use crate::state_diff::{ClassDeclaration, ContractUpdate, StateDiff};
+
+pub mod pre_v0_11;
+pub mod pre_v0_9;
+pub mod v0_13_1;
+pub mod v0_13_3;
+
+/// Sections of a parsed state update.
+#[derive(Debug, Default)]
+pub struct Sections {
+    pub contract_updates: Vec<ContractUpdate>,
+    pub class_declarations: Vec<ClassDeclaration>,
+}
+
+/// Section of a state update: parsed by a `SectionParser` into
+/// `Sections` and encoded from a state diff. The sections below are
+/// those of the formats so far; a new format can bring its own.
+pub trait Section: Sync {
+    /// Parses the section into `sections` - which, on failure, keep
+    /// the sections parsed so far.
+    fn parse(&self, parser: &mut dyn SectionParser, sections: &mut Sections) -> eyre::Result<()>;
+
+    /// Appends the section of `state_diff` to `seq`.
+    fn encode(
+        &self,
+        state_diff: &StateDiff,
+        format: &dyn DaFormat,
+        seq: &mut Vec<BigUint>,
+    ) -> eyre::Result<()>;
+
+    fn has_class_declarations(&self) -> bool {
+        false
+    }
+}
+
+/// Words of a deployed contract in the deployments section.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DeploymentLayout {
+    /// address and class hash
+    Plain,
+    /// address, class hash, number of constructor arguments and the
+    /// arguments (before v0.9)
+    WithCalldata,
+}
+
+/// Deployed contracts (before v0.11), laid out as given.
+pub struct Deployments(pub DeploymentLayout);
+
+/// Contract updates, packed by the format.
+pub struct ContractUpdates;
+
+/// Declared classes (since v0.11).
+pub struct ClassDeclarations;
+
+impl Section for Deployments {
+    // deployments don't have their own field, but are kept as
+    // contract updates while the actual contract updates are parsed
+    fn parse(&self, parser: &mut dyn SectionParser, sections: &mut Sections) -> eyre::Result<()> {
+        sections.contract_updates = parser.parse_deployments(self.0)?;
+        Ok(())
+    }
+
+    fn encode(&self, _: &StateDiff, _: &dyn DaFormat, _: &mut Vec<BigUint>) -> eyre::Result<()> {
+        Err(anyhow!("encoding deployments section not supported"))
+    }
+}
+
+impl Section for ContractUpdates {
+    fn parse(&self, parser: &mut dyn SectionParser, sections: &mut Sections) -> eyre::Result<()> {
+        let contract_updates = parser.parse_contract_updates()?;
+        let deployments = std::mem::replace(&mut sections.contract_updates, contract_updates);
+        merge_deployments(&mut sections.contract_updates, deployments);
+        Ok(())
+    }
+
+    fn encode(
+        &self,
+        state_diff: &StateDiff,
+        format: &dyn DaFormat,
+        seq: &mut Vec<BigUint>,
+    ) -> eyre::Result<()> {
+        encode_contract_updates(seq, state_diff, format.pack_const())
+    }
+}
+
+impl Section for ClassDeclarations {
+    fn parse(&self, parser: &mut dyn SectionParser, sections: &mut Sections) -> eyre::Result<()> {
+        sections.class_declarations = parser.parse_class_declarations()?;
+        Ok(())
+    }
+
+    fn encode(
+        &self,
+        state_diff: &StateDiff,
+        _: &dyn DaFormat,
+        seq: &mut Vec<BigUint>,
+    ) -> eyre::Result<()> {
+        encode_class_declarations(seq, state_diff);
+        Ok(())
+    }
+
+    fn has_class_declarations(&self) -> bool {
+        true
+    }
+}
+
+// Pre-v0.11 updates list deployed contracts separately from
+// (optional) storage updates of the same contracts.
+fn merge_deployments(contract_updates: &mut Vec<ContractUpdate>, deployments: Vec<ContractUpdate>) {
+    for deployment in deployments {
+        if let Some(cu) = contract_updates
+            .iter_mut()
+            .find(|cu| cu.address == deployment.address)
+        {
+            cu.new_class_hash = deployment.new_class_hash;
+        } else {
+            contract_updates.push(deployment);
+        }
+    }
+}
+
+/// Layout of the data published by a state update: its sections (in
+/// order), packing of contract updates and decompression. Each format
+/// is a module with a static instance, listed in `FORMATS`.
+pub trait DaFormat: Sync {
+    /// Identifier of the format in the configuration.
+    fn id(&self) -> &'static str;
+
+    fn name(&self) -> &'static str;
+
+    fn pack_const(&self) -> &PackConst;
+
+    fn is_compressed(&self) -> bool {
+        false
+    }
+
+    /// Converts the published sequence to the one parsed by
+    /// `parse_sections`, also returning the number of zeros after
+    /// the compressed data.
+    fn decompress(&self, seq: &[BigUint]) -> Result<(Vec<BigUint>, usize)> {
+        Ok((seq.to_vec(), 0))
+    }
+
+    /// Sections of the update, in order.
+    fn sections(&self) -> &'static [&'static dyn Section];
+
+    /// Parses the update into `sections` - which, on failure, keep
+    /// the sections parsed so far.
+    fn parse_sections(
+        &self,
+        parser: &mut dyn SectionParser,
+        sections: &mut Sections,
+    ) -> eyre::Result<()> {
+        for section in self.sections() {
+            section.parse(parser, sections)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Known formats, in chronological order.
+pub static FORMATS: &[&dyn DaFormat] = &[
+    &pre_v0_9::FORMAT,
+    &pre_v0_11::FORMAT,
+    &v0_13_1::FORMAT,
+    &v0_13_3::FORMAT,
+];
+
+/// Heuristic telling compressed sequences from uncompressed ones: the
+/// first element is either the number of contract updates or a
+/// compression header, with high bits set for all but pretty much
+/// empty updates.
+pub fn looks_compressed(seq: &[BigUint]) -> bool {
+    seq.first().is_some_and(|first| first.to_usize().is_none())
+}
+
+/// Version of the data availability format, as configured: one of
+/// `FORMATS`, by its id.
+#[derive(Clone, Copy)]
+pub struct FormatVersion(&'static dyn DaFormat);
+
+impl FormatVersion {
+    /// memory pages before v0.9
+    pub const PRE_V0_9: Self = Self(&pre_v0_9::FORMAT);
+    /// memory pages of v0.9 - v0.10
+    pub const PRE_V0_11: Self = Self(&pre_v0_11::FORMAT);
+    /// v0.11 - v0.13.2
+    pub const V0_13_1: Self = Self(&v0_13_1::FORMAT);
+    /// since v0.13.3
+    pub const V0_13_3: Self = Self(&v0_13_3::FORMAT);
+
+    pub fn from_id(id: &str) -> Option<Self> {
+        FORMATS
+            .iter()
+            .find(|format| format.id() == id)
+            .map(|format| Self(*format))
+    }
+
+    pub fn get_format(&self) -> &'static dyn DaFormat {
+        self.0
+    }
+}
+
+impl PartialEq for FormatVersion {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.id() == other.0.id()
+    }
+}
+
+impl Eq for FormatVersion {}
+
+impl fmt::Debug for FormatVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.0.id())
+    }
+}
+
+impl<'de> Deserialize<'de> for FormatVersion {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let id = String::deserialize(deserializer)?;
+        Self::from_id(&id)
+            .ok_or_else(|| de::Error::custom(format!("unknown data availability format {}", id)))
+    }
+}
+
+/// Where a format starts.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum FormatStart {
+    /// first Ethereum block
+    FromBlock(u64),
+    /// first Starknet block
+    FromSeqNo(u64),
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct FormatEntry {
+    #[serde(flatten)]
+    pub start: FormatStart,
+    pub format: FormatVersion,
+}
+
+impl FormatEntry {
+    pub fn new(start: FormatStart, format: FormatVersion) -> Self {
+        Self { start, format }
+    }
+
+    // `None` when it can't be decided, i.e. when the entry starts at a
+    // Starknet block but `seq_no` isn't known
+    fn is_reached(&self, block_no: u64, seq_no: Option<u64>) -> Option<bool> {
+        match self.start {
+            FormatStart::FromBlock(n) => Some(block_no >= n),
+            FormatStart::FromSeqNo(0) => Some(true),
+            FormatStart::FromSeqNo(n) => seq_no.map(|seq_no| seq_no >= n),
+        }
+    }
+}
+
+/// Formats of a network's state updates, in chronological order.
+#[derive(Clone, Debug)]
+pub struct FormatRegistry {
+    entries: Vec<FormatEntry>,
+}
+
+impl FormatRegistry {
+    /// Checks that `entries` aren't empty and that entries starting
+    /// at the same kind of block are in order (entries starting at
+    /// different kinds can't be compared).
+    pub fn new(entries: Vec<FormatEntry>) -> eyre::Result<Self> {
+        if entries.is_empty() {
+            return Err(anyhow!("no data availability formats"));
+        }
+
+        let mut last_block = None;
+        let mut last_seq_no = None;
+        for entry in entries.iter() {
+            let (last, n) = match entry.start {
+                FormatStart::FromBlock(n) => (&mut last_block, n),
+                FormatStart::FromSeqNo(n) => (&mut last_seq_no, n),
+            };
+            if last.is_some_and(|last| n <= last) {
+                return Err(anyhow!(
+                    "data availability format {:?} from {:?} out of order",
+                    entry.format,
+                    entry.start
+                ));
+            }
+            *last = Some(n);
+        }
+
+        Ok(Self { entries })
+    }
+
+    /// Returns the format of the update published in Ethereum block
+    /// `block_no` for Starknet block `seq_no` - which must be known
+    /// when the format depends on it.
+    pub fn get_version(&self, block_no: u64, seq_no: Option<u64>) -> eyre::Result<FormatVersion> {
+        for entry in self.entries.iter().rev() {
+            match entry.is_reached(block_no, seq_no) {
+                Some(true) => return Ok(entry.format),
+                Some(false) => (),
+                None => {
+                    return Err(anyhow!(
+                        "data availability format of block {} depends on its Starknet block number, which isn't known",
+                        block_no
+                    ));
+                }
+            }
+        }
+
+        Err(anyhow!(
+            "no data availability format for block {}",
+            block_no
+        ))
+    }
+}
+
+/// Describes how the data `seq` contradicts the `expected` format
+/// version (as given by the registry), if `looks_compressed` tells
+/// it does. The heuristic only tells compressed data from
+/// uncompressed, so the observed version is the latest one of its
+/// kind - and it doesn't override the registry.
+pub fn check_version(expected: FormatVersion, seq: &[BigUint]) -> Option<String> {
+    let compressed = looks_compressed(seq);
+    let expected_format = expected.get_format();
+    if expected_format.is_compressed() == compressed {
+        return None;
+    }
+
+    let observed_format = FORMATS
+        .iter()
+        .rev()
+        .find(|format| format.is_compressed() == compressed)?;
+    Some(format!(
+        "{} format expected, but data looks like {} (first word {:#x})",
+        expected_format.name(),
+        observed_format.name(),
+        seq.first().cloned().unwrap_or_default()
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use eyre::anyhow;
+    use num_bigint::BigUint;
+    use num_traits::Zero;
+
+    use std::sync::LazyLock;
+
+    use super::{
+        ClassDeclarations, ContractUpdates, DaFormat, FormatEntry, FormatRegistry, FormatStart,
+        FormatVersion, Section, Sections, check_version,
+    };
+    use crate::annotation::Annotator;
+    use crate::compress::Compressor;
+    use crate::encode::encode_state_diff;
+    use crate::felt::Felt;
+    use crate::packing::{PackConst, v0_13_3};
+    use crate::parser::{SectionParser, StateUpdateParser};
+    use crate::state_diff::{ContractUpdate, StateDiff, StorageUpdate};
+    use crate::test_util::make_lookup;
+
+    // hypothetical future format, with a new section (of reserved
+    // words, which must be zero) after the declared classes
+    struct Reserved;
+
+    impl Section for Reserved {
+        fn parse(&self, parser: &mut dyn SectionParser, _: &mut Sections) -> eyre::Result<()> {
+            let n = parser.next_word("Missing number of reserved words")?;
+            let mut i = BigUint::ZERO;
+            while i < n {
+                if !parser.next_word("Missing reserved word")?.is_zero() {
+                    return Err(anyhow!("reserved word set"));
+                }
+                i += 1u32;
+            }
+
+            Ok(())
+        }
+
+        fn encode(
+            &self,
+            _: &StateDiff,
+            _: &dyn DaFormat,
+            seq: &mut Vec<BigUint>,
+        ) -> eyre::Result<()> {
+            seq.push(BigUint::ZERO);
+            Ok(())
+        }
+    }
+
+    struct Future {
+        pack_const: LazyLock<PackConst>,
+    }
+
+    static FUTURE: Future = Future {
+        pack_const: LazyLock::new(v0_13_3::make_pack_const),
+    };
+
+    impl DaFormat for Future {
+        fn id(&self) -> &'static str {
+            "future"
+        }
+
+        fn name(&self) -> &'static str {
+            "future"
+        }
+
+        fn pack_const(&self) -> &PackConst {
+            &self.pack_const
+        }
+
+        fn sections(&self) -> &'static [&'static dyn Section] {
+            &[&ContractUpdates, &ClassDeclarations, &Reserved]
+        }
+    }
+
+    #[test]
+    fn new_section() {
+        let lookup = make_lookup();
+        let parse = |words: &[u32]| {
+            let seq: Vec<BigUint> = words.iter().map(|w| BigUint::from(*w)).collect();
+            StateUpdateParser::parse(seq.into_iter(), &FUTURE, lookup.clone(), Annotator::none())
+        };
+
+        // 1 contract with 1 storage update, 1 declaration, 2 reserved
+        // words and zero padding
+        let state_diff = parse(&[1, 0x1234, 0b100, 5, 6, 1, 7, 8, 2, 0, 0, 0]).unwrap();
+        assert_eq!(state_diff.contract_updates.len(), 1);
+        assert_eq!(state_diff.class_declarations.len(), 1);
+        assert_eq!(state_diff.tail_size, 1);
+        assert!(parse(&[0, 0, 1, 3]).is_err());
+
+        // the new section is encoded as well
+        let seq = encode_state_diff(&state_diff, &FUTURE).unwrap();
+        assert_eq!(seq.last(), Some(&BigUint::ZERO));
+        let words: Vec<u32> = seq.iter().map(|w| w.try_into().unwrap()).collect();
+        let again = parse(&words).unwrap();
+        assert_eq!(again.contract_updates, state_diff.contract_updates);
+        assert_eq!(again.class_declarations, state_diff.class_declarations);
+    }
+
+    #[test]
+    fn deployments() {
+        let lookup = make_lookup();
+        let format = FormatVersion::PRE_V0_11.get_format();
+        let parse = |words: &[u32]| {
+            let seq: Vec<BigUint> = words.iter().map(|w| BigUint::from(*w)).collect();
+            StateUpdateParser::parse(seq.into_iter(), format, lookup.clone(), Annotator::none())
+        };
+
+        // 2 deployments (4 words), then 1 contract (the first deployed
+        // one) with 1 storage update
+        let state_diff = parse(&[4, 0xa, 0x100, 0xb, 0x200, 1, 0xa, 1, 5, 6, 0]).unwrap();
+        let deployed = ContractUpdate {
+            address: Felt::from(0xa),
+            nonce: 0,
+            new_class_hash: Some(Felt::from(0x100)),
+            storage_updates: vec![StorageUpdate {
+                key: Felt::from(5),
+                value: Felt::from(6),
+            }],
+            class_change: None,
+        };
+        let only_deployed = ContractUpdate {
+            address: Felt::from(0xb),
+            nonce: 0,
+            new_class_hash: Some(Felt::from(0x200)),
+            storage_updates: Vec::new(),
+            class_change: None,
+        };
+        assert_eq!(state_diff.contract_updates, vec![deployed, only_deployed]);
+        assert!(state_diff.class_declarations.is_empty());
+        assert_eq!(state_diff.tail_size, 1);
+
+        // length in words must be even
+        assert!(parse(&[3, 0xa, 0x100, 0xb, 0]).is_err());
+    }
+
+    #[test]
+    fn deployments_with_calldata() {
+        let lookup = make_lookup();
+        let format = FormatVersion::PRE_V0_9.get_format();
+        let parse = |words: &[u32]| {
+            let seq: Vec<BigUint> = words.iter().map(|w| BigUint::from(*w)).collect();
+            StateUpdateParser::parse(seq.into_iter(), format, lookup.clone(), Annotator::none())
+        };
+
+        // 2 deployments (8 words) with 2 and 0 constructor arguments,
+        // then 1 contract (the second deployed one) with 1 storage
+        // update
+        let words = [8, 0xa, 0x100, 2, 0x77, 0x88, 0xb, 0x200, 0, 1, 0xb, 1, 5, 6];
+        let state_diff = parse(&words).unwrap();
+        let only_deployed = ContractUpdate {
+            address: Felt::from(0xa),
+            nonce: 0,
+            new_class_hash: Some(Felt::from(0x100)),
+            storage_updates: Vec::new(),
+            class_change: None,
+        };
+        let deployed = ContractUpdate {
+            address: Felt::from(0xb),
+            nonce: 0,
+            new_class_hash: Some(Felt::from(0x200)),
+            storage_updates: vec![StorageUpdate {
+                key: Felt::from(5),
+                value: Felt::from(6),
+            }],
+            class_change: None,
+        };
+        assert_eq!(state_diff.contract_updates, vec![deployed, only_deployed]);
+        assert_eq!(state_diff.tail_size, 0);
+
+        // odd lengths are fine, but calldata must fit the section
+        assert!(parse(&[3, 0xa, 0x100, 0, 0]).is_ok());
+        assert!(parse(&[4, 0xa, 0x100, 2, 0x77, 0x88, 0]).is_err());
+        assert!(parse(&[2, 0xa, 0x100, 0]).is_err());
+    }
+
+    #[test]
+    fn get_version() {
+        let registry = FormatRegistry::new(vec![
+            FormatEntry::new(FormatStart::FromSeqNo(0), FormatVersion::PRE_V0_11),
+            FormatEntry::new(FormatStart::FromSeqNo(28000), FormatVersion::V0_13_1),
+            FormatEntry::new(FormatStart::FromBlock(21282183), FormatVersion::V0_13_3),
+        ])
+        .unwrap();
+        assert_eq!(
+            registry.get_version(16000000, Some(27999)).unwrap(),
+            FormatVersion::PRE_V0_11
+        );
+        assert_eq!(
+            registry.get_version(17000000, Some(28000)).unwrap(),
+            FormatVersion::V0_13_1
+        );
+        assert_eq!(
+            registry.get_version(21282182, Some(900000)).unwrap(),
+            FormatVersion::V0_13_1
+        );
+        assert_eq!(
+            registry.get_version(21282183, Some(900000)).unwrap(),
+            FormatVersion::V0_13_3
+        );
+        // Starknet block numbers are only needed before the last
+        // switch
+        assert_eq!(
+            registry.get_version(21282183, None).unwrap(),
+            FormatVersion::V0_13_3
+        );
+        assert!(registry.get_version(20000000, None).is_err());
+
+        let late = FormatRegistry::new(vec![FormatEntry::new(
+            FormatStart::FromBlock(100),
+            FormatVersion::V0_13_3,
+        )])
+        .unwrap();
+        assert!(late.get_version(99, None).is_err());
+        assert!(FormatRegistry::new(Vec::new()).is_err());
+
+        // entries out of order would be found in the wrong order
+        let swapped = FormatRegistry::new(vec![
+            FormatEntry::new(FormatStart::FromSeqNo(0), FormatVersion::PRE_V0_11),
+            FormatEntry::new(FormatStart::FromBlock(21282183), FormatVersion::V0_13_3),
+            FormatEntry::new(FormatStart::FromBlock(19000000), FormatVersion::V0_13_1),
+        ]);
+        assert!(swapped.is_err());
+        let repeated = FormatRegistry::new(vec![
+            FormatEntry::new(FormatStart::FromSeqNo(0), FormatVersion::PRE_V0_9),
+            FormatEntry::new(FormatStart::FromSeqNo(0), FormatVersion::PRE_V0_11),
+        ]);
+        assert!(repeated.is_err());
+
+        assert_eq!(
+            FormatVersion::from_id("pre_v0_11"),
+            Some(FormatVersion::PRE_V0_11)
+        );
+        assert_eq!(
+            FormatVersion::from_id("pre_v0_9"),
+            Some(FormatVersion::PRE_V0_9)
+        );
+        assert_eq!(FormatVersion::from_id("future"), None);
+    }
+
+    #[test]
+    fn contradiction() {
+        let compressed = Compressor::compress(&[BigUint::from(1u32)]).unwrap();
+        let uncompressed = vec![BigUint::from(1u32), BigUint::from(0x1234u32)];
+        assert_eq!(check_version(FormatVersion::V0_13_3, &compressed), None);
+        assert_eq!(check_version(FormatVersion::V0_13_1, &uncompressed), None);
+        assert_eq!(check_version(FormatVersion::PRE_V0_11, &uncompressed), None);
+        assert_eq!(
+            check_version(FormatVersion::V0_13_1, &compressed).unwrap(),
+            format!(
+                "v0.13.1 format expected, but data looks like v0.13.3 (first word {:#x})",
+                compressed[0]
+            )
+        );
+        assert_eq!(
+            check_version(FormatVersion::V0_13_3, &uncompressed).unwrap(),
+            "v0.13.3 format expected, but data looks like v0.13.1 (first word 0x1)"
+        );
+    }
+}
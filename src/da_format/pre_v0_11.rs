@@ -0,0 +1,32 @@
+use std::sync::LazyLock;
+
+use crate::packing::{PackConst, pre_v0_11};
+
+use super::{ContractUpdates, DaFormat, DeploymentLayout, Deployments, Section};
+
+pub static FORMAT: PreV0_11 = PreV0_11 {
+    pack_const: LazyLock::new(pre_v0_11::make_pack_const),
+};
+
+/// Deployed contracts, then contract updates without class flag.
+pub struct PreV0_11 {
+    pack_const: LazyLock<PackConst>,
+}
+
+impl DaFormat for PreV0_11 {
+    fn id(&self) -> &'static str {
+        "pre_v0_11"
+    }
+
+    fn name(&self) -> &'static str {
+        "pre-v0.11"
+    }
+
+    fn pack_const(&self) -> &PackConst {
+        &self.pack_const
+    }
+
+    fn sections(&self) -> &'static [&'static dyn Section] {
+        &[&Deployments(DeploymentLayout::Plain), &ContractUpdates]
+    }
+}
@@ -0,0 +1,36 @@
+use std::sync::LazyLock;
+
+use crate::packing::{PackConst, pre_v0_11};
+
+use super::{ContractUpdates, DaFormat, DeploymentLayout, Deployments, Section};
+
+pub static FORMAT: PreV0_9 = PreV0_9 {
+    pack_const: LazyLock::new(pre_v0_11::make_pack_const),
+};
+
+/// Deployed contracts with their constructor calldata, then contract
+/// updates without class flag (packed like pre-v0.11 ones).
+pub struct PreV0_9 {
+    pack_const: LazyLock<PackConst>,
+}
+
+impl DaFormat for PreV0_9 {
+    fn id(&self) -> &'static str {
+        "pre_v0_9"
+    }
+
+    fn name(&self) -> &'static str {
+        "pre-v0.9"
+    }
+
+    fn pack_const(&self) -> &PackConst {
+        &self.pack_const
+    }
+
+    fn sections(&self) -> &'static [&'static dyn Section] {
+        &[
+            &Deployments(DeploymentLayout::WithCalldata),
+            &ContractUpdates,
+        ]
+    }
+}
@@ -0,0 +1,33 @@
+use std::sync::LazyLock;
+
+use crate::packing::{PackConst, v0_13_1};
+
+use super::{ClassDeclarations, ContractUpdates, DaFormat, Section};
+
+pub static FORMAT: V0_13_1 = V0_13_1 {
+    pack_const: LazyLock::new(v0_13_1::make_pack_const),
+};
+
+/// Contract updates with class flag, then declared classes (in fact
+/// introduced by v0.11).
+pub struct V0_13_1 {
+    pack_const: LazyLock<PackConst>,
+}
+
+impl DaFormat for V0_13_1 {
+    fn id(&self) -> &'static str {
+        "v0_13_1"
+    }
+
+    fn name(&self) -> &'static str {
+        "v0.13.1"
+    }
+
+    fn pack_const(&self) -> &PackConst {
+        &self.pack_const
+    }
+
+    fn sections(&self) -> &'static [&'static dyn Section] {
+        &[&ContractUpdates, &ClassDeclarations]
+    }
+}
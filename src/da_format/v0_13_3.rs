@@ -0,0 +1,45 @@
+use num_bigint::BigUint;
+
+use std::sync::LazyLock;
+
+use crate::decomp::Decompressor;
+use crate::error::Result;
+use crate::packing::{PackConst, v0_13_3};
+
+use super::{ClassDeclarations, ContractUpdates, DaFormat, Section};
+
+pub static FORMAT: V0_13_3 = V0_13_3 {
+    pack_const: LazyLock::new(v0_13_3::make_pack_const),
+};
+
+/// Same sections as v0.13.1, with shorter packing of contract updates
+/// and stateless compression of the whole sequence.
+pub struct V0_13_3 {
+    pack_const: LazyLock<PackConst>,
+}
+
+impl DaFormat for V0_13_3 {
+    fn id(&self) -> &'static str {
+        "v0_13_3"
+    }
+
+    fn name(&self) -> &'static str {
+        "v0.13.3"
+    }
+
+    fn pack_const(&self) -> &PackConst {
+        &self.pack_const
+    }
+
+    fn is_compressed(&self) -> bool {
+        true
+    }
+
+    fn decompress(&self, seq: &[BigUint]) -> Result<(Vec<BigUint>, usize)> {
+        Decompressor::decompress(seq.iter().cloned())
+    }
+
+    fn sections(&self) -> &'static [&'static dyn Section] {
+        &[&ContractUpdates, &ClassDeclarations]
+    }
+}
@@ -1,18 +1,28 @@
 // ported from compression.cairo
 
-use eyre::{ContextCompat, anyhow};
+use eyre::{WrapErr, anyhow};
 use num_bigint::{BigUint, ToBigUint};
 use num_traits::{ToPrimitive, Zero};
+use serde_json::json;
 
 use std::ops::Shl;
 
 use crate::blob_util::parse_usize;
+use crate::diag::{FailurePoint, Stage};
+use crate::error::{Error, Result};
 
-const HEADER_ELM_N_BITS: usize = 20;
+pub(crate) const HEADER_ELM_N_BITS: usize = 20;
 
 const MAX_N_BITS_PER_FELT: usize = 251;
 
-const TOTAL_N_BUCKETS: usize = 7;
+pub(crate) const TOTAL_N_BUCKETS: usize = 7;
+
+/// bit lengths of the unique value buckets, largest first
+pub(crate) const BUCKET_N_BITS: [u64; 6] = [252, 125, 83, 62, 31, 15];
+
+/// number of elements packed in a felt, for each unique value bucket
+/// (the largest one doesn't do any packing)
+pub(crate) const BUCKET_PACK_COUNTS: [usize; 6] = [1, 2, 3, 4, 8, 16];
 
 fn unpack_felt(
     packed: BigUint,
@@ -61,13 +71,15 @@ fn unpack_header(packed: BigUint) -> eyre::Result<Vec<usize>> {
     Ok(sizes)
 }
 
-fn make_bucket_bounds() -> Vec<BigUint> {
+pub(crate) fn make_bucket_bounds() -> Vec<BigUint> {
     let one: BigUint = 1u32.to_biguint().unwrap();
-    let powers = [252, 125, 83, 62, 31, 15];
-    powers.into_iter().map(|p| one.clone().shl(p)).collect()
+    BUCKET_N_BITS
+        .into_iter()
+        .map(|p| one.clone().shl(p))
+        .collect()
 }
 
-fn get_n_elms_per_felt(elm_bound: usize) -> usize {
+pub(crate) fn get_n_elms_per_felt(elm_bound: usize) -> usize {
     if elm_bound < 2 {
         return MAX_N_BITS_PER_FELT;
     }
@@ -77,7 +89,7 @@ fn get_n_elms_per_felt(elm_bound: usize) -> usize {
     MAX_N_BITS_PER_FELT / (n_bits_per_elm as usize)
 }
 
-fn extend_with_repeats(src_and_dst: &mut Vec<BigUint>, indices: &Vec<BigUint>) -> eyre::Result<()> {
+fn extend_with_repeats(src_and_dst: &mut Vec<BigUint>, indices: &[BigUint]) -> eyre::Result<()> {
     let n_unique_values = src_and_dst.len();
     for big_idx in indices.iter() {
         // caller ensures indices are actually indices, i.e. small enough...
@@ -94,7 +106,7 @@ fn extend_with_repeats(src_and_dst: &mut Vec<BigUint>, indices: &Vec<BigUint>) -
 }
 
 // from sequencer
-fn get_bucket_offsets(bucket_lengths: &[usize]) -> Vec<usize> {
+pub(crate) fn get_bucket_offsets(bucket_lengths: &[usize]) -> Vec<usize> {
     let mut offsets = Vec::with_capacity(bucket_lengths.len());
     let mut current = 0;
     for &length in bucket_lengths {
@@ -108,26 +120,44 @@ fn get_bucket_offsets(bucket_lengths: &[usize]) -> Vec<usize> {
 pub struct Decompressor<I> {
     pub current: I,
     pub sizes: Vec<usize>, // 8 items (header w/o version)
+    // number of words read
+    offset: usize,
+    // set when the sequence ended prematurely
+    exhausted: bool,
+    step: &'static str,
+    // number of values unpacked so far
+    n_unpacked: usize,
 }
 
 impl<I> Decompressor<I>
 where
     I: Iterator<Item = BigUint>,
 {
-    pub fn decompress(iter: I) -> eyre::Result<(Vec<BigUint>, usize)> {
-        let mut iter = iter;
-        let header = iter.next().context("nothing to decompress")?;
-        let sizes = unpack_header(header)?;
+    pub fn decompress(iter: I) -> Result<(Vec<BigUint>, usize)> {
         let mut decompressor = Self {
             current: iter,
-            sizes,
+            sizes: Vec::new(),
+            offset: 0,
+            exhausted: false,
+            step: "header",
+            n_unpacked: 0,
         };
+        decompressor.run().map_err(|err| Error::Decompression {
+            point: Box::new(decompressor.make_failure_point()),
+            message: format!("{:#}", err),
+        })
+    }
+
+    fn run(&mut self) -> eyre::Result<(Vec<BigUint>, usize)> {
+        let header = self.next_word("nothing to decompress")?;
+        self.sizes = unpack_header(header)?;
 
+        self.step = "unique values";
         let mut all_values = Vec::new();
-        decompressor.unpack_unique_values(&mut all_values)?;
+        self.unpack_unique_values(&mut all_values)?;
         let n_unique_values = all_values.len();
-        let uncompressed_len = decompressor.sizes[0];
-        let n_repeating_values = decompressor.sizes[7];
+        let uncompressed_len = self.sizes[0];
+        let n_repeating_values = self.sizes[7];
         if uncompressed_len != (n_unique_values + n_repeating_values) {
             return Err(anyhow!(
                 "uncompressed length {}, {} unique values, {} repeating values",
@@ -137,26 +167,54 @@ where
             ));
         }
 
-        decompressor.unpack_repeating_values(&mut all_values)?;
-        let bucket_index_per_elm = decompressor.unpack_bucket_index_per_elm()?;
-        let data = decompressor.reconstruct_data(&all_values, &bucket_index_per_elm)?;
-        let n = decompressor.check_zero_tail()?;
+        self.step = "repeating values";
+        self.unpack_repeating_values(&mut all_values)?;
+        self.step = "bucket indices";
+        let bucket_index_per_elm = self.unpack_bucket_index_per_elm()?;
+        self.step = "reconstruction";
+        let data = self.reconstruct_data(&all_values, &bucket_index_per_elm)?;
+        self.step = "tail";
+        let n = self.check_zero_tail()?;
         Ok((data, n))
     }
 
+    fn make_failure_point(&self) -> FailurePoint {
+        let index = if self.exhausted || self.offset == 0 {
+            self.offset
+        } else {
+            self.offset - 1
+        };
+        FailurePoint {
+            stage: Stage::Decompression,
+            index,
+            state: json!({
+                "step": self.step,
+                "sizes": self.sizes,
+            }),
+            partial: json!({
+                "unpacked_values": self.n_unpacked,
+            }),
+        }
+    }
+
+    fn next_word(&mut self, missing: &'static str) -> eyre::Result<BigUint> {
+        let Some(el) = self.current.next() else {
+            self.exhausted = true;
+            return Err(anyhow!(missing));
+        };
+        self.offset += 1;
+        Ok(el)
+    }
+
     fn unpack_unique_values(&mut self, decompressed_dst: &mut Vec<BigUint>) -> eyre::Result<()> {
         let bucket_bounds = make_bucket_bounds();
 
-        // not including the largest bucket (which doesn't do any
-        // packing)
-        let pack_counts: [usize; 5] = [2, 3, 4, 8, 16];
-
         self.copy_largest_bucket(decompressed_dst)?;
-        for i in 0..5 {
+        for i in 1..6 {
             self.unpack_felts(
-                self.sizes[i + 2],
-                &bucket_bounds[i + 1],
-                pack_counts[i],
+                self.sizes[i + 1],
+                &bucket_bounds[i],
+                BUCKET_PACK_COUNTS[i],
                 decompressed_dst,
             )?;
         }
@@ -190,10 +248,10 @@ where
         let n_elms = self.sizes[1];
         for i in 0..n_elms {
             let el = self
-                .current
-                .next()
-                .with_context(|| format!("large element {} of {} not found", i, n_elms))?;
+                .next_word("large element not found")
+                .with_context(|| format!("element {} of {}", i, n_elms))?;
             decompressed_dst.push(el);
+            self.n_unpacked += 1;
         }
 
         Ok(())
@@ -214,8 +272,8 @@ where
 
     fn reconstruct_data(
         &mut self,
-        all_values: &Vec<BigUint>,
-        bucket_index_per_elm: &Vec<BigUint>,
+        all_values: &[BigUint],
+        bucket_index_per_elm: &[BigUint],
     ) -> eyre::Result<Vec<BigUint>> {
         // input includes repeated values count but that's just a
         // placeholder - the offset after the last segment (AKA the
@@ -274,22 +332,21 @@ where
         n_elms_per_felt: usize,
         decompressed_dst: &mut Vec<BigUint>,
     ) -> eyre::Result<()> {
-        let felt = self
-            .current
-            .next()
-            .context("iterator finished before going through sizes")?;
+        let felt = self.next_word("iterator finished before going through sizes")?;
         let (mut elements, rest) = unpack_felt(felt, elm_bound, n_elms_per_felt)?;
         if !rest.is_zero() {
             return Err(anyhow!("high bits set"));
         }
 
+        self.n_unpacked += elements.len();
         decompressed_dst.append(&mut elements);
         Ok(())
     }
 
     fn check_zero_tail(&mut self) -> eyre::Result<usize> {
         let mut n = 0;
-        while let Some(el) = self.current.next() {
+        for el in self.current.by_ref() {
+            self.offset += 1;
             n += 1;
             if !el.is_zero() {
                 return Err(anyhow!("Extra tail"));
@@ -0,0 +1,224 @@
+use alloy::primitives::FixedBytes;
+use num_bigint::BigUint;
+use serde::Serialize;
+use serde_json::Value;
+
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use crate::error::Error;
+
+/// Number of words shown on each side of the failing one.
+const WINDOW_RADIUS: usize = 8;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Stage {
+    Decompression,
+    Parsing,
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Stage::Decompression => f.write_str("decompression"),
+            Stage::Parsing => f.write_str("parsing"),
+        }
+    }
+}
+
+/// Position and state of a failed decompression or parse, reported
+/// by `Decompressor` or `StateUpdateParser`.
+#[derive(Debug)]
+pub struct FailurePoint {
+    pub stage: Stage,
+    /// index of the word being interpreted (equal to the sequence
+    /// length when the sequence ended prematurely)
+    pub index: usize,
+    pub state: Value,
+    /// data decoded before the failure
+    pub partial: Value,
+}
+
+impl fmt::Display for FailurePoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} failed at word {}", self.stage, self.index)
+    }
+}
+
+/// Origin of a state update on Ethereum.
+#[derive(Clone, Debug, Default)]
+pub struct UpdateSource {
+    pub block_no: u64,
+    /// not known for previously-dumped sequences
+    pub tx_hash: Option<FixedBytes<32>>,
+    /// empty for calldata updates
+    pub blob_hashes: Vec<FixedBytes<32>>,
+}
+
+/// Report of a failed decompression or parse.
+#[derive(Debug)]
+pub struct Diagnostic {
+    pub error: String,
+    pub format: &'static str,
+    pub source: Option<UpdateSource>,
+    pub stage: Stage,
+    pub index: usize,
+    pub seq_len: usize,
+    /// words around the failing one, with their indices
+    pub window: Vec<(usize, BigUint)>,
+    pub state: Value,
+    pub partial: Value,
+}
+
+impl Diagnostic {
+    /// Makes a report of `err`, if it has a `FailurePoint` - which
+    /// must refer to `seq`, i.e. the compressed sequence for
+    /// decompression failures and the uncompressed one otherwise.
+    pub fn new(
+        err: &Error,
+        seq: &[BigUint],
+        format: &'static str,
+        source: Option<&UpdateSource>,
+    ) -> Option<Self> {
+        let point = match err {
+            Error::Decompression { point, .. } | Error::Parse { point, .. } => point,
+            _ => {
+                return None;
+            }
+        };
+        let first = point.index.saturating_sub(WINDOW_RADIUS);
+        let last = (point.index + WINDOW_RADIUS + 1).min(seq.len());
+        let window = (first..last).map(|i| (i, seq[i].clone())).collect();
+        Some(Self {
+            error: err.to_string(),
+            format,
+            source: source.cloned(),
+            stage: point.stage,
+            index: point.index,
+            seq_len: seq.len(),
+            window,
+            state: point.state.clone(),
+            partial: point.partial.clone(),
+        })
+    }
+
+    /// Logs the report and writes it into `path`.
+    pub fn report(&self, path: &Path) -> eyre::Result<()> {
+        tracing::error!("{}", self);
+        tracing::info!("writing diagnostics into {:?}...", path);
+        fs::write(path, self.to_string())?;
+        Ok(())
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{} of {} state update failed", self.stage, self.format)?;
+        writeln!(f, "error: {}", self.error)?;
+        if let Some(source) = &self.source {
+            write!(f, "source: Ethereum block {}", source.block_no)?;
+            if let Some(tx_hash) = &source.tx_hash {
+                write!(f, ", tx {}", tx_hash)?;
+            }
+            writeln!(f)?;
+            for blob_hash in source.blob_hashes.iter() {
+                writeln!(f, "blob: {}", blob_hash)?;
+            }
+        }
+
+        writeln!(f, "word: {} of {}", self.index, self.seq_len)?;
+        writeln!(f, "state: {}", self.state)?;
+        writeln!(f, "window:")?;
+        for (i, word) in self.window.iter() {
+            let mark = if *i == self.index { '>' } else { ' ' };
+            writeln!(f, "{} {:>6} {:#x}", mark, i, word)?;
+        }
+        if self.index >= self.seq_len {
+            writeln!(f, "> {:>6} (end of sequence)", self.index)?;
+        }
+
+        write!(f, "partial decode: {}", self.partial)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloy::primitives::FixedBytes;
+    use num_bigint::BigUint;
+
+    use super::{Diagnostic, Stage, UpdateSource};
+    use crate::annotation::Annotator;
+    use crate::da_format::FormatVersion;
+    use crate::parser::StateUpdateParser;
+    use crate::test_util::make_lookup;
+
+    #[test]
+    fn truncated_update() {
+        let lookup = make_lookup();
+
+        // 2 contracts, the second one missing its last storage value
+        let words: Vec<u64> = vec![2, 0x1234, 0b100, 5, 6, 0x5678, 0b1000, 7, 8, 9];
+        let seq: Vec<BigUint> = words.into_iter().map(BigUint::from).collect();
+        let format = FormatVersion::V0_13_3.get_format();
+        let err = StateUpdateParser::parse(seq.iter().cloned(), format, lookup, Annotator::none())
+            .unwrap_err();
+        let source = UpdateSource {
+            block_no: 10,
+            tx_hash: Some(FixedBytes::repeat_byte(1)),
+            blob_hashes: vec![FixedBytes::repeat_byte(2)],
+        };
+        let diag = Diagnostic::new(&err, &seq, format.name(), Some(&source)).unwrap();
+        assert_eq!(diag.stage, Stage::Parsing);
+        assert_eq!(diag.index, 10);
+        assert_eq!(diag.window.len(), 8);
+        assert_eq!(diag.window[0].0, 2);
+        assert_eq!(diag.state["section"], "contract updates");
+        assert_eq!(diag.state["contract"], 1);
+        assert_eq!(diag.partial["storage_diffs"][0]["address"], "0x1234");
+        assert_eq!(
+            diag.error,
+            "parsing failed at word 10: contract 1 of 2: storage update 1 of 2: Missing storage value"
+        );
+
+        let text = diag.to_string();
+        assert!(text.contains("source: Ethereum block 10, tx 0x0101"));
+        assert!(text.contains("blob: 0x0202"));
+        assert!(text.contains(">     10 (end of sequence)\n"));
+    }
+
+    #[test]
+    fn truncated_declarations() {
+        let lookup = make_lookup();
+
+        // 1 contract, then 2 declarations, the second one missing its
+        // compiled class hash
+        let words: Vec<u64> = vec![1, 0x1234, 0b100, 5, 6, 2, 0xc1, 0xd1, 0xc2];
+        let seq: Vec<BigUint> = words.into_iter().map(BigUint::from).collect();
+        let format = FormatVersion::V0_13_3.get_format();
+        let err = StateUpdateParser::parse(seq.iter().cloned(), format, lookup, Annotator::none())
+            .unwrap_err();
+        let diag = Diagnostic::new(&err, &seq, format.name(), None).unwrap();
+        assert_eq!(diag.state["section"], "class declarations");
+        // completed section
+        assert_eq!(diag.partial["storage_diffs"][0]["address"], "0x1234");
+        // failed section
+        let declared_classes = diag.partial["declared_classes"].as_array().unwrap();
+        assert_eq!(declared_classes.len(), 1);
+        assert_eq!(declared_classes[0]["class_hash"], "0xc1");
+    }
+
+    #[test]
+    fn bad_header() {
+        let seq = vec![BigUint::from(1u32) << 250, BigUint::from(3u32)];
+        let format = FormatVersion::V0_13_3.get_format();
+        let err = format.decompress(&seq).unwrap_err();
+        let diag = Diagnostic::new(&err, &seq, format.name(), None).unwrap();
+        assert_eq!(diag.stage, Stage::Decompression);
+        assert_eq!(diag.index, 0);
+        assert_eq!(diag.window.len(), 2);
+        assert_eq!(diag.state["step"], "header");
+        assert!(!diag.to_string().contains("source:"));
+    }
+}
@@ -1,23 +1,33 @@
-use alloy::primitives::FixedBytes;
-use eyre::anyhow;
+use alloy::{eips::eip4844::kzg_to_versioned_hash, primitives::FixedBytes};
+use futures::{
+    future::try_join_all,
+    stream::{self, Stream, StreamExt},
+};
 use num_bigint::BigUint;
 use serde::Deserialize;
 use tokio::{
+    sync::OnceCell,
     task,
     time::{Duration, sleep},
 };
 
+use std::collections::HashMap;
 use std::fs;
-use std::io::Write;
+use std::future::Future;
+use std::io::{self, Write};
 use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
 
-use crate::blob_util::parse_str_to_blob_data;
+use crate::blob_util::{blob_bytes_to_data, parse_str_to_blob_bytes, verify_blob};
+use crate::error::{Error, Result};
 use crate::transform::Transformer;
 
 /// `MAX_RETRIES` is the maximum number of retries on failed blob retrieval.
 const MAX_RETRIES: usize = 5;
 /// The interval in seconds to wait before retrying to fetch a blob.
 const FAILED_FETCH_RETRY_INTERVAL_S: u64 = 10;
+/// Beacon chain slot duration (the same for mainnet and testnets).
+const SECONDS_PER_SLOT: u64 = 12;
 
 #[derive(Deserialize)]
 struct JsonResponse {
@@ -25,37 +35,81 @@ struct JsonResponse {
     data: String,
 }
 
+#[derive(Deserialize)]
+struct GenesisResponse {
+    data: GenesisData,
+}
+
+#[derive(Deserialize)]
+struct GenesisData {
+    genesis_time: String,
+}
+
+#[derive(Deserialize)]
+struct SidecarsResponse {
+    data: Vec<Sidecar>,
+}
+
+#[derive(Deserialize)]
+struct Sidecar {
+    blob: String,
+    kzg_commitment: String,
+}
+
+pub enum BlobSource {
+    /// Blobscan-style JSON API, queried by versioned hash appended to
+    /// `url_base`
+    Blobscan { url_base: String },
+    /// consensus client's blob sidecar API at `url`
+    Beacon { url: String },
+    /// blobs previously saved into the cache directory
+    Local,
+}
+
+/// Downloads (or loads) blobs, verifies and transforms them. Can be
+/// shared by concurrent downloads.
 pub struct Downloader {
     client: reqwest::Client,
-    blob_url_base: String,
-    transformer: Transformer,
+    source: BlobSource,
+    genesis_time: OnceCell<u64>,
+    local_index: OnceCell<HashMap<FixedBytes<32>, PathBuf>>,
+    transformer: Arc<Transformer>,
     save: bool,
     cache_dir: PathBuf,
     prune: bool,
-    doomed: Option<PathBuf>,
+    // blobs saved (while pruning) by updates not yet fully processed
+    saved: Mutex<HashMap<FixedBytes<32>, PathBuf>>,
 }
 
 impl Downloader {
     pub fn new(
         client: reqwest::Client,
-        blob_url_base: String,
+        source: BlobSource,
         save: bool,
         cache_dir: PathBuf,
         prune: bool,
     ) -> Self {
-        let transformer = Transformer::new();
+        let transformer = Arc::new(Transformer::new());
         Self {
             client,
-            blob_url_base,
+            source,
+            genesis_time: OnceCell::new(),
+            local_index: OnceCell::new(),
             transformer,
             save,
             cache_dir,
             prune,
-            doomed: None,
+            saved: Mutex::new(HashMap::new()),
         }
     }
 
-    async fn repeat_get(&mut self, url: &str) -> eyre::Result<reqwest::Response> {
+    /// Beacon sidecars are looked up by slot, which must be computed
+    /// from the timestamp of the Ethereum block including the blobs.
+    pub fn needs_block_timestamp(&self) -> bool {
+        matches!(self.source, BlobSource::Beacon { .. })
+    }
+
+    async fn repeat_get(&self, url: &str) -> Result<reqwest::Response> {
         for attempt in 1..=MAX_RETRIES {
             match self.client.get(url).send().await {
                 Ok(response) => {
@@ -64,7 +118,7 @@ impl Downloader {
                     // 10x statuses aren't really expected, but just
                     // in case of a strange server...
                     if status_code < 200 {
-                        return Err(anyhow!("{} got status {}", url, status));
+                        return Err(transport_error(url, format!("got status {}", status)));
                     }
 
                     if status_code >= 400 {
@@ -81,43 +135,564 @@ impl Downloader {
             }
         }
 
-        Err(anyhow!("can't get blob"))
+        Err(transport_error(
+            url,
+            format!("failed {} attempts", MAX_RETRIES),
+        ))
     }
 
-    pub async fn download(&mut self, blob_hash: &FixedBytes<32>) -> eyre::Result<Vec<BigUint>> {
-        let url = format!(
-            "{}0x{}",
-            self.blob_url_base,
-            hex::encode(blob_hash.as_slice())
-        );
-        let response = self.repeat_get(&url).await?;
-        let text = response.text().await?;
-        let json_response = match serde_json::from_str::<JsonResponse>(&text) {
-            Ok(rsp) => rsp,
+    async fn get_json<T>(&self, url: &str) -> Result<T>
+    where
+        T: for<'de> Deserialize<'de>,
+    {
+        let response = self.repeat_get(url).await?;
+        let text = response
+            .text()
+            .await
+            .map_err(|e| transport_error(url, e.to_string()))?;
+        match serde_json::from_str::<T>(&text) {
+            Ok(rsp) => Ok(rsp),
             Err(e) => {
                 tracing::warn!("URL {} has invalid JSON: {} ({:?})", url, text, e);
-                return Err(e.into());
+                Err(transport_error(url, format!("invalid JSON: {}", e)))
+            }
+        }
+    }
+
+    /// Downloads and transforms the blobs with versioned hashes
+    /// `blob_hashes`, returning the concatenated result.
+    /// `block_timestamp` is only needed by (and required for) the
+    /// beacon source.
+    pub async fn download(
+        &self,
+        blob_hashes: &[FixedBytes<32>],
+        block_timestamp: Option<u64>,
+    ) -> Result<Vec<BigUint>> {
+        let raw_blobs = match &self.source {
+            BlobSource::Blobscan { url_base } => {
+                try_join_all(blob_hashes.iter().map(|blob_hash| async move {
+                    let url = format!("{}0x{}", url_base, hex::encode(blob_hash.as_slice()));
+                    let json_response = self.get_json::<JsonResponse>(&url).await?;
+                    Ok::<_, Error>((*blob_hash, json_response))
+                }))
+                .await?
+            }
+            BlobSource::Beacon { url } => {
+                let block_timestamp = block_timestamp.ok_or_else(|| {
+                    Error::Usage("beacon blob source requires block timestamp".to_string())
+                })?;
+                self.download_sidecars(url, blob_hashes, block_timestamp)
+                    .await?
+            }
+            BlobSource::Local => {
+                let mut raw_blobs = Vec::with_capacity(blob_hashes.len());
+                for blob_hash in blob_hashes.iter() {
+                    let raw_blob = self.load_local(blob_hash).await?;
+                    raw_blobs.push((*blob_hash, raw_blob));
+                }
+                raw_blobs
+            }
+        };
+
+        // blobs of a single update are transformed in parallel
+        let transformed = try_join_all(
+            raw_blobs
+                .into_iter()
+                .map(
+                    |(blob_hash, raw_blob)| async move { self.process(&blob_hash, raw_blob).await },
+                ),
+        )
+        .await?;
+        Ok(transformed.concat())
+    }
+
+    /// Removes the blobs with versioned hashes `blob_hashes`, saved
+    /// by an update which has been fully processed (i.e. committed),
+    /// if pruning.
+    pub fn prune(&self, blob_hashes: &[FixedBytes<32>]) -> Result<()> {
+        if !self.prune {
+            return Ok(());
+        }
+
+        let mut saved = self.saved.lock().unwrap();
+        for blob_hash in blob_hashes.iter() {
+            if let Some(path) = saved.remove(blob_hash) {
+                fs::remove_file(path)?;
             }
+        }
+
+        Ok(())
+    }
+
+    async fn download_sidecars(
+        &self,
+        url: &str,
+        blob_hashes: &[FixedBytes<32>],
+        block_timestamp: u64,
+    ) -> Result<Vec<(FixedBytes<32>, JsonResponse)>> {
+        let genesis_time = self.get_genesis_time(url).await?;
+        let slot = timestamp_to_slot(genesis_time, block_timestamp)
+            .map_err(|e| transport_error(url, e))?;
+        let sidecars_url = format!("{}/eth/v1/beacon/blob_sidecars/{}", url, slot);
+        let sidecars = self.get_json::<SidecarsResponse>(&sidecars_url).await?;
+
+        let mut indexed = Vec::with_capacity(sidecars.data.len());
+        for sidecar in sidecars.data {
+            let raw_commitment = decode_commitment(&sidecar.kzg_commitment)
+                .map_err(|e| transport_error(&sidecars_url, e))?;
+
+            indexed.push((kzg_to_versioned_hash(&raw_commitment), sidecar));
+        }
+
+        let mut raw_blobs = Vec::with_capacity(blob_hashes.len());
+        for blob_hash in blob_hashes.iter() {
+            let pos = indexed
+                .iter()
+                .position(|(h, _)| h == blob_hash)
+                .ok_or_else(|| Error::MissingBlob {
+                    versioned_hash: *blob_hash,
+                    location: format!("slot {}", slot),
+                })?;
+            let (_, sidecar) = indexed.swap_remove(pos);
+            raw_blobs.push((
+                *blob_hash,
+                JsonResponse {
+                    commitment: sidecar.kzg_commitment,
+                    data: sidecar.blob,
+                },
+            ));
+        }
+
+        Ok(raw_blobs)
+    }
+
+    async fn load_local(&self, blob_hash: &FixedBytes<32>) -> Result<JsonResponse> {
+        let local_index = self
+            .local_index
+            .get_or_try_init(|| async { self.make_local_index() })
+            .await?;
+        let path = local_index
+            .get(blob_hash)
+            .ok_or_else(|| Error::MissingBlob {
+                versioned_hash: *blob_hash,
+                location: "cache directory".to_string(),
+            })?;
+        let commitment = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .ok_or_else(|| io::Error::other(format!("invalid blob file name {:?}", path)))?
+            .to_string();
+        let data = fs::read_to_string(path)?;
+        Ok(JsonResponse { commitment, data })
+    }
+
+    // saved blobs are named by their commitment, but transactions
+    // refer to them by versioned hash
+    fn make_local_index(&self) -> Result<HashMap<FixedBytes<32>, PathBuf>> {
+        let mask = self.cache_dir.join("*.blob");
+        let mask_str = mask
+            .to_str()
+            .ok_or_else(|| io::Error::other("invalid cache dir"))?;
+        let mut index = HashMap::new();
+        for raw_entry in glob::glob(mask_str).map_err(Error::from_glob)? {
+            let entry = raw_entry.map_err(Error::from_glob)?;
+            let opt_commitment = entry
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| decode_commitment(stem).ok());
+            match opt_commitment {
+                Some(commitment) => {
+                    index.insert(kzg_to_versioned_hash(&commitment), entry);
+                }
+                None => tracing::warn!("ignoring {:?}", entry),
+            }
+        }
+
+        tracing::debug!("{} saved blob(s) found", index.len());
+        Ok(index)
+    }
+
+    async fn get_genesis_time(&self, url: &str) -> Result<u64> {
+        let genesis_time = self
+            .genesis_time
+            .get_or_try_init(|| async {
+                let genesis_url = format!("{}/eth/v1/beacon/genesis", url);
+                let genesis = self.get_json::<GenesisResponse>(&genesis_url).await?;
+                str::parse::<u64>(&genesis.data.genesis_time)
+                    .map_err(|_| transport_error(&genesis_url, "invalid beacon genesis time"))
+            })
+            .await?;
+        Ok(*genesis_time)
+    }
+
+    async fn process(
+        &self,
+        blob_hash: &FixedBytes<32>,
+        raw_blob: JsonResponse,
+    ) -> Result<Vec<BigUint>> {
+        let integrity_error = |message: String| Error::BlobIntegrity {
+            versioned_hash: *blob_hash,
+            message,
         };
-        if self.save {
-            let target_name = format!("{}.blob", json_response.commitment);
+        let blob =
+            parse_str_to_blob_bytes(&raw_blob.data).map_err(|e| integrity_error(e.to_string()))?;
+        let commitment = decode_commitment(&raw_blob.commitment).map_err(integrity_error)?;
+        verify_blob(&blob, &commitment, blob_hash)?;
+
+        // local blobs are already saved
+        if self.save && !matches!(self.source, BlobSource::Local) {
+            let target_name = format!("{}.blob", raw_blob.commitment);
             let target_path = self.cache_dir.join(target_name);
             let mut target = fs::File::create(&target_path)?;
-            target.write_all(json_response.data.as_bytes())?;
+            target.write_all(raw_blob.data.as_bytes())?;
             if self.prune {
-                if let Some(doomed) = &self.doomed {
-                    fs::remove_file(doomed)?;
-                }
-
-                self.doomed = Some(target_path);
+                let mut saved = self.saved.lock().unwrap();
+                saved.insert(*blob_hash, target_path);
             }
         }
 
-        // copying thousands of constants is inefficient - but so
-        // is locking access to them...
-        let transformer = self.transformer.clone();
-        let words = parse_str_to_blob_data(&json_response.data)?;
+        let transformer = Arc::clone(&self.transformer);
+        let words = blob_bytes_to_data(&blob);
         let transformed = task::spawn_blocking(move || transformer.transform(&words)).await?;
         Ok(transformed)
     }
 }
+
+/// Runs `fetch` on up to `concurrency` items at once, yielding the
+/// results in the order of `items` (no matter when they finish).
+pub fn fetch_in_order<I, F, Fut>(
+    items: I,
+    concurrency: usize,
+    fetch: F,
+) -> impl Stream<Item = Fut::Output>
+where
+    I: IntoIterator,
+    F: FnMut(I::Item) -> Fut,
+    Fut: Future,
+{
+    stream::iter(items).map(fetch).buffered(concurrency)
+}
+
+fn transport_error(url: &str, message: impl Into<String>) -> Error {
+    Error::Transport {
+        url: url.to_string(),
+        message: message.into(),
+    }
+}
+
+fn decode_commitment(commitment: &str) -> std::result::Result<Vec<u8>, String> {
+    let raw = commitment.strip_prefix("0x").unwrap_or(commitment);
+    let bytes = hex::decode(raw).map_err(|_| format!("invalid commitment {}", commitment))?;
+    if bytes.len() != 48 {
+        return Err(format!("commitment has {} bytes", bytes.len()));
+    }
+
+    Ok(bytes)
+}
+
+fn timestamp_to_slot(genesis_time: u64, block_timestamp: u64) -> std::result::Result<u64, String> {
+    if block_timestamp < genesis_time {
+        return Err(format!(
+            "block timestamp {} precedes beacon genesis {}",
+            block_timestamp, genesis_time
+        ));
+    }
+
+    Ok((block_timestamp - genesis_time) / SECONDS_PER_SLOT)
+}
+
+#[cfg(test)]
+mod tests {
+    use alloy::{
+        eips::eip4844::kzg_to_versioned_hash,
+        primitives::{FixedBytes, fixed_bytes},
+    };
+    use futures::stream::StreamExt;
+    use num_bigint::BigUint;
+    use num_traits::Zero;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use tokio::time::{Duration, sleep};
+
+    use std::sync::Mutex;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::{BlobSource, Downloader, fetch_in_order, timestamp_to_slot};
+    use crate::blob_util::{FIELD_ELEMENTS_PER_BLOB, blob_bytes_to_data};
+    use crate::error::{Error, Result};
+    use crate::transform::Transformer;
+
+    const GENESIS_TIME: u64 = 1606824023;
+
+    // commitment of the zero polynomial, i.e. the point at infinity
+    fn zero_commitment() -> String {
+        format!("0xc0{}", "00".repeat(47))
+    }
+
+    fn zero_blob() -> String {
+        format!("0x{}", "00".repeat(FIELD_ELEMENTS_PER_BLOB * 32))
+    }
+
+    // commitment & versioned hash of sample_blob(), computed by the
+    // reference c-kzg implementation (with the mainnet trusted setup)
+    const SAMPLE_COMMITMENT: &str = "0x9831f06075c97d865f59a56613d06eeb2cf2c9f8d58d66f78da4a81f65bc163739653dd5df7de38ea0928ce9f173d39f";
+    const SAMPLE_VERSIONED_HASH: FixedBytes<32> =
+        fixed_bytes!("01775a4ba7f0c923c30a7d5da4c9ffdb50e984effca104c65cca24e505989579");
+
+    // a different element at each position
+    fn sample_blob() -> Vec<u8> {
+        let mut blob = vec![0u8; FIELD_ELEMENTS_PER_BLOB * 32];
+        for (i, el) in blob.chunks_mut(32).enumerate() {
+            let value = (i as u64 + 1).wrapping_mul(0x9e3779b97f4a7c15);
+            el[24..].copy_from_slice(&value.to_be_bytes());
+        }
+        blob
+    }
+
+    // minimal HTTP server, answering each request path by `respond`
+    async fn serve<F>(respond: F) -> String
+    where
+        F: Fn(&str) -> (&'static str, String) + Send + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut buf = vec![0u8; 4096];
+                let n = stream.read(&mut buf).await.unwrap();
+                let request = String::from_utf8_lossy(&buf[..n]);
+                let path = request.split_whitespace().nth(1).unwrap_or_default();
+                let (status, body) = respond(path);
+                let response = format!(
+                    "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                );
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+        format!("http://{}", addr)
+    }
+
+    // stand-in for a consensus client, answering (only) the genesis &
+    // sidecar requests of the given slot
+    async fn serve_beacon(slot: u64) -> String {
+        serve(move |path| {
+            if path == "/eth/v1/beacon/genesis" {
+                let body = format!("{{\"data\":{{\"genesis_time\":\"{}\"}}}}", GENESIS_TIME);
+                ("200 OK", body)
+            } else if path == format!("/eth/v1/beacon/blob_sidecars/{}", slot) {
+                let body = format!(
+                    "{{\"data\":[{{\"index\":\"0\",\"blob\":\"{}\",\"kzg_commitment\":\"{}\"}}]}}",
+                    zero_blob(),
+                    zero_commitment()
+                );
+                ("200 OK", body)
+            } else {
+                ("404 Not Found", String::new())
+            }
+        })
+        .await
+    }
+
+    // stand-in for Blobscan, answering any versioned hash by `blob`
+    // and `commitment`
+    async fn serve_blobscan(blob: &[u8], commitment: &str) -> String {
+        let body = format!(
+            "{{\"commitment\":\"{}\",\"data\":\"0x{}\"}}",
+            commitment,
+            hex::encode(blob)
+        );
+        let url = serve(move |_| ("200 OK", body.clone())).await;
+        format!("{}/blobs/", url)
+    }
+
+    async fn download_sample(blob: &[u8], versioned_hash: FixedBytes<32>) -> Result<Vec<BigUint>> {
+        let url_base = serve_blobscan(blob, SAMPLE_COMMITMENT).await;
+        let downloader = Downloader::new(
+            reqwest::Client::new(),
+            BlobSource::Blobscan { url_base },
+            false,
+            std::env::temp_dir(),
+            false,
+        );
+        downloader.download(&[versioned_hash], None).await
+    }
+
+    fn integrity_message(res: Result<Vec<BigUint>>) -> String {
+        match res {
+            Err(Error::BlobIntegrity { message, .. }) => message,
+            other => panic!("unexpected result {:?}", other.map(|seq| seq.len())),
+        }
+    }
+
+    fn make_downloader(url: String) -> Downloader {
+        Downloader::new(
+            reqwest::Client::new(),
+            BlobSource::Beacon { url },
+            false,
+            std::env::temp_dir(),
+            false,
+        )
+    }
+
+    #[test]
+    fn slot_mapping() {
+        assert_eq!(timestamp_to_slot(GENESIS_TIME, GENESIS_TIME).unwrap(), 0);
+        assert_eq!(
+            timestamp_to_slot(GENESIS_TIME, GENESIS_TIME + 25).unwrap(),
+            2
+        );
+        assert!(timestamp_to_slot(GENESIS_TIME, GENESIS_TIME - 1).is_err());
+    }
+
+    #[tokio::test]
+    async fn beacon_sidecar() {
+        let slot = 8626176;
+        let url = serve_beacon(slot).await;
+        let downloader = make_downloader(url);
+        let commitment = hex::decode(&zero_commitment()[2..]).unwrap();
+        let blob_hash = kzg_to_versioned_hash(&commitment);
+        let timestamp = GENESIS_TIME + slot * 12 + 5;
+        let seq = downloader
+            .download(&[blob_hash], Some(timestamp))
+            .await
+            .unwrap();
+        assert_eq!(seq.len(), FIELD_ELEMENTS_PER_BLOB);
+        assert!(seq.iter().all(|el| el.is_zero()));
+    }
+
+    #[tokio::test]
+    async fn sample_blob_verified() {
+        let commitment = hex::decode(&SAMPLE_COMMITMENT[2..]).unwrap();
+        assert_eq!(kzg_to_versioned_hash(&commitment), SAMPLE_VERSIONED_HASH);
+        let blob = sample_blob();
+        let seq = download_sample(&blob, SAMPLE_VERSIONED_HASH).await.unwrap();
+        let expected = Transformer::new().transform(&blob_bytes_to_data(&blob));
+        assert_eq!(seq, expected);
+    }
+
+    #[tokio::test]
+    async fn flipped_element() {
+        let mut blob = sample_blob();
+        blob[1000 * 32 + 31] ^= 1;
+        let res = download_sample(&blob, SAMPLE_VERSIONED_HASH).await;
+        let message = integrity_message(res);
+        assert_eq!(
+            message,
+            format!("blob doesn't match commitment {}", SAMPLE_COMMITMENT)
+        );
+    }
+
+    #[tokio::test]
+    async fn mismatched_versioned_hash() {
+        let zero_commitment = hex::decode(&zero_commitment()[2..]).unwrap();
+        let versioned_hash = kzg_to_versioned_hash(&zero_commitment);
+        let res = download_sample(&sample_blob(), versioned_hash).await;
+        let message = integrity_message(res);
+        assert_eq!(
+            message,
+            format!(
+                "commitment {} has versioned hash {}",
+                SAMPLE_COMMITMENT, SAMPLE_VERSIONED_HASH
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn local_blob() {
+        let cache_dir = std::env::temp_dir().join(format!("local-blob-{}", std::process::id()));
+        std::fs::create_dir_all(&cache_dir).unwrap();
+        let blob_path = cache_dir.join(format!("{}.blob", zero_commitment()));
+        std::fs::write(&blob_path, zero_blob()).unwrap();
+
+        let downloader = Downloader::new(
+            reqwest::Client::new(),
+            BlobSource::Local,
+            true,
+            cache_dir.clone(),
+            true,
+        );
+        let commitment = hex::decode(&zero_commitment()[2..]).unwrap();
+        let blob_hash = kzg_to_versioned_hash(&commitment);
+        let seq = downloader.download(&[blob_hash], None).await.unwrap();
+        assert_eq!(seq.len(), FIELD_ELEMENTS_PER_BLOB);
+        let missing = FixedBytes::<32>::repeat_byte(1);
+        assert!(downloader.download(&[missing], None).await.is_err());
+
+        std::fs::remove_dir_all(&cache_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn beacon_sidecar_not_found() {
+        let slot = 8626176;
+        let url = serve_beacon(slot).await;
+        let downloader = make_downloader(url);
+        let blob_hash = FixedBytes::<32>::repeat_byte(1);
+        let timestamp = GENESIS_TIME + slot * 12;
+        let res = downloader.download(&[blob_hash], Some(timestamp)).await;
+        assert!(res.is_err());
+    }
+
+    #[tokio::test]
+    async fn prune_after_commit() {
+        let slot = 8626176;
+        let url = serve_beacon(slot).await;
+        let cache_dir = std::env::temp_dir().join(format!("prune-blob-{}", std::process::id()));
+        std::fs::create_dir_all(&cache_dir).unwrap();
+        let downloader = Downloader::new(
+            reqwest::Client::new(),
+            BlobSource::Beacon { url },
+            true,
+            cache_dir.clone(),
+            true,
+        );
+        let commitment = hex::decode(&zero_commitment()[2..]).unwrap();
+        let blob_hash = kzg_to_versioned_hash(&commitment);
+        let timestamp = GENESIS_TIME + slot * 12;
+        downloader
+            .download(&[blob_hash], Some(timestamp))
+            .await
+            .unwrap();
+
+        // saved blobs stay until their update is committed
+        let blob_path = cache_dir.join(format!("{}.blob", zero_commitment()));
+        assert!(blob_path.exists());
+        downloader.prune(&[FixedBytes::repeat_byte(1)]).unwrap();
+        assert!(blob_path.exists());
+        downloader.prune(&[blob_hash]).unwrap();
+        assert!(!blob_path.exists());
+
+        std::fs::remove_dir_all(&cache_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn ordered_fetch() {
+        // earlier items take longer
+        let delays = [50u64, 40, 30, 20, 10, 0];
+        let finished = Mutex::new(Vec::new());
+        let in_flight = AtomicUsize::new(0);
+        let max_in_flight = AtomicUsize::new(0);
+        let results: Vec<usize> = fetch_in_order(0..delays.len(), 3, |i| {
+            let finished = &finished;
+            let in_flight = &in_flight;
+            let max_in_flight = &max_in_flight;
+            async move {
+                let n = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                max_in_flight.fetch_max(n, Ordering::SeqCst);
+                sleep(Duration::from_millis(delays[i])).await;
+                in_flight.fetch_sub(1, Ordering::SeqCst);
+                finished.lock().unwrap().push(i);
+                i
+            }
+        })
+        .collect()
+        .await;
+        assert_eq!(results, vec![0, 1, 2, 3, 4, 5]);
+        assert_eq!(max_in_flight.load(Ordering::SeqCst), 3);
+        let finished = finished.into_inner().unwrap();
+        assert_ne!(finished, results);
+    }
+}
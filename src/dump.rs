@@ -1,11 +1,12 @@
 use eyre::{ContextCompat, anyhow};
 use num_bigint::BigUint;
+use num_traits::Num;
 
 use std::fs;
-use std::io::{LineWriter, Write};
-use std::path::PathBuf;
+use std::io::{BufRead, BufReader, LineWriter, Write};
+use std::path::{Path, PathBuf};
 
-pub fn uncond_dump(seq: &Vec<BigUint>, target: &PathBuf) -> eyre::Result<()> {
+pub fn uncond_dump(seq: &[BigUint], target: &PathBuf) -> eyre::Result<()> {
     tracing::debug!("dumping {:?}...", target);
     let file = fs::File::create(target)?;
     let mut writer = LineWriter::new(file);
@@ -16,6 +17,31 @@ pub fn uncond_dump(seq: &Vec<BigUint>, target: &PathBuf) -> eyre::Result<()> {
     Ok(())
 }
 
+pub fn uncond_load(source: &PathBuf) -> eyre::Result<Vec<BigUint>> {
+    let file = fs::File::open(source)?;
+    let mut elements = Vec::new();
+    for res in BufReader::new(file).lines() {
+        let ln = res?;
+        let (data, radix) = match ln.strip_prefix("0x") {
+            Some(tail) => (tail, 16),
+            None => (ln.as_str(), 10),
+        };
+        let el =
+            BigUint::from_str_radix(data, radix).map_err(|_| anyhow!("invalid integer {}", ln))?;
+        elements.push(el);
+    }
+
+    Ok(elements)
+}
+
+/// Extensions of files written by `Dumper` (and by parsing the
+/// dumped sequences).
+const DUMP_EXTENSIONS: [&str; 6] = ["seq", "seq_no", "unc", "anno", "jsonl", "diag"];
+
+/// Extension of the file recording the Starknet block number of a
+/// dumped sequence, which its format may depend on.
+const SEQ_NO_EXTENSION: &str = "seq_no";
+
 pub struct Dumper {
     dump: bool,
     cache_dir: PathBuf,
@@ -61,6 +87,26 @@ impl Dumper {
         Ok(())
     }
 
+    /// Removes dumps of Ethereum blocks from `block_no` onwards, and
+    /// restarts block numbering so that those blocks can be
+    /// processed again.
+    pub fn remove_from(&mut self, block_no: u64) -> eyre::Result<()> {
+        for ext in DUMP_EXTENSIONS {
+            let mask = self.cache_dir.join(format!("*.{}", ext));
+            for raw_entry in glob::glob(mask.to_str().context("invalid cache dir mask")?)? {
+                let entry = raw_entry?;
+                if parse_dump_name(&entry).is_some_and(|n| n >= block_no) {
+                    fs::remove_file(entry)?;
+                }
+            }
+        }
+
+        self.doomed = None;
+        self.cur_block_no = None;
+        self.cur_block_repeat = 0;
+        Ok(())
+    }
+
     pub fn make_dump_target(&self, ext: &str) -> eyre::Result<PathBuf> {
         let block_no = self
             .cur_block_no
@@ -74,10 +120,16 @@ impl Dumper {
         Ok(self.cache_dir.join(name))
     }
 
-    pub fn cond_dump(&mut self, seq: &Vec<BigUint>) -> eyre::Result<()> {
+    /// Dumps `seq` of the update for Starknet block `seq_no`, if
+    /// dumping.
+    pub fn cond_dump(&mut self, seq: &[BigUint], seq_no: u64) -> eyre::Result<()> {
         if self.dump {
             let seq_path = self.make_dump_target("seq")?;
             uncond_dump(seq, &seq_path)?;
+            fs::write(
+                self.make_dump_target(SEQ_NO_EXTENSION)?,
+                format!("{}\n", seq_no),
+            )?;
 
             if self.prune {
                 if let Some(doomed) = &self.doomed {
@@ -98,3 +150,27 @@ impl Dumper {
         Ok(())
     }
 }
+
+/// Returns the Starknet block number recorded for the sequence
+/// dumped into `dump` (by `Dumper::cond_dump`), if any - sequences
+/// dumped by older versions don't have it.
+pub fn load_seq_no(dump: &Path) -> eyre::Result<Option<u64>> {
+    let path = dump.with_extension(SEQ_NO_EXTENSION);
+    if !path.exists() {
+        return Ok(None);
+    }
+
+    let raw = fs::read_to_string(&path)?;
+    let seq_no = str::parse::<u64>(raw.trim())
+        .map_err(|_| anyhow!("invalid Starknet block number in {:?}", path))?;
+    Ok(Some(seq_no))
+}
+
+fn parse_dump_name(path: &Path) -> Option<u64> {
+    let stem = path.file_stem()?.to_str()?;
+    let block = match stem.split_once('-') {
+        Some((block, _)) => block,
+        None => stem,
+    };
+    block.parse().ok()
+}
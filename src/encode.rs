@@ -0,0 +1,287 @@
+use eyre::anyhow;
+use num_bigint::BigUint;
+
+use crate::blob_util::FIELD_ELEMENTS_PER_BLOB;
+use crate::compress::Compressor;
+use crate::da_format::DaFormat;
+use crate::packing::PackConst;
+use crate::state_diff::StateDiff;
+use crate::transform::Transformer;
+
+/// Serializes `state_diff` into the sequence parsed by
+/// `StateUpdateParser` with the same `format` (before compression).
+/// Contract addresses and storage keys are written as they are, i.e.
+/// without stateful compression.
+pub fn encode_state_diff(
+    state_diff: &StateDiff,
+    format: &dyn DaFormat,
+) -> eyre::Result<Vec<BigUint>> {
+    let sections = format.sections();
+    if !state_diff.class_declarations.is_empty()
+        && !sections
+            .iter()
+            .any(|section| section.has_class_declarations())
+    {
+        return Err(anyhow!("class declarations not supported"));
+    }
+
+    let mut seq = Vec::new();
+    for section in sections {
+        section.encode(state_diff, format, &mut seq)?;
+    }
+
+    Ok(seq)
+}
+
+pub(crate) fn encode_contract_updates(
+    seq: &mut Vec<BigUint>,
+    state_diff: &StateDiff,
+    pack_const: &PackConst,
+) -> eyre::Result<()> {
+    seq.push(BigUint::from(state_diff.contract_updates.len()));
+    for cu in state_diff.contract_updates.iter() {
+        seq.push(cu.address.to_biguint());
+        let packed = pack_const.pack_contract_update(
+            cu.new_class_hash.is_some(),
+            cu.nonce,
+            cu.storage_updates.len() as u64,
+        )?;
+        seq.push(packed);
+        if let Some(class_hash) = &cu.new_class_hash {
+            seq.push(class_hash.to_biguint());
+        }
+
+        for su in cu.storage_updates.iter() {
+            seq.push(su.key.to_biguint());
+            seq.push(su.value.to_biguint());
+        }
+    }
+
+    Ok(())
+}
+
+pub(crate) fn encode_class_declarations(seq: &mut Vec<BigUint>, state_diff: &StateDiff) {
+    seq.push(BigUint::from(state_diff.class_declarations.len()));
+    for decl in state_diff.class_declarations.iter() {
+        seq.push(decl.class_hash.to_biguint());
+        seq.push(decl.compiled_class_hash.to_biguint());
+    }
+}
+
+/// Splits `seq` into blobs (the last one padded with zeros) and
+/// evaluates them, i.e. reverses `Transformer::transform` of the
+/// concatenated blobs.
+pub fn make_blobs(seq: &[BigUint], transformer: &Transformer) -> Vec<Vec<BigUint>> {
+    seq.chunks(FIELD_ELEMENTS_PER_BLOB)
+        .map(|chunk| {
+            let mut coeffs = chunk.to_vec();
+            coeffs.resize(FIELD_ELEMENTS_PER_BLOB, BigUint::ZERO);
+            transformer.evaluate(&coeffs)
+        })
+        .collect()
+}
+
+/// Encodes `state_diff` into blobs of `format`, compressing it if the
+/// format is compressed.
+pub fn encode(
+    state_diff: &StateDiff,
+    format: &dyn DaFormat,
+    transformer: &Transformer,
+) -> eyre::Result<Vec<Vec<BigUint>>> {
+    let seq = encode_state_diff(state_diff, format)?;
+    let seq = if format.is_compressed() {
+        Compressor::compress(&seq)?
+    } else {
+        seq
+    };
+    Ok(make_blobs(&seq, transformer))
+}
+
+#[cfg(test)]
+mod tests {
+    use num_bigint::BigUint;
+
+    use super::{encode, encode_state_diff};
+    use crate::annotation::Annotator;
+    use crate::blob_util::{FIELD_ELEMENTS_PER_BLOB, blob_bytes_to_data, blob_data_to_bytes};
+    use crate::da_format::{DaFormat, FormatVersion, looks_compressed};
+    use crate::felt::Felt;
+    use crate::parser::StateUpdateParser;
+    use crate::state_diff::{ClassDeclaration, ContractUpdate, StateDiff, StorageUpdate};
+    use crate::test_util::make_lookup;
+    use crate::transform::Transformer;
+
+    fn shifted(n: u64, shift: usize) -> Felt {
+        Felt::try_from(&(BigUint::from(n) << shift)).unwrap()
+    }
+
+    fn make_storage_updates(n: u64) -> Vec<StorageUpdate> {
+        (0..n)
+            .map(|i| StorageUpdate {
+                key: Felt::from(0x1000 + i),
+                value: shifted(i, 200),
+            })
+            .collect()
+    }
+
+    fn make_state_diff() -> StateDiff {
+        StateDiff {
+            contract_updates: vec![
+                ContractUpdate {
+                    address: Felt::from(0x1234),
+                    nonce: 0,
+                    new_class_hash: None,
+                    storage_updates: make_storage_updates(3),
+                    class_change: None,
+                },
+                ContractUpdate {
+                    address: shifted(0x5678, 220),
+                    nonce: 7,
+                    new_class_hash: Some(shifted(0xabcd, 200)),
+                    storage_updates: Vec::new(),
+                    class_change: None,
+                },
+                // spans blobs, and doesn't fit the short v0.13.3 format
+                ContractUpdate {
+                    address: Felt::from(0x9abc),
+                    nonce: 1,
+                    new_class_hash: None,
+                    storage_updates: make_storage_updates(2500),
+                    class_change: None,
+                },
+            ],
+            class_declarations: vec![ClassDeclaration {
+                class_hash: Felt::from(0x42),
+                compiled_class_hash: shifted(0x43, 240),
+            }],
+            range: Default::default(),
+            tail_size: 0,
+        }
+    }
+
+    // xorshift, to generate reproducible state diffs without a
+    // dependency
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: u64) -> u64 {
+            self.next() % n
+        }
+
+        // up to 250 bits, i.e. always a felt - mostly small, to hit
+        // all compression buckets
+        fn felt(&mut self) -> Felt {
+            let bits = [250, 124, 82, 61, 30, 14][self.below(6) as usize];
+            let mut n = BigUint::ZERO;
+            for _ in 0..4 {
+                n = (n << 64) + self.next();
+            }
+            Felt::try_from(&(n >> (256 - bits))).unwrap()
+        }
+    }
+
+    fn make_random_state_diff(seed: u64) -> StateDiff {
+        let mut rng = Rng(seed);
+        // small pool of values, for repeats
+        let pool: Vec<Felt> = (0..16).map(|_| rng.felt()).collect();
+        let contract_updates = (0..rng.below(8))
+            .map(|_| {
+                let storage_updates = (0..rng.below(300))
+                    .map(|_| StorageUpdate {
+                        key: rng.felt(),
+                        value: if rng.below(2) == 0 {
+                            pool[rng.below(16) as usize]
+                        } else {
+                            rng.felt()
+                        },
+                    })
+                    .collect();
+                ContractUpdate {
+                    // 0x1 and 0x2 are special
+                    address: Felt::try_from(&(rng.felt().to_biguint() + 3u32)).unwrap(),
+                    nonce: rng.next() >> rng.below(64),
+                    new_class_hash: (rng.below(3) == 0).then(|| rng.felt()),
+                    storage_updates,
+                    class_change: None,
+                }
+            })
+            .collect();
+        let class_declarations = (0..rng.below(5))
+            .map(|_| ClassDeclaration {
+                class_hash: rng.felt(),
+                compiled_class_hash: rng.felt(),
+            })
+            .collect();
+        StateDiff {
+            contract_updates,
+            class_declarations,
+            range: Default::default(),
+            tail_size: 0,
+        }
+    }
+
+    // same steps as parsing a downloaded update
+    fn decode(blobs: &[Vec<BigUint>], format: &dyn DaFormat) -> StateDiff {
+        let transformer = Transformer::new();
+        let seq = blobs
+            .iter()
+            .flat_map(|blob| transformer.transform(blob))
+            .collect::<Vec<_>>();
+        assert_eq!(looks_compressed(&seq), format.is_compressed());
+        let (seq, _) = format.decompress(&seq).unwrap();
+        let lookup = make_lookup();
+        StateUpdateParser::parse(seq.into_iter(), format, lookup, Annotator::none()).unwrap()
+    }
+
+    fn check_round_trip(state_diff: &StateDiff, version: FormatVersion) -> usize {
+        let format = version.get_format();
+        let blobs = encode(state_diff, format, &Transformer::new()).unwrap();
+        for blob in blobs.iter() {
+            let bytes = blob_data_to_bytes(blob);
+            assert_eq!(bytes.len(), FIELD_ELEMENTS_PER_BLOB * 32);
+            assert_eq!(&blob_bytes_to_data(&bytes), blob);
+        }
+
+        let decoded = decode(&blobs, format);
+        assert_eq!(decoded.contract_updates, state_diff.contract_updates);
+        assert_eq!(decoded.class_declarations, state_diff.class_declarations);
+        blobs.len()
+    }
+
+    #[rstest::rstest]
+    #[case::v0_13_1(FormatVersion::V0_13_1, 2)]
+    // compression of the many small storage keys & values saves a blob
+    #[case::v0_13_3(FormatVersion::V0_13_3, 1)]
+    fn round_trip(#[case] version: FormatVersion, #[case] blob_count: usize) {
+        assert_eq!(check_round_trip(&make_state_diff(), version), blob_count);
+    }
+
+    #[rstest::rstest]
+    fn random_round_trip(
+        #[values(FormatVersion::V0_13_1, FormatVersion::V0_13_3)] version: FormatVersion,
+        #[values(1, 2, 3, 0xdead_beef, 0x1234_5678_9abc)] seed: u64,
+    ) {
+        check_round_trip(&make_random_state_diff(seed), version);
+    }
+
+    #[test]
+    fn deployments_section() {
+        let format = FormatVersion::PRE_V0_11.get_format();
+        let err = encode_state_diff(&make_state_diff(), format).unwrap_err();
+        assert_eq!(err.to_string(), "class declarations not supported");
+        let mut state_diff = make_state_diff();
+        state_diff.class_declarations.clear();
+        let err = encode_state_diff(&state_diff, format).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "encoding deployments section not supported"
+        );
+    }
+}
@@ -0,0 +1,202 @@
+use alloy::primitives::FixedBytes;
+
+use std::fmt;
+use std::io;
+
+use crate::diag::FailurePoint;
+use crate::felt::Felt;
+
+/// Errors of the library API. The binary (and other code not
+/// interested in telling them apart) can just convert them to
+/// `eyre::Report`.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// blob (or other data) server unavailable, or its response
+    /// unusable
+    #[error("{url}: {message}")]
+    Transport { url: String, message: String },
+    /// blob not provided by its source
+    #[error("blob {versioned_hash} not found in {location}")]
+    MissingBlob {
+        versioned_hash: FixedBytes<32>,
+        location: String,
+    },
+    /// blob not matching its commitment or versioned hash
+    #[error("blob {versioned_hash}: {message}")]
+    BlobIntegrity {
+        versioned_hash: FixedBytes<32>,
+        message: String,
+    },
+    /// memory pages of a state update published in calldata not
+    /// found through the events linking them to it
+    #[error("memory pages of tx {tx_hash}: {message}")]
+    MissingPages {
+        tx_hash: FixedBytes<32>,
+        message: String,
+    },
+    /// memory page not matching the hash logged by the SHARP
+    /// verifier (or not registered directly by its tx)
+    #[error("memory page {page_hash}: {message}")]
+    PageIntegrity {
+        page_hash: FixedBytes<32>,
+        message: String,
+    },
+    /// stateless compression format violation
+    #[error("{point}: {message}")]
+    Decompression {
+        point: Box<FailurePoint>,
+        message: String,
+    },
+    /// state update format violation
+    #[error("{point}: {message}")]
+    Parse {
+        point: Box<FailurePoint>,
+        /// context chain, including the cause (if any)
+        message: String,
+        /// library error causing the failure (e.g. a missing alias)
+        cause: Option<Box<Error>>,
+    },
+    /// invalid access to the stateful compression lookup table
+    #[error("lookup index {index}: {reason}")]
+    Lookup { index: u64, reason: LookupFailure },
+    /// historical state query for a block no processed update covers
+    #[error("Starknet block {0} not covered by processed state updates")]
+    BlockNotCovered(u64),
+    /// global state root computed from processed state updates
+    /// differing from the one published with them
+    #[error(
+        "update at Ethereum block {} index {}: computed state root {computed:#x} but expected {expected:#x}",
+        position.0,
+        position.1
+    )]
+    RootMismatch {
+        position: (u64, u64),
+        computed: Felt,
+        expected: Felt,
+    },
+    /// class change of a contract not classified as a deployment or
+    /// a replacement, as processing didn't start at Starknet genesis
+    #[error(
+        "class change of contract {0:#x} not classified: STATE_UPDATE output requires processing from Starknet genesis"
+    )]
+    Unclassified(Felt),
+    /// Merkle proof not matching its root (or malformed)
+    #[error("invalid proof: {0}")]
+    InvalidProof(String),
+    /// API called out of order, or without an argument it requires
+    #[error("invalid usage: {0}")]
+    Usage(String),
+    /// blocking task panicked (or was cancelled)
+    #[error("task: {0}")]
+    Task(#[from] tokio::task::JoinError),
+    /// database failure
+    #[error("storage: {0}")]
+    Storage(Box<redb::Error>),
+    /// file system failure
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LookupFailure {
+    /// below `START_INDEX`
+    TooSmall,
+    /// alias not in the table
+    NotFound,
+    /// alias recorded twice by the same update
+    Repeated,
+    /// first alias recorded by an update doesn't follow the table
+    Incomplete,
+    /// gap between aliases recorded by an update
+    NotConsecutive,
+    /// stored alias isn't a felt
+    Corrupt,
+}
+
+impl fmt::Display for LookupFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let text = match self {
+            LookupFailure::TooSmall => "too small",
+            LookupFailure::NotFound => "not found",
+            LookupFailure::Repeated => "repeated",
+            LookupFailure::Incomplete => "lookup table not complete before it",
+            LookupFailure::NotConsecutive => "not consecutive",
+            LookupFailure::Corrupt => "corrupt",
+        };
+        f.write_str(text)
+    }
+}
+
+impl Error {
+    /// Converts an error of the glob crate, which is always about
+    /// the file system (or the cache directory path).
+    pub(crate) fn from_glob<E>(err: E) -> Self
+    where
+        E: std::error::Error + Send + Sync + 'static,
+    {
+        Error::Io(io::Error::other(err))
+    }
+}
+
+// redb has an error type per operation, all convertible to
+// `redb::Error` (which is large enough to be boxed)
+macro_rules! from_redb {
+    ($($t:ty),*) => {
+        $(
+            impl From<$t> for Error {
+                fn from(err: $t) -> Self {
+                    Error::Storage(Box::new(err.into()))
+                }
+            }
+        )*
+    };
+}
+
+from_redb!(
+    redb::Error,
+    redb::DatabaseError,
+    redb::TransactionError,
+    redb::TableError,
+    redb::StorageError,
+    redb::CommitError
+);
+
+#[cfg(test)]
+mod tests {
+    use num_bigint::BigUint;
+
+    use super::{Error, LookupFailure};
+    use crate::annotation::Annotator;
+    use crate::da_format::FormatVersion;
+    use crate::lookup::START_INDEX;
+    use crate::parser::StateUpdateParser;
+    use crate::test_util::primed_lookup;
+
+    #[test]
+    fn missing_alias() {
+        let lookup = primed_lookup();
+
+        // 0x1 contract, then a contract aliased by an unknown index
+        let words: Vec<u64> = vec![2, 1, 1, 100, 5, START_INDEX + 1, 0];
+        let seq = words.into_iter().map(BigUint::from);
+        let format = FormatVersion::V0_13_1.get_format();
+        let err = StateUpdateParser::parse(seq, format, lookup, Annotator::none()).unwrap_err();
+        let Error::Parse { point, cause, .. } = &err else {
+            panic!("unexpected error {:?}", err);
+        };
+        assert_eq!(point.index, 5);
+        assert!(matches!(
+            cause.as_deref(),
+            Some(Error::Lookup {
+                index: 129,
+                reason: LookupFailure::NotFound
+            })
+        ));
+        assert_eq!(
+            err.to_string(),
+            "parsing failed at word 5: contract 1 of 2: lookup index 129: not found"
+        );
+    }
+}
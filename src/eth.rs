@@ -5,5 +5,30 @@ sol!(
     #[sol(rpc)]
     interface StarknetCore {
         event LogStateUpdate(uint256 globalRoot, int256 blockNumber, uint256 blockHash);
+        event LogStateTransitionFact(bytes32 stateTransitionFact);
+
+        function stateRoot() external view returns (uint256);
+    }
+);
+
+sol!(
+    #[allow(missing_docs)]
+    interface GpsStatementVerifier {
+        event LogMemoryPagesHashes(bytes32 programOutputFact, bytes32[] pagesHashes);
+    }
+);
+
+sol!(
+    #[allow(missing_docs)]
+    interface MemoryPageFactRegistry {
+        event LogMemoryPageFactContinuous(bytes32 factHash, uint256 memoryHash, uint256 prime);
+
+        function registerContinuousMemoryPage(
+            uint256 startAddr,
+            uint256[] values,
+            uint256 z,
+            uint256 alpha,
+            uint256 prime
+        ) external returns (bytes32 factHash, uint256 memoryHash, uint256 prod);
     }
 );
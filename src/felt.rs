@@ -0,0 +1,223 @@
+use eyre::anyhow;
+use num_bigint::BigUint;
+use num_traits::Num;
+use serde::{Deserialize, Deserializer, Serialize, Serializer, de};
+
+use std::fmt;
+use std::str::FromStr;
+
+/// Stark prime 2^251 + 17 * 2^192 + 1, big-endian
+const STARK_PRIME: [u8; 32] = [
+    0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01,
+];
+
+/// Element of the Starknet field (addresses, storage keys & values,
+/// class hashes), guaranteed to be below the Stark prime. Stored
+/// big-endian, so that the derived ordering is numeric.
+#[derive(Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Felt([u8; 32]);
+
+impl Felt {
+    pub const ZERO: Felt = Felt([0; 32]);
+
+    pub fn from_be_bytes(bytes: [u8; 32]) -> eyre::Result<Self> {
+        // lexicographic comparison of big-endian byte arrays orders
+        // them numerically
+        if bytes >= STARK_PRIME {
+            Err(anyhow!("{:#x} is not below the Stark prime", Felt(bytes)))
+        } else {
+            Ok(Felt(bytes))
+        }
+    }
+
+    /// Converts a big-endian integer of at most 32 bytes.
+    pub fn from_be_slice(raw: &[u8]) -> eyre::Result<Self> {
+        if raw.len() > 32 {
+            return Err(anyhow!("{} bytes don't fit a felt", raw.len()));
+        }
+
+        let mut bytes = [0u8; 32];
+        bytes[32 - raw.len()..].copy_from_slice(raw);
+        Self::from_be_bytes(bytes)
+    }
+
+    pub fn to_be_bytes(&self) -> [u8; 32] {
+        self.0
+    }
+
+    pub fn to_biguint(&self) -> BigUint {
+        BigUint::from_bytes_be(&self.0)
+    }
+
+    pub fn to_u64(&self) -> Option<u64> {
+        if self.0[..24].iter().all(|b| *b == 0) {
+            Some(u64::from_be_bytes(self.0[24..].try_into().unwrap()))
+        } else {
+            None
+        }
+    }
+
+    pub fn is_zero(&self) -> bool {
+        *self == Self::ZERO
+    }
+}
+
+impl From<u64> for Felt {
+    fn from(n: u64) -> Self {
+        let mut bytes = [0u8; 32];
+        bytes[24..].copy_from_slice(&n.to_be_bytes());
+        Felt(bytes)
+    }
+}
+
+impl TryFrom<&BigUint> for Felt {
+    type Error = eyre::Report;
+
+    fn try_from(n: &BigUint) -> eyre::Result<Self> {
+        let raw = n.to_bytes_be();
+        if raw.len() > 32 {
+            return Err(anyhow!("{:#x} is not below the Stark prime", n));
+        }
+
+        Self::from_be_slice(&raw)
+    }
+}
+
+impl From<Felt> for BigUint {
+    fn from(felt: Felt) -> Self {
+        felt.to_biguint()
+    }
+}
+
+impl FromStr for Felt {
+    type Err = eyre::Report;
+
+    /// Parses hex (with the 0x prefix) or decimal.
+    fn from_str(s: &str) -> eyre::Result<Self> {
+        let (data, radix) = match s.strip_prefix("0x") {
+            Some(tail) => (tail, 16),
+            None => (s, 10),
+        };
+        let n = BigUint::from_str_radix(data, radix).map_err(|_| anyhow!("invalid felt {}", s))?;
+        Self::try_from(&n)
+    }
+}
+
+impl fmt::LowerHex for Felt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::LowerHex::fmt(&self.to_biguint(), f)
+    }
+}
+
+impl fmt::Display for Felt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.to_biguint(), f)
+    }
+}
+
+impl fmt::Debug for Felt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#x}", self)
+    }
+}
+
+impl Serialize for Felt {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(&format!("{:#x}", self))
+    }
+}
+
+struct FeltVisitor;
+
+impl de::Visitor<'_> for FeltVisitor {
+    type Value = Felt;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a hex or decimal string, or an unsigned integer")
+    }
+
+    fn visit_str<E>(self, s: &str) -> Result<Felt, E>
+    where
+        E: de::Error,
+    {
+        Felt::from_str(s).map_err(E::custom)
+    }
+
+    fn visit_u64<E>(self, n: u64) -> Result<Felt, E>
+    where
+        E: de::Error,
+    {
+        Ok(Felt::from(n))
+    }
+}
+
+impl<'de> Deserialize<'de> for Felt {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_any(FeltVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use num_bigint::BigUint;
+
+    use std::str::FromStr;
+
+    use super::Felt;
+
+    fn stark_prime() -> BigUint {
+        (BigUint::from(1u32) << 251) + (BigUint::from(17u32) << 192) + 1u32
+    }
+
+    #[test]
+    fn range() {
+        let max = stark_prime() - 1u32;
+        assert_eq!(Felt::try_from(&max).unwrap().to_biguint(), max);
+        let err = Felt::try_from(&stark_prime()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "0x800000000000011000000000000000000000000000000000000000000000001 is not below the Stark prime"
+        );
+        assert!(Felt::try_from(&(BigUint::from(1u32) << 256)).is_err());
+        assert!(Felt::from_be_slice(&[1u8; 33]).is_err());
+    }
+
+    #[test]
+    fn conversion() {
+        let felt = Felt::from(0x1234u64);
+        assert_eq!(felt.to_u64(), Some(0x1234));
+        assert_eq!(format!("{:#x}", felt), "0x1234");
+        assert_eq!(felt.to_string(), "4660");
+        assert_eq!(Felt::from_str("0x1234").unwrap(), felt);
+        assert_eq!(Felt::from_str("4660").unwrap(), felt);
+        assert!(Felt::from_str("0xg").is_err());
+        assert!(Felt::ZERO.is_zero());
+        assert!(Felt::from(2) > Felt::from(1));
+
+        let big = Felt::try_from(&(BigUint::from(3u32) << 200)).unwrap();
+        assert_eq!(big.to_u64(), None);
+        assert!(big > felt);
+    }
+
+    #[test]
+    fn serde() {
+        let felt = Felt::from(20);
+        assert_eq!(serde_json::to_string(&felt).unwrap(), "\"0x14\"");
+        for raw in ["\"0x14\"", "\"20\"", "20"] {
+            assert_eq!(serde_json::from_str::<Felt>(raw).unwrap(), felt);
+        }
+        assert!(
+            serde_json::from_str::<Felt>(
+                "\"0x800000000000011000000000000000000000000000000000000000000000001\""
+            )
+            .is_err()
+        );
+    }
+}
@@ -0,0 +1,312 @@
+use alloy::primitives::FixedBytes;
+use redb::{Database, TableDefinition, TableError, WriteTransaction};
+
+use std::sync::Arc;
+
+use crate::error::Result;
+use crate::felt::Felt;
+
+// keyed by (Ethereum block number, log index)
+const UPDATE_TABLE: TableDefinition<(u64, u64), &[u8] /* UpdateRecord */> =
+    TableDefinition::new("update_history");
+
+const RECORD_SIZE: usize = 32 + 32 + 8 + 1 + 8 + 8 + 32 + 32;
+
+// presence flags of the optional record fields (absent ones are
+// stored as zeros)
+const SEQ_RANGE_FLAG: u8 = 1;
+const GLOBAL_ROOT_FLAG: u8 = 2;
+const STARKNET_BLOCK_HASH_FLAG: u8 = 4;
+
+// number of records read at once when looking for the checkpoint
+const CHECKPOINT_BATCH: usize = 64;
+
+/// Provenance of a processed state update.
+#[derive(Clone, Debug, PartialEq)]
+pub struct UpdateRecord {
+    pub block_no: u64,
+    pub log_index: u64,
+    pub block_hash: FixedBytes<32>,
+    pub tx_hash: FixedBytes<32>,
+    /// size of the stateful compression lookup table before the
+    /// update was processed
+    pub lookup_size: u64,
+    /// Starknet blocks covered by the update - known iff the update
+    /// was parsed
+    pub seq_range: Option<(u64, u64)>,
+    /// Starknet state root after the update
+    pub global_root: Option<Felt>,
+    /// hash of the last Starknet block covered by the update
+    pub starknet_block_hash: Option<Felt>,
+}
+
+impl UpdateRecord {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut flags = 0u8;
+        let (from_seq_no, to_seq_no) = match self.seq_range {
+            Some(range) => {
+                flags |= SEQ_RANGE_FLAG;
+                range
+            }
+            None => (0, 0),
+        };
+        let mut felt_or_zero = |opt_felt: Option<Felt>, flag: u8| match opt_felt {
+            Some(felt) => {
+                flags |= flag;
+                felt.to_be_bytes()
+            }
+            None => [0u8; 32],
+        };
+        let global_root = felt_or_zero(self.global_root, GLOBAL_ROOT_FLAG);
+        let starknet_block_hash = felt_or_zero(self.starknet_block_hash, STARKNET_BLOCK_HASH_FLAG);
+
+        let mut bytes = Vec::with_capacity(RECORD_SIZE);
+        bytes.extend_from_slice(self.block_hash.as_slice());
+        bytes.extend_from_slice(self.tx_hash.as_slice());
+        bytes.extend_from_slice(&self.lookup_size.to_be_bytes());
+        bytes.push(flags);
+        bytes.extend_from_slice(&from_seq_no.to_be_bytes());
+        bytes.extend_from_slice(&to_seq_no.to_be_bytes());
+        bytes.extend_from_slice(&global_root);
+        bytes.extend_from_slice(&starknet_block_hash);
+        bytes
+    }
+
+    fn from_bytes(key: (u64, u64), bytes: &[u8]) -> Result<Self> {
+        let corrupted = |what: String| {
+            redb::Error::Corrupted(format!("update record of block {} {}", key.0, what))
+        };
+        if bytes.len() != RECORD_SIZE {
+            return Err(corrupted(format!("has {} bytes", bytes.len())).into());
+        }
+
+        let flags = bytes[72];
+        if flags & !(SEQ_RANGE_FLAG | GLOBAL_ROOT_FLAG | STARKNET_BLOCK_HASH_FLAG) != 0 {
+            return Err(corrupted(format!("has invalid flags {:#x}", flags)).into());
+        }
+
+        let read_u64 = |pos: usize| u64::from_be_bytes(bytes[pos..pos + 8].try_into().unwrap());
+        let read_felt = |pos: usize, flag: u8| -> Result<Option<Felt>> {
+            if flags & flag == 0 {
+                return Ok(None);
+            }
+
+            let felt =
+                Felt::from_be_slice(&bytes[pos..pos + 32]).map_err(|e| corrupted(e.to_string()))?;
+            Ok(Some(felt))
+        };
+        let seq_range = if flags & SEQ_RANGE_FLAG == 0 {
+            None
+        } else {
+            Some((read_u64(73), read_u64(81)))
+        };
+        Ok(Self {
+            block_no: key.0,
+            log_index: key.1,
+            block_hash: FixedBytes::from_slice(&bytes[0..32]),
+            tx_hash: FixedBytes::from_slice(&bytes[32..64]),
+            lookup_size: read_u64(64),
+            seq_range,
+            global_root: read_felt(89, GLOBAL_ROOT_FLAG)?,
+            starknet_block_hash: read_felt(121, STARKNET_BLOCK_HASH_FLAG)?,
+        })
+    }
+}
+
+/// Persistent list of processed state updates, used to detect (and
+/// roll back) Ethereum reorgs.
+pub struct History {
+    db: Arc<Database>,
+}
+
+impl History {
+    pub fn new(db: Arc<Database>) -> Self {
+        Self { db }
+    }
+
+    /// Records a processed update as part of `txn` - which should
+    /// also persist the update's lookup table expansion.
+    pub fn record(txn: &WriteTransaction, record: &UpdateRecord) -> Result<()> {
+        let mut table = txn.open_table(UPDATE_TABLE)?;
+        let bytes = record.to_bytes();
+        table.insert((record.block_no, record.log_index), bytes.as_slice())?;
+        Ok(())
+    }
+
+    /// Returns the record of the update at `position`, if it has
+    /// been processed.
+    pub fn get(&self, position: (u64, u64)) -> Result<Option<UpdateRecord>> {
+        let txn = self.db.begin_read()?;
+        let table = match txn.open_table(UPDATE_TABLE) {
+            Err(TableError::TableDoesNotExist(_)) => {
+                return Ok(None);
+            }
+            Err(err) => {
+                return Err(err.into());
+            }
+            Ok(table) => table,
+        };
+        match table.get(position)? {
+            Some(found) => Ok(Some(UpdateRecord::from_bytes(position, found.value())?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the last processed update, i.e. the point to resume
+    /// from. When `parsed` is set, updates processed without parsing
+    /// (which therefore didn't expand the lookup table) are skipped.
+    pub fn get_checkpoint(&self, parsed: bool) -> Result<Option<UpdateRecord>> {
+        let mut before = None;
+        loop {
+            let records = self.get_preceding(before, CHECKPOINT_BATCH)?;
+            let Some(oldest) = records.last() else {
+                return Ok(None);
+            };
+
+            before = Some((oldest.block_no, oldest.log_index));
+            if let Some(record) = records
+                .into_iter()
+                .find(|record| !parsed || record.seq_range.is_some())
+            {
+                return Ok(Some(record));
+            }
+        }
+    }
+
+    /// Returns up to `limit` records preceding the update at `before`
+    /// (or the newest ones, when `before` is `None`), newest first.
+    pub fn get_preceding(
+        &self,
+        before: Option<(u64, u64)>,
+        limit: usize,
+    ) -> Result<Vec<UpdateRecord>> {
+        let txn = self.db.begin_read()?;
+        let table = match txn.open_table(UPDATE_TABLE) {
+            Err(TableError::TableDoesNotExist(_)) => {
+                return Ok(Vec::new());
+            }
+            Err(err) => {
+                return Err(err.into());
+            }
+            Ok(table) => table,
+        };
+        let range = match before {
+            Some(key) => table.range(..key)?,
+            None => table.range::<(u64, u64)>(..)?,
+        };
+        let mut records = Vec::new();
+        for res in range.rev().take(limit) {
+            let (key, value) = res?;
+            records.push(UpdateRecord::from_bytes(key.value(), value.value())?);
+        }
+
+        Ok(records)
+    }
+
+    /// Removes (and returns, oldest first) records of updates from
+    /// block `block_no` onwards.
+    pub fn truncate_from(&self, block_no: u64) -> Result<Vec<UpdateRecord>> {
+        let txn = self.db.begin_write()?;
+        let mut removed = Vec::new();
+        {
+            let mut table = txn.open_table(UPDATE_TABLE)?;
+            let doomed = table.extract_from_if((block_no, 0).., |_, _| true)?;
+            for res in doomed {
+                let (key, value) = res?;
+                removed.push(UpdateRecord::from_bytes(key.value(), value.value())?);
+            }
+        }
+        txn.commit()?;
+        Ok(removed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloy::primitives::FixedBytes;
+
+    use super::{History, UpdateRecord};
+    use crate::felt::Felt;
+    use crate::test_util::memory_db;
+
+    fn make_record(block_no: u64, log_index: u64) -> UpdateRecord {
+        UpdateRecord {
+            block_no,
+            log_index,
+            block_hash: FixedBytes::repeat_byte(block_no as u8),
+            tx_hash: FixedBytes::repeat_byte(log_index as u8),
+            lookup_size: block_no * 10,
+            seq_range: if log_index > 0 {
+                Some((block_no, block_no + 1))
+            } else {
+                None
+            },
+            // optional fields are independent
+            global_root: (log_index > 0).then(|| Felt::from(block_no * 100 + log_index)),
+            starknet_block_hash: (!block_no.is_multiple_of(2))
+                .then(|| Felt::from(block_no * 1000 + log_index)),
+        }
+    }
+
+    #[test]
+    fn record_and_truncate() {
+        let db = memory_db();
+        let history = History::new(db.clone());
+        assert!(history.get_preceding(None, 10).unwrap().is_empty());
+        for (block_no, log_index) in [(1, 0), (2, 3), (2, 5), (4, 1)] {
+            let txn = db.begin_write().unwrap();
+            History::record(&txn, &make_record(block_no, log_index)).unwrap();
+            txn.commit().unwrap();
+        }
+        assert_eq!(
+            history.get_checkpoint(false).unwrap(),
+            Some(make_record(4, 1))
+        );
+
+        let newest = history.get_preceding(None, 2).unwrap();
+        assert_eq!(newest, vec![make_record(4, 1), make_record(2, 5)]);
+        let older = history.get_preceding(Some((2, 5)), 10).unwrap();
+        assert_eq!(older, vec![make_record(2, 3), make_record(1, 0)]);
+        for (block_no, log_index) in [(1, 0), (2, 3), (4, 1)] {
+            assert_eq!(
+                history.get((block_no, log_index)).unwrap(),
+                Some(make_record(block_no, log_index))
+            );
+        }
+        assert_eq!(history.get((2, 4)).unwrap(), None);
+
+        let removed = history.truncate_from(2).unwrap();
+        assert_eq!(
+            removed,
+            vec![make_record(2, 3), make_record(2, 5), make_record(4, 1)]
+        );
+        assert_eq!(
+            history.get_checkpoint(false).unwrap(),
+            Some(make_record(1, 0))
+        );
+        // not parsed
+        assert_eq!(history.get_checkpoint(true).unwrap(), None);
+    }
+
+    #[test]
+    fn parsed_checkpoint() {
+        let db = memory_db();
+        let history = History::new(db.clone());
+        // parsed, then many more updates processed without parsing
+        let txn = db.begin_write().unwrap();
+        History::record(&txn, &make_record(2, 3)).unwrap();
+        for block_no in 3..200 {
+            History::record(&txn, &make_record(block_no, 0)).unwrap();
+        }
+        txn.commit().unwrap();
+
+        assert_eq!(
+            history.get_checkpoint(false).unwrap(),
+            Some(make_record(199, 0))
+        );
+        assert_eq!(
+            history.get_checkpoint(true).unwrap(),
+            Some(make_record(2, 3))
+        );
+    }
+}
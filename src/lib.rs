@@ -1,11 +1,32 @@
+pub mod annotation;
+pub mod archive;
 pub mod blob_util;
+pub mod bls_field;
+pub mod class_index;
+pub mod commitment;
+pub mod compress;
 pub mod config;
+pub mod da_format;
 pub mod decomp;
+pub mod diag;
 pub mod download;
 pub mod dump;
+pub mod encode;
+pub mod error;
 pub mod eth;
+pub mod felt;
+pub mod history;
 pub mod lookup;
+pub mod memory_pages;
+pub mod network;
 pub mod packing;
 pub mod parser;
+pub mod proof;
+pub mod scan;
 pub mod state_diff;
+pub mod state_store;
 pub mod transform;
+pub mod trie;
+
+#[cfg(test)]
+mod test_util;
@@ -1,64 +1,88 @@
-use eyre::{ContextCompat, anyhow};
-use num_bigint::{BigUint, ToBigUint};
-use redb::{Database, ReadableTableMetadata, TableDefinition, TableError, WriteTransaction};
+use redb::{
+    Database, ReadableTable, ReadableTableMetadata, TableDefinition, TableError, WriteTransaction,
+};
 
 use std::collections::BTreeMap;
-use std::path::PathBuf;
+use std::sync::Arc;
+
+use crate::error::{Error, LookupFailure, Result};
+use crate::felt::Felt;
 
 pub const START_INDEX: u64 = 128;
 
 const PHASE_CHANGE: TableDefinition<&str, u64> = TableDefinition::new("phase_change");
 const STATEFUL_COMPRESSION_START: &str = "stateful";
+// position of the last update whose expansion was persisted: block
+// number under STATEFUL_COMPRESSION_CREST and log index under
+// STATEFUL_COMPRESSION_CREST_LOG (missing when the whole block is
+// covered)
 const STATEFUL_COMPRESSION_CREST: &str = "crest";
+const STATEFUL_COMPRESSION_CREST_LOG: &str = "crest_log";
 
-const LOOKUP_TABLE: TableDefinition<u64, &[u8] /* BigUint */> =
-    TableDefinition::new("lookup_table");
+const LOOKUP_TABLE: TableDefinition<u64, &[u8] /* Felt */> = TableDefinition::new("lookup_table");
 
 pub struct Lookup {
-    pub global_start_index: BigUint,
-    scratchpad: BTreeMap<u64, BigUint>,
-    cur_block_no: Option<u64>,
-    db: Database,
+    pub global_start_index: Felt,
+    scratchpad: BTreeMap<u64, Felt>,
+    // expansion of the current update, persisted by commit
+    pending: BTreeMap<u64, Felt>,
+    expanded: bool,
+    // (Ethereum block number, log index) of the current update
+    cur_position: Option<(u64, u64)>,
+    db: Arc<Database>,
 }
 
 impl Lookup {
-    pub fn new(db_file: &PathBuf) -> eyre::Result<Self> {
-        let db = Database::create(db_file)?;
-        Ok(Self {
-            global_start_index: START_INDEX.to_biguint().unwrap(),
+    pub fn new(db: Arc<Database>) -> Self {
+        Self {
+            global_start_index: Felt::from(START_INDEX),
             scratchpad: BTreeMap::new(),
-            cur_block_no: None,
+            pending: BTreeMap::new(),
+            expanded: false,
+            cur_position: None,
             db,
-        })
+        }
     }
 
-    pub fn set_block_no(&mut self, cur_block_no: u64) {
-        self.cur_block_no = Some(cur_block_no)
+    /// Starts processing the update at `cur_position` (Ethereum
+    /// block number, log index), discarding any uncommitted
+    /// expansion.
+    pub fn set_position(&mut self, cur_position: (u64, u64)) {
+        self.scratchpad.clear();
+        self.pending.clear();
+        self.expanded = false;
+        self.cur_position = Some(cur_position)
     }
 
-    pub fn record(&mut self, index: u64, value: &BigUint) -> eyre::Result<()> {
+    pub fn record(&mut self, index: u64, value: &Felt) -> Result<()> {
         if index < START_INDEX {
-            return Err(anyhow!("index {} too small", index));
+            return Err(Error::Lookup {
+                index,
+                reason: LookupFailure::TooSmall,
+            });
         }
 
-        if let Some(old) = self.scratchpad.insert(index, value.clone()) {
+        if let Some(old) = self.scratchpad.insert(index, *value) {
             // reject invalid input data
             self.scratchpad.insert(index, old);
-            Err(anyhow!("index repeated"))
+            Err(Error::Lookup {
+                index,
+                reason: LookupFailure::Repeated,
+            })
         } else {
             Ok(())
         }
     }
 
     // clears scratchpad (even) on error
-    pub fn expand(&mut self) -> eyre::Result<()> {
+    pub fn expand(&mut self) -> Result<()> {
         if let Some(crest) = self.get_crest()? {
-            let block_no = self.get_cur_block_no()?;
-            if block_no <= crest {
+            let position = self.get_cur_position()?;
+            if position <= crest {
                 tracing::info!(
-                    "stateful compression mapping had already been persisted up to block {} and won't be updated for block {} again",
+                    "stateful compression mapping had already been persisted up to {:?} and won't be updated for {:?} again",
                     crest,
-                    block_no
+                    position
                 );
                 // could be checked to be the same, though...
                 self.scratchpad.clear();
@@ -71,33 +95,102 @@ impl Lookup {
         }
     }
 
-    fn do_expand(&mut self) -> eyre::Result<()> {
+    // the expansion is visible to get, but isn't persisted until
+    // commit
+    fn do_expand(&mut self) -> Result<()> {
         let scratchpad = std::mem::take(&mut self.scratchpad);
         let mut first = true;
         let mut sz = self.get_table_size()?;
-        let mut txn = self.db.begin_write()?;
-        for (index, value) in scratchpad.into_iter() {
+        for index in scratchpad.keys() {
             if index - START_INDEX != sz {
-                return Err(if first {
-                    anyhow!("lookup table not complete before {}", index)
+                let reason = if first {
+                    LookupFailure::Incomplete
                 } else {
-                    anyhow!("index {} not consecutive", index)
+                    LookupFailure::NotConsecutive
+                };
+                return Err(Error::Lookup {
+                    index: *index,
+                    reason,
                 });
             }
 
-            Self::set_expansion(&mut txn, index, value)?;
             sz += 1;
             first = false;
         }
 
-        self.set_stateful_compression(&mut txn)?;
+        self.pending.extend(scratchpad);
+        self.expanded = true;
+        tracing::debug!("lookup table expanded to {} entries", sz);
+        Ok(())
+    }
+
+    /// Persists the expansion of the current update (if any),
+    /// together with whatever `also` writes, in a single transaction.
+    pub fn commit<F>(&mut self, also: F) -> Result<()>
+    where
+        F: FnOnce(&WriteTransaction) -> Result<()>,
+    {
+        let pending = std::mem::take(&mut self.pending);
+        let mut txn = self.db.begin_write()?;
+        if self.expanded {
+            for (index, value) in pending.into_iter() {
+                Self::set_expansion(&mut txn, index, value)?;
+            }
+
+            self.set_stateful_compression(&mut txn)?;
+            self.expanded = false;
+        }
+
+        also(&txn)?;
         txn.commit()?;
+        Ok(())
+    }
 
-        tracing::debug!("lookup table expanded to {} entries", sz);
+    /// Removes entries added by updates from Ethereum block
+    /// `block_no` onwards, given the table size before the first of
+    /// them.
+    pub fn roll_back(&mut self, size: u64, block_no: u64) -> Result<()> {
+        self.scratchpad.clear();
+        self.pending.clear();
+        self.expanded = false;
+        let txn = self.db.begin_write()?;
+        {
+            let mut table = txn.open_table(LOOKUP_TABLE)?;
+            table.retain(|index, _| index < START_INDEX + size)?;
+            let empty = table.is_empty()?;
+            let mut phase_change = txn.open_table(PHASE_CHANGE)?;
+            let opt_crest = phase_change
+                .get(STATEFUL_COMPRESSION_CREST)?
+                .map(|found| found.value());
+            // all of the previous block, if there is one
+            let prev_crest = match opt_crest {
+                Some(crest) if crest >= block_no => Some(block_no.checked_sub(1)),
+                _ => None,
+            };
+            if empty || prev_crest == Some(None) {
+                phase_change.remove(STATEFUL_COMPRESSION_CREST)?;
+                phase_change.remove(STATEFUL_COMPRESSION_CREST_LOG)?;
+                phase_change.remove(STATEFUL_COMPRESSION_START)?;
+            } else if let Some(Some(crest)) = prev_crest {
+                phase_change.insert(STATEFUL_COMPRESSION_CREST, crest)?;
+                phase_change.remove(STATEFUL_COMPRESSION_CREST_LOG)?;
+            }
+        }
+        txn.commit()?;
+
+        tracing::info!(
+            "lookup table rolled back to {} entries before block {}",
+            size,
+            block_no
+        );
         Ok(())
     }
 
-    pub fn is_on(&self) -> eyre::Result<bool> {
+    pub fn is_on(&self) -> Result<bool> {
+        if self.expanded {
+            return Ok(self.get_table_size()? > 0);
+        }
+
         let txn = self.db.begin_read()?;
         match txn.open_table(PHASE_CHANGE) {
             Err(TableError::TableDoesNotExist(_)) => {
@@ -109,7 +202,7 @@ impl Lookup {
             Ok(phase_change) => {
                 if let Some(found) = phase_change.get(STATEFUL_COMPRESSION_START)? {
                     let start_no = found.value();
-                    let block_no = self.get_cur_block_no()?;
+                    let (block_no, _) = self.get_cur_position()?;
                     if block_no < start_no {
                         return Ok(false);
                     }
@@ -129,19 +222,31 @@ impl Lookup {
         Ok(!empty)
     }
 
-    pub fn get(&self, index: u64) -> eyre::Result<BigUint> {
+    pub fn get(&self, index: u64) -> Result<Felt> {
         if index < START_INDEX {
-            return Err(anyhow!("index {} is too small", index));
+            return Err(Error::Lookup {
+                index,
+                reason: LookupFailure::TooSmall,
+            });
+        }
+
+        if let Some(value) = self.pending.get(&index) {
+            return Ok(*value);
         }
 
         let txn = self.db.begin_read()?;
         let table = txn.open_table(LOOKUP_TABLE)?;
         if let Some(found) = table.get(index)? {
-            let bytes = found.value();
-            let n = BigUint::from_bytes_be(bytes);
-            Ok(n)
+            // older versions stored minimal big-endian integers
+            Felt::from_be_slice(found.value()).map_err(|_| Error::Lookup {
+                index,
+                reason: LookupFailure::Corrupt,
+            })
         } else {
-            Err(anyhow!("index {} not found", index))
+            Err(Error::Lookup {
+                index,
+                reason: LookupFailure::NotFound,
+            })
         }
     }
 
@@ -149,7 +254,7 @@ impl Lookup {
         self.scratchpad.len()
     }
 
-    pub fn get_table_size(&self) -> eyre::Result<u64> {
+    pub fn get_table_size(&self) -> Result<u64> {
         let txn = self.db.begin_read()?;
         let l = match txn.open_table(LOOKUP_TABLE) {
             Err(TableError::TableDoesNotExist(_)) => 0,
@@ -158,14 +263,17 @@ impl Lookup {
             }
             Ok(table) => table.len()?,
         };
-        Ok(l)
+        Ok(l + self.pending.len() as u64)
     }
 
-    fn get_cur_block_no(&self) -> eyre::Result<u64> {
-        self.cur_block_no.context("Lookup.cur_block_no not set")
+    // callers must start processing an update before using its
+    // position
+    fn get_cur_position(&self) -> Result<(u64, u64)> {
+        self.cur_position
+            .ok_or_else(|| Error::Usage("lookup position not set".to_string()))
     }
 
-    fn get_crest(&self) -> eyre::Result<Option<u64>> {
+    fn get_crest(&self) -> Result<Option<(u64, u64)>> {
         let txn = self.db.begin_read()?;
         let opt_crest = match txn.open_table(PHASE_CHANGE) {
             Err(TableError::TableDoesNotExist(_)) => None,
@@ -174,8 +282,10 @@ impl Lookup {
             }
             Ok(phase_change) => {
                 if let Some(found) = phase_change.get(STATEFUL_COMPRESSION_CREST)? {
-                    let crest = found.value();
-                    Some(crest)
+                    let log_index = phase_change
+                        .get(STATEFUL_COMPRESSION_CREST_LOG)?
+                        .map_or(u64::MAX, |found| found.value());
+                    Some((found.value(), log_index))
                 } else {
                     None
                 }
@@ -184,9 +294,10 @@ impl Lookup {
         Ok(opt_crest)
     }
 
-    fn set_stateful_compression(&self, txn: &mut WriteTransaction) -> eyre::Result<()> {
-        let block_no = self.get_cur_block_no()?;
+    fn set_stateful_compression(&self, txn: &mut WriteTransaction) -> Result<()> {
+        let (block_no, log_index) = self.get_cur_position()?;
         let mut phase_change = txn.open_table(PHASE_CHANGE)?;
+        phase_change.insert(STATEFUL_COMPRESSION_CREST_LOG, log_index)?;
         let updated = {
             let opt_old = phase_change.insert(STATEFUL_COMPRESSION_CREST, block_no)?;
             opt_old.is_none()
@@ -200,8 +311,8 @@ impl Lookup {
         Ok(())
     }
 
-    fn set_expansion(txn: &mut WriteTransaction, index: u64, value: BigUint) -> eyre::Result<()> {
-        let bytes = value.to_bytes_be();
+    fn set_expansion(txn: &mut WriteTransaction, index: u64, value: Felt) -> Result<()> {
+        let bytes = value.to_be_bytes();
         let mut table = txn.open_table(LOOKUP_TABLE)?;
         let opt_old = table.insert(index, bytes.as_slice())?;
         // caller ensures indices are in order, IOW they don't repeat
@@ -209,3 +320,93 @@ impl Lookup {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{Lookup, START_INDEX};
+    use crate::error::{Error, LookupFailure};
+    use crate::felt::Felt;
+    use crate::test_util::memory_db;
+
+    fn expand_at(lookup: &mut Lookup, position: (u64, u64), values: &[u64]) {
+        lookup.set_position(position);
+        let base = START_INDEX + lookup.get_table_size().unwrap();
+        for (i, v) in values.iter().enumerate() {
+            lookup.record(base + i as u64, &Felt::from(*v)).unwrap();
+        }
+        lookup.expand().unwrap();
+        lookup.commit(|_| Ok(())).unwrap();
+    }
+
+    #[test]
+    fn roll_back() {
+        let mut lookup = Lookup::new(memory_db());
+        expand_at(&mut lookup, (10, 0), &[1, 2]);
+        expand_at(&mut lookup, (11, 0), &[3]);
+        expand_at(&mut lookup, (12, 0), &[4, 5]);
+        assert_eq!(lookup.get_table_size().unwrap(), 5);
+
+        lookup.roll_back(2, 11).unwrap();
+        assert_eq!(lookup.get_table_size().unwrap(), 2);
+        assert!(matches!(
+            lookup.get(START_INDEX + 2),
+            Err(Error::Lookup {
+                index: 130,
+                reason: LookupFailure::NotFound
+            })
+        ));
+        // block 11 is expanded again, by a different update
+        expand_at(&mut lookup, (11, 0), &[6]);
+        assert_eq!(lookup.get(START_INDEX + 2).unwrap(), Felt::from(6));
+
+        // an uncommitted expansion is visible, but not persisted
+        lookup.set_position((13, 0));
+        lookup.record(START_INDEX + 3, &Felt::from(7)).unwrap();
+        lookup.expand().unwrap();
+        assert_eq!(lookup.get_table_size().unwrap(), 4);
+        assert_eq!(lookup.get(START_INDEX + 3).unwrap(), Felt::from(7));
+        lookup.set_position((13, 0));
+        assert_eq!(lookup.get_table_size().unwrap(), 3);
+        assert!(lookup.get(START_INDEX + 3).is_err());
+
+        lookup.roll_back(0, 10).unwrap();
+        assert_eq!(lookup.get_table_size().unwrap(), 0);
+        lookup.set_position((12, 0));
+        assert!(!lookup.is_on().unwrap());
+    }
+
+    #[test]
+    fn same_block() {
+        let mut lookup = Lookup::new(memory_db());
+        expand_at(&mut lookup, (10, 1), &[1]);
+        // a later update in the same block isn't persisted yet
+        expand_at(&mut lookup, (10, 3), &[2]);
+        assert_eq!(lookup.get_table_size().unwrap(), 2);
+        assert_eq!(lookup.get(START_INDEX + 1).unwrap(), Felt::from(2));
+
+        // but after resuming, it's skipped
+        lookup.set_position((10, 3));
+        lookup.record(START_INDEX + 2, &Felt::from(3)).unwrap();
+        lookup.expand().unwrap();
+        assert_eq!(lookup.get_scratchpad_size(), 0);
+        assert_eq!(lookup.get_table_size().unwrap(), 2);
+
+        // rolling back the next block keeps all of this one
+        expand_at(&mut lookup, (11, 0), &[4]);
+        lookup.roll_back(2, 11).unwrap();
+        lookup.set_position((10, 5));
+        lookup.record(START_INDEX + 2, &Felt::from(5)).unwrap();
+        lookup.expand().unwrap();
+        assert_eq!(lookup.get_table_size().unwrap(), 2);
+    }
+
+    #[test]
+    fn roll_back_first_block() {
+        let mut lookup = Lookup::new(memory_db());
+        expand_at(&mut lookup, (0, 0), &[1]);
+        // there's no block before 0 to keep the crest at
+        lookup.roll_back(1, 0).unwrap();
+        lookup.set_position((0, 0));
+        assert!(!lookup.is_on().unwrap());
+    }
+}
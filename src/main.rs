@@ -1,40 +1,66 @@
 use alloy::{
     consensus::transaction::TxEip4844Variant,
-    primitives::{FixedBytes, address},
+    eips::{BlockId, BlockNumberOrTag},
+    primitives::{FixedBytes, U256},
     providers::{Provider, ProviderBuilder},
-    rpc::types::{Filter, Transaction},
+    rpc::types::{Filter, Log, Transaction},
     sol_types::SolEvent,
 };
 use clap::Parser;
 use eyre::{ContextCompat, WrapErr, anyhow};
+use futures::stream::StreamExt;
 use num_bigint::BigUint;
-use num_traits::{Num, ToPrimitive};
+use redb::Database;
 use tokio::time::{Duration, sleep};
 use tracing_subscriber::{EnvFilter, filter::LevelFilter};
 
 use std::cell::RefCell;
+use std::fmt;
 use std::fs;
-use std::io::{BufRead, BufReader, LineWriter, Write};
-use std::path::PathBuf;
+use std::io::LineWriter;
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
+use std::sync::Arc;
 
 use starknet_scrape::{
-    config::{Cli, Config},
-    decomp::Decompressor,
-    download::Downloader,
-    dump::{Dumper, uncond_dump},
-    eth::StarknetCore::LogStateUpdate,
+    annotation::{AnnotationFormat, Annotator},
+    archive::Archive,
+    config::{BlobSourceKind, Cli, Config},
+    da_format::{FormatRegistry, FormatVersion, check_version},
+    diag::{Diagnostic, UpdateSource},
+    download::{BlobSource, Downloader, fetch_in_order},
+    dump::{Dumper, load_seq_no, uncond_dump, uncond_load},
+    error::Error,
+    eth::StarknetCore::{self, LogStateUpdate},
+    felt::Felt,
+    history::{History, UpdateRecord},
     lookup::Lookup,
-    packing::{
-        v0_13_1::make_pack_const as make_pack_const1, v0_13_3::make_pack_const as make_pack_const3,
-    },
+    memory_pages::MemoryPageFetcher,
+    network::NetworkProfile,
     parser::StateUpdateParser,
+    scan::{Confirmation, MAX_BLOCK_COUNT, RangeScanner, is_range_error},
+    state_diff::{JsonFormat, JsonOutput, UpdateHeader},
+    state_store::{StateAt, StateStore, Versioned},
 };
 
 /// `MAX_RETRIES` is the maximum number of retries on failed tx retrieval.
 const MAX_RETRIES: usize = 5;
 /// The interval in seconds to wait before retrying to fetch tx.
 const FAILED_FETCH_RETRY_INTERVAL_S: u64 = 10;
+/// Number of processed updates compared with the canonical chain at
+/// once, when looking for a reorg.
+const REORG_CHECK_BATCH: usize = 16;
+
+/// Origin of the sequence passed to `do_parse`.
+#[derive(Clone, Copy, PartialEq)]
+enum InputFormat {
+    /// blob data, compressed or not
+    Blob,
+    /// previously-decompressed data
+    Uncompressed,
+    /// memory pages, compressed or not
+    Calldata,
+}
 
 fn start_logger(default_level: LevelFilter) {
     let filter = match EnvFilter::try_from_default_env() {
@@ -55,7 +81,7 @@ fn start_logger(default_level: LevelFilter) {
         .init();
 }
 
-fn convert_cache_file(path: &PathBuf) -> (u64, i32) {
+fn convert_cache_file(path: &Path) -> (u64, i32) {
     match path.file_stem() {
         Some(os_stem) => match os_stem.to_str() {
             Some(stem) => match stem.find('-') {
@@ -92,12 +118,16 @@ fn convert_cache_file(path: &PathBuf) -> (u64, i32) {
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 fn parse_local(
     lookup: Rc<RefCell<Lookup>>,
+    state: &mut StateStore,
+    formats: &FormatRegistry,
     cache_dir: &PathBuf,
     annotate: bool,
+    annotation_format: AnnotationFormat,
     dump: bool,
-    save_json: bool,
+    json: Option<JsonOutput>,
 ) -> eyre::Result<()> {
     let cache_dir = fs::canonicalize(cache_dir)?;
     let mask = if annotate { "*.unc" } else { "*.seq" };
@@ -112,66 +142,182 @@ fn parse_local(
     });
 
     for mut entry in entries {
-        let file = fs::File::open(&entry)?;
-        let mut elements = Vec::new();
-        for res in BufReader::new(file).lines() {
-            let ln = res?;
-            let (data, radix) = match ln.strip_prefix("0x") {
-                Some(tail) => (tail, 16),
-                None => (ln.as_str(), 10),
-            };
-            let el = BigUint::from_str_radix(data, radix)
-                .map_err(|_| anyhow!("invalid integer {}", ln))?;
-            elements.push(el);
-        }
+        let elements = uncond_load(&entry)?;
+        let (block_no, repeat) = convert_cache_file(&entry);
+        let seq_no = load_seq_no(&entry)?;
+        let version = formats
+            .get_version(block_no, seq_no)
+            .with_context(|| format!("{:?} (dump it again to record its Starknet block)", entry))?;
+        let source = UpdateSource {
+            block_no,
+            ..Default::default()
+        };
+        // dumps don't have log indices
+        state.set_position((block_no, repeat.max(0) as u64));
 
         if dump {
             entry.set_extension("unc");
         };
+        let format = if annotate {
+            InputFormat::Uncompressed
+        } else {
+            InputFormat::Blob
+        };
         do_parse(
             lookup.clone(),
+            state,
             elements,
-            annotate,
+            format,
+            version,
             dump,
-            dump,
-            save_json,
+            dump.then_some(annotation_format),
+            json.clone(),
             entry,
             None,
+            Some(&source),
         )?;
+        let mut lookup = lookup.borrow_mut();
+        lookup.commit(|txn| state.commit(txn))?;
+    }
+
+    Ok(())
+}
+
+/// Logs and saves the diagnostic report of a failed decompression or
+/// parse of `seq` (if `err` has one) next to the update's dump.
+fn report_failure(
+    err: Error,
+    seq: &[BigUint],
+    format: &'static str,
+    source: Option<&UpdateSource>,
+    dump_target: &Path,
+) -> Error {
+    if let Some(diag) = Diagnostic::new(&err, seq, format, source) {
+        let mut path = dump_target.to_path_buf();
+        path.set_extension("diag");
+        if let Err(e) = diag.report(&path) {
+            tracing::warn!("can't save diagnostics: {:?}", e);
+        }
+    }
+
+    err
+}
+
+fn print_versioned<T: fmt::LowerHex>(name: &str, opt_versioned: Option<Versioned<T>>) {
+    match opt_versioned {
+        Some(versioned) => println!(
+            "{}: {:#x} (set by {})",
+            name, versioned.value, versioned.update
+        ),
+        None => println!("{}: not set", name),
+    }
+}
+
+/// Prints the class hash, nonce and (optionally) a storage value of
+/// the contract at `address`, as of `at`.
+fn query_contract(
+    state: &StateStore,
+    address: &Felt,
+    storage_key: Option<&Felt>,
+    at: StateAt,
+) -> eyre::Result<()> {
+    match state.get_update_at(at)? {
+        Some(update) => println!("state after {}", update),
+        None => println!("no state updates processed"),
+    }
+    if let Some(commitment) = state.get_commitment_at(at)? {
+        println!("state root: {:#x}", commitment.value.global_root());
+    }
+    print_versioned("class hash", state.get_class_hash_at(address, at)?);
+    print_versioned("nonce", state.get_nonce_at(address, at)?);
+    if let Some(key) = storage_key {
+        let name = format!("storage {:#x}", key);
+        print_versioned(&name, state.get_storage_at(address, key, at)?);
     }
 
     Ok(())
 }
 
+/// Prints a Merkle proof of the value of `key` in the storage of the
+/// contract at `address`, as of `at`, after verifying it against the
+/// global root published with the update it's anchored to.
+fn prove_storage(
+    state: &StateStore,
+    history: &History,
+    address: &Felt,
+    key: &Felt,
+    at: StateAt,
+) -> eyre::Result<()> {
+    let Some(versioned) = state.get_storage_proof(address, key, at)? else {
+        println!("no state updates processed with --verify-root");
+        return Ok(());
+    };
+
+    let proof = versioned.value;
+    // the computed root is no root of trust
+    let record = history.get(versioned.update.position)?;
+    let (global_root, block_hash) = record
+        .and_then(|record| Some((record.global_root?, record.starknet_block_hash?)))
+        .with_context(|| {
+            format!(
+                "state root and block hash of {} not recorded, reprocess it to prove its state",
+                versioned.update
+            )
+        })?;
+    let value = proof.verify(&global_root)?;
+    println!(
+        "proof of storage {:#x}: {:#x} (global root {:#x} after {})",
+        key, value, global_root, versioned.update
+    );
+    println!("{:#}", proof.to_rpc_storage_proof(&block_hash));
+    Ok(())
+}
+
+/// Returns the range of Starknet blocks covered by the parsed update.
+#[allow(clippy::too_many_arguments)]
 fn do_parse(
     lookup: Rc<RefCell<Lookup>>,
+    state: &mut StateStore,
     seq: Vec<BigUint>,
-    uncompressed: bool,
+    format: InputFormat,
+    version: FormatVersion,
     dump_uncompressed: bool,
-    dump_annotated: bool,
-    save_json: bool,
+    annotation: Option<AnnotationFormat>,
+    json: Option<JsonOutput>,
     dump_target: PathBuf,
     seq_no: Option<u64>,
-) -> eyre::Result<()> {
-    if seq.len() == 0 {
+    source: Option<&UpdateSource>,
+) -> eyre::Result<(u64, u64)> {
+    if seq.is_empty() {
         return Err(anyhow!("empty sequence"));
     }
 
     let mut dump_target = dump_target;
+    let da_format = version.get_format();
 
-    // uncompressed means the sequence had been compressed previously,
-    // i.e. has the v0_13_3 format
-    let (seq, unpacker) = if uncompressed {
-        (seq, make_pack_const3())
+    // uncompressed means the sequence had been compressed previously
+    let seq = if format == InputFormat::Uncompressed {
+        if !da_format.is_compressed() {
+            return Err(anyhow!(
+                "decompressed data found where {} format is expected",
+                da_format.name()
+            ));
+        }
+
+        seq
     } else {
-        // This isn't really a _guaranteed_ check for the compressed
-        // format, but the update would have to be pretty much empty
-        // to have all high header bits clear... Maybe we should lock
-        // the format change the first time we encounter it?
-        if seq[0].to_usize().is_some() {
-            (seq, make_pack_const1())
-        } else {
-            let (unc, tail_size) = Decompressor::decompress(seq.into_iter())?;
+        // the registry decides, even when the data seems to disagree
+        if let Some(contradiction) = check_version(version, &seq) {
+            match source {
+                Some(source) => tracing::warn!("block {}: {}", source.block_no, contradiction),
+                None => tracing::warn!("{}", contradiction),
+            }
+        }
+
+        if da_format.is_compressed() {
+            let (unc, tail_size) = da_format
+                .decompress(&seq)
+                .map_err(|err| report_failure(err, &seq, da_format.name(), source, &dump_target))?;
             tracing::debug!(
                 "{} zeros after decompressed sequence of {} words",
                 tail_size,
@@ -182,19 +328,23 @@ fn do_parse(
                 uncond_dump(&unc, &dump_target)?;
             }
 
-            (unc, make_pack_const3())
+            unc
+        } else {
+            seq
         }
     };
 
-    let anno_dump: Box<dyn Write> = if dump_annotated {
-        dump_target.set_extension("anno");
+    let annotator = if let Some(annotation_format) = annotation {
+        dump_target.set_extension(annotation_format.get_extension());
         let file = fs::File::create(&dump_target)?;
-        Box::new(LineWriter::new(file))
+        Annotator::new(annotation_format, Box::new(LineWriter::new(file)))
     } else {
-        Box::new(std::io::empty())
+        Annotator::none()
     };
 
-    let state_diff = StateUpdateParser::parse(seq.into_iter(), unpacker, lookup, anno_dump)?;
+    let mut state_diff =
+        StateUpdateParser::parse(seq.iter().cloned(), da_format, lookup, annotator)
+            .map_err(|err| report_failure(err, &seq, da_format.name(), source, &dump_target))?;
     tracing::debug!("{} zeros after parsed blob", state_diff.tail_size);
 
     // see https://docs.starknet.io/architecture-and-concepts/network-architecture/starknet-state/#address_0x1
@@ -203,50 +353,253 @@ fn do_parse(
         .max_seq_no
         .map(|n| n + 10)
         .unwrap_or_default();
-    if let Some(log_seq_no) = seq_no {
-        if log_seq_no != to_seq_no {
+    // older calldata updates predate contract 0x1
+    let legacy = state_diff.range.max_seq_no.is_none() && format == InputFormat::Calldata;
+    let (from_seq_no, to_seq_no) = if legacy {
+        let n = seq_no.unwrap_or_default();
+        (n, n)
+    } else {
+        if let Some(log_seq_no) = seq_no
+            && log_seq_no != to_seq_no
+        {
             return Err(anyhow!(
                 "storage updates of contract 0x1 do not match log event calldata"
             ));
         }
-    }
-    if save_json {
-        if !dump_target.pop() {
-            return Err(anyhow!("can't get cache directory"));
-        }
 
         let from_seq_no = state_diff
             .range
             .min_seq_no
             .map(|n| n + 10)
             .unwrap_or_default();
-        let name = format!("{}-{}.json", from_seq_no, to_seq_no);
-        dump_target.push(name);
-        let j = state_diff.to_json_state_diff();
+        (from_seq_no, to_seq_no)
+    };
+    let range_known = if legacy {
+        seq_no.is_some()
+    } else {
+        state_diff.range.max_seq_no.is_some()
+    };
+    // the range tells whether the update starts at genesis, so it
+    // must be known before applying the update
+    if range_known {
+        state.set_seq_range((from_seq_no, to_seq_no))?;
+    }
+    state.apply(&mut state_diff)?;
+    if let Some(json) = json {
+        if !dump_target.pop() {
+            return Err(anyhow!("can't get cache directory"));
+        }
+
+        let (json, j) = match state_diff.to_json(&json) {
+            // not processed from genesis
+            Err(Error::Unclassified(address)) => {
+                tracing::warn!(
+                    "class change of contract {:#x} not classified, saving state diff (.state_diff.json) instead",
+                    address
+                );
+                let json = JsonOutput::StateDiffInstead;
+                let j = state_diff.to_json(&json)?;
+                (json, j)
+            }
+            res => (json, res?),
+        };
+        dump_target.push(json.get_file_name(from_seq_no, to_seq_no));
         fs::write(dump_target, j.to_string())?;
     }
 
-    Ok(())
+    Ok((from_seq_no, to_seq_no))
+}
+
+fn to_felt(n: U256) -> eyre::Result<Felt> {
+    Felt::from_be_bytes(n.to_be_bytes())
+}
+
+/// State update data fetched (and transformed) ahead of parsing.
+struct FetchedUpdate {
+    log: Log,
+    seq_no: u64,
+    /// Starknet state root after the update
+    global_root: Felt,
+    /// hash of the last Starknet block covered by the update
+    starknet_block_hash: Felt,
+    tx_hash: FixedBytes<32>,
+    /// empty for calldata updates
+    blob_hashes: Vec<FixedBytes<32>>,
+    seq: Vec<BigUint>,
+    format: InputFormat,
+    version: FormatVersion,
+}
+
+/// Fetches the data of state updates. Unlike parsing, fetching
+/// doesn't have to be done in chain order, so multiple updates can be
+/// fetched concurrently.
+struct Fetcher<P> {
+    provider: P,
+    downloader: Downloader,
+    memory_pages: MemoryPageFetcher,
+    archive: Archive,
+    offline: bool,
+    save: bool,
+    formats: FormatRegistry,
+}
+
+impl<P> Fetcher<P>
+where
+    P: Provider,
+{
+    async fn fetch(&self, log: Log) -> eyre::Result<FetchedUpdate> {
+        let decoded_log = LogStateUpdate::decode_log(&log.inner, true)?;
+        let seq_no = decoded_log.data.blockNumber.try_into()?;
+        let global_root = to_felt(decoded_log.data.globalRoot)?;
+        let starknet_block_hash = to_felt(decoded_log.data.blockHash)?;
+        let tx_hash = log.transaction_hash.context("log has no tx hash")?;
+        let outer = self.get_transaction(&tx_hash).await?;
+        let mut blob_hashes = Vec::new();
+        let (seq, format) = if let Some(signed) = outer.inner.as_eip4844() {
+            if let TxEip4844Variant::TxEip4844(tx) = signed.tx() {
+                if tx.blob_versioned_hashes.is_empty() {
+                    return Err(anyhow!("no blobs"));
+                }
+                let block_timestamp = if self.downloader.needs_block_timestamp() {
+                    Some(self.get_block_timestamp(&log).await?)
+                } else {
+                    None
+                };
+                let seq = self
+                    .downloader
+                    .download(&tx.blob_versioned_hashes, block_timestamp)
+                    .await?;
+                blob_hashes.clone_from(&tx.blob_versioned_hashes);
+                (seq, InputFormat::Blob)
+            } else {
+                // this would in fact be ideal, but doesn't happen in
+                // practice...
+                return Err(anyhow!("tx already includes blob"));
+            }
+        } else {
+            // older txs (and theoretically even newer ones, if
+            // Starknet switches back to calldata for some reason)
+            // publish state diffs in memory pages
+            let seq = self.get_memory_pages(&log, &tx_hash).await?;
+            (seq, InputFormat::Calldata)
+        };
+
+        let block_no = log.block_number.context("block not set")?;
+        let version = self.formats.get_version(block_no, Some(seq_no))?;
+        Ok(FetchedUpdate {
+            log,
+            seq_no,
+            global_root,
+            starknet_block_hash,
+            tx_hash,
+            blob_hashes,
+            seq,
+            format,
+            version,
+        })
+    }
+
+    async fn get_block_timestamp(&self, log: &Log) -> eyre::Result<u64> {
+        // not all nodes include timestamps in logs
+        if let Some(block_timestamp) = log.block_timestamp {
+            return Ok(block_timestamp);
+        }
+
+        let block_no = log.block_number.context("block not set")?;
+        let block = self
+            .provider
+            .get_block_by_number(BlockNumberOrTag::Number(block_no))
+            .await
+            .context("can't get block")?
+            .with_context(|| format!("block {} not found", block_no))?;
+        Ok(block.header.timestamp)
+    }
+
+    async fn get_transaction(&self, tx_hash: &FixedBytes<32>) -> eyre::Result<Transaction> {
+        if self.offline {
+            return Ok(self.archive.load_tx(tx_hash)?);
+        }
+
+        let tx = self.repeat_get_transaction(tx_hash).await?;
+        if self.save {
+            self.archive.save_tx(&tx)?;
+        }
+
+        Ok(tx)
+    }
+
+    async fn get_memory_pages(
+        &self,
+        log: &Log,
+        tx_hash: &FixedBytes<32>,
+    ) -> eyre::Result<Vec<BigUint>> {
+        if self.offline {
+            return Ok(self.archive.load_pages(tx_hash)?);
+        }
+
+        let seq = self
+            .memory_pages
+            .fetch(&self.provider, log)
+            .await
+            .with_context(|| format!("memory pages of tx {}", tx_hash))?;
+        if self.save {
+            self.archive.save_pages(tx_hash, &seq)?;
+        }
+
+        Ok(seq)
+    }
+
+    async fn repeat_get_transaction(&self, tx_hash: &FixedBytes<32>) -> eyre::Result<Transaction> {
+        for attempt in 1..=MAX_RETRIES {
+            match self.provider.get_transaction_by_hash(*tx_hash).await {
+                Ok(opt_tx) => {
+                    return opt_tx.ok_or_else(|| anyhow!("logged tx {} not found", tx_hash));
+                }
+                Err(e) => {
+                    tracing::warn!(
+                        "attempt {}: get_transaction_by_hash error: {:?}",
+                        attempt,
+                        e
+                    );
+                    sleep(Duration::from_secs(FAILED_FETCH_RETRY_INTERVAL_S)).await;
+                }
+            }
+        }
+
+        Err(anyhow!("can't get logged tx {}", tx_hash))
+    }
 }
 
 struct App<P> {
     cli: Cli,
-    provider: P,
     filter_base: Filter,
-    downloader: Downloader,
+    fetcher: Fetcher<P>,
     dumper: Dumper,
     lookup: Rc<RefCell<Lookup>>,
+    state: StateStore,
+    history: History,
+    cache_dir: PathBuf,
+    network: NetworkProfile,
+    /// position of the last update processed before restart
+    resume_after: Option<(u64, u64)>,
+    /// Starknet state root after the last processed update, if known
+    last_root: Option<Felt>,
 }
 
 impl<P> App<P>
 where
     P: Provider,
 {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         cli: Cli,
         config: Config,
+        network: NetworkProfile,
         provider: P,
         lookup: Rc<RefCell<Lookup>>,
+        state: StateStore,
+        history: History,
+        checkpoint: Option<UpdateRecord>,
     ) -> eyre::Result<Self> {
         let cache_dir = fs::canonicalize(&config.cache_dir)?;
 
@@ -259,137 +612,369 @@ where
             .default_headers(headers)
             .build()?;
 
-        let starknet_core = address!("0xc662c410C0ECf747543f5bA90660f6ABeBD9C8c4");
         let filter_base = Filter::new()
-            .address(starknet_core)
+            .address(network.core_contract)
             .event_signature(LogStateUpdate::SIGNATURE_HASH);
 
-        let downloader = Downloader::new(
-            client,
-            config.blob_url_base,
-            cli.save,
-            cache_dir.clone(),
-            cli.prune,
-        );
+        let blob_source = if cli.offline {
+            BlobSourceKind::Local
+        } else {
+            config.blob_source
+        };
+        let source = match blob_source {
+            BlobSourceKind::Blobscan => BlobSource::Blobscan {
+                url_base: config.blob_url_base,
+            },
+            BlobSourceKind::Beacon => BlobSource::Beacon {
+                url: config
+                    .beacon_url
+                    .context("beacon blob source requires beacon_url")?,
+            },
+            BlobSourceKind::Local => BlobSource::Local,
+        };
+        let downloader = Downloader::new(client, source, cli.save, cache_dir.clone(), cli.prune);
+        let fetcher = Fetcher {
+            provider,
+            downloader,
+            memory_pages: MemoryPageFetcher::new(
+                &config.rpc_url,
+                network.gps_verifier,
+                network.memory_page_registry,
+            ),
+            archive: Archive::new(cache_dir.clone()),
+            offline: cli.offline,
+            save: cli.save,
+            formats: network.get_format_registry()?,
+        };
         let dumper = Dumper::new(cli.dump, cache_dir.clone(), cli.prune);
 
         Ok(Self {
             cli,
-            provider,
             filter_base,
-            downloader,
+            fetcher,
             dumper,
             lookup,
+            state,
+            history,
+            cache_dir,
+            network,
+            resume_after: checkpoint
+                .as_ref()
+                .map(|record| (record.block_no, record.log_index)),
+            last_root: checkpoint.and_then(|record| record.global_root),
         })
     }
 
-    pub async fn cycle(&mut self, from_block: u64, to_block: u64) -> eyre::Result<()> {
+    pub async fn check_chain_id(&self) -> eyre::Result<()> {
+        let chain_id = self
+            .fetcher
+            .provider
+            .get_chain_id()
+            .await
+            .context("can't get chain id")?;
+        self.network.check_chain_id(chain_id, "RPC node")
+    }
+
+    /// Returns the newest block with enough confirmations to be
+    /// processed.
+    pub async fn get_head(&self) -> eyre::Result<u64> {
+        let tag = match self.cli.confirmations {
+            Confirmation::Depth(_) => BlockNumberOrTag::Latest,
+            Confirmation::Finalized => BlockNumberOrTag::Finalized,
+        };
+        let block = self
+            .fetcher
+            .provider
+            .get_block_by_number(tag)
+            .await
+            .context("can't get head")?
+            .with_context(|| format!("{} block not found", tag))?;
+        let latest = block.header.number;
+        Ok(self.cli.confirmations.apply_depth(latest))
+    }
+
+    pub async fn get_logs(&self, from_block: u64, to_block: u64) -> eyre::Result<Vec<Log>> {
+        if self.cli.offline {
+            return Ok(self.fetcher.archive.load_logs(from_block, to_block)?);
+        }
+
         let filter = self
             .filter_base
             .clone()
             .from_block(from_block)
             .to_block(to_block);
         let logs = self
+            .fetcher
             .provider
             .get_logs(&filter)
             .await
             .context("can't get logs")?;
-        tracing::info!("got {} log(s)", logs.len());
-        if logs.is_empty() {
-            // obviously it would be better to increase the range here
-            // (unless we're already at the newest block), but that's
-            // getting into the full dynamic range support...
-            return Err(anyhow!("no logs found"));
-        }
+        Ok(logs)
+    }
 
-        for log in logs {
-            let cur_block_no = log.block_number.context("block not set")?;
-            self.dumper.set_block_no(cur_block_no)?;
-            {
-                let mut lookup = self.lookup.borrow_mut();
-                lookup.set_block_no(cur_block_no);
+    /// Compares the block hashes of processed updates with the
+    /// canonical chain, newest first, and returns the oldest block
+    /// number that no longer matches.
+    pub async fn find_fork(&self) -> eyre::Result<Option<u64>> {
+        let mut fork = None;
+        let mut before = None;
+        let mut last_checked: Option<(u64, bool)> = None;
+        loop {
+            let records = self.history.get_preceding(before, REORG_CHECK_BATCH)?;
+            if records.is_empty() {
+                return Ok(fork);
             }
-            let decoded_log = LogStateUpdate::decode_log(&log.inner, true)?;
-            let raw_seq_no = decoded_log.data.blockNumber;
-            tracing::debug!(
-                "processing Ethereum block {} (Starknet {})...",
-                cur_block_no,
-                raw_seq_no
-            );
-            let seq_no = raw_seq_no.try_into()?;
-            let tx_hash = log.transaction_hash.context("log has no tx hash")?;
-            let outer = self.repeat_get_transaction(&tx_hash).await?;
-            if let Some(signed) = outer.inner.as_eip4844() {
-                if let TxEip4844Variant::TxEip4844(tx) = signed.tx() {
-                    if tx.blob_versioned_hashes.is_empty() {
-                        return Err(anyhow!("no blobs"));
+
+            for record in records {
+                before = Some((record.block_no, record.log_index));
+                // blocks with multiple updates are checked just once
+                let same = match last_checked {
+                    Some((block_no, same)) if block_no == record.block_no => same,
+                    _ => {
+                        let same = self.is_canonical(&record).await?;
+                        last_checked = Some((record.block_no, same));
+                        same
                     }
-                    let mut seq = Vec::new();
-                    for blob in tx.blob_versioned_hashes.iter() {
-                        let mut transformed = self.downloader.download(blob).await?;
-                        seq.append(&mut transformed);
+                };
+                if same {
+                    return Ok(fork);
+                }
+
+                fork = Some(record.block_no);
+            }
+        }
+    }
+
+    async fn is_canonical(&self, record: &UpdateRecord) -> eyre::Result<bool> {
+        let opt_block = self
+            .fetcher
+            .provider
+            .get_block_by_number(BlockNumberOrTag::Number(record.block_no))
+            .await
+            .context("can't get block")?;
+        // a missing block is past the (new) head
+        Ok(opt_block.is_some_and(|block| block.header.hash == record.block_hash))
+    }
+
+    /// Removes lookup table entries and outputs derived from updates
+    /// in blocks from `fork_block` onwards.
+    pub fn roll_back(&mut self, fork_block: u64) -> eyre::Result<()> {
+        let removed = self.history.truncate_from(fork_block)?;
+        tracing::warn!(
+            "reorg at block {}: rolling back {} update(s)",
+            fork_block,
+            removed.len()
+        );
+        if let Some(first) = removed.first() {
+            let mut lookup = self.lookup.borrow_mut();
+            lookup.roll_back(first.lookup_size, fork_block)?;
+        }
+        self.state.roll_back(fork_block)?;
+
+        for record in removed.iter() {
+            if let Some((from_seq_no, to_seq_no)) = record.seq_range {
+                for json in [JsonOutput::StateDiff, JsonOutput::StateDiffInstead] {
+                    let path = self
+                        .cache_dir
+                        .join(json.get_file_name(from_seq_no, to_seq_no));
+                    if path.exists() {
+                        fs::remove_file(path)?;
                     }
-                    self.dumper.cond_dump(&seq)?;
-                    self.cond_parse(seq, seq_no)?;
-                } else {
-                    // this would in fact be ideal, but doesn't happen in
-                    // practice...
-                    return Err(anyhow!("tx already includes blob"));
                 }
-            } else {
-                // this can actually happen for older txs (and
-                // theoretically even newer ones, if Starknet switches to
-                // calldata for some reason), but we aren't supporting
-                // them yet...
-                return Err(anyhow!("tx not EIP4844"));
             }
         }
 
+        self.dumper.remove_from(fork_block)?;
+        self.fetcher.archive.remove_logs_from(fork_block)?;
+        if self
+            .resume_after
+            .is_some_and(|(block_no, _)| block_no >= fork_block)
+        {
+            self.resume_after = None;
+        }
+        // the new checkpoint may not precede the next processed
+        // update, so the root is fetched again
+        self.last_root = None;
+
         Ok(())
     }
 
-    async fn repeat_get_transaction(
-        &mut self,
-        tx_hash: &FixedBytes<32>,
-    ) -> eyre::Result<Transaction> {
-        for attempt in 1..=MAX_RETRIES {
-            match self.provider.get_transaction_by_hash(*tx_hash).await {
-                Ok(opt_tx) => {
-                    return opt_tx.ok_or_else(|| anyhow!("logged tx {} not found", tx_hash));
-                }
-                Err(e) => {
-                    tracing::warn!(
-                        "attempt {}: get_transaction_by_hash error: {:?}",
-                        attempt,
-                        e
-                    );
-                    sleep(Duration::from_secs(FAILED_FETCH_RETRY_INTERVAL_S)).await;
+    /// Returns the Starknet state root as of the end of Ethereum
+    /// block `block_no` (which requires an archive node for older
+    /// blocks).
+    async fn get_state_root(&self, block_no: u64) -> eyre::Result<Felt> {
+        let core = StarknetCore::new(self.network.core_contract, &self.fetcher.provider);
+        let root = core
+            .stateRoot()
+            .block(BlockId::number(block_no))
+            .call()
+            .await
+            .with_context(|| format!("can't get state root at block {}", block_no))?
+            ._0;
+        to_felt(root)
+    }
+
+    /// Determines the JSON output for `update`, which must be the
+    /// first update after the last processed one.
+    async fn get_json_output(
+        &self,
+        update: &FetchedUpdate,
+        block_no: u64,
+    ) -> eyre::Result<JsonOutput> {
+        if self.cli.json_format == JsonFormat::StateDiff {
+            return Ok(JsonOutput::StateDiff);
+        }
+
+        let old_root = match self.last_root {
+            Some(root) => root,
+            None if self.cli.offline => {
+                tracing::warn!(
+                    "state root before block {} not known offline, saving state diff (.state_diff.json) instead",
+                    block_no
+                );
+                return Ok(JsonOutput::StateDiffInstead);
+            }
+            None => match self.get_state_root(block_no - 1).await {
+                Ok(root) => root,
+                // most likely a node without historical state
+                Err(err) => {
+                    tracing::warn!("{:#}, saving state diff (.state_diff.json) instead", err);
+                    return Ok(JsonOutput::StateDiffInstead);
                 }
+            },
+        };
+        Ok(JsonOutput::StateUpdate(UpdateHeader {
+            block_hash: update.starknet_block_hash,
+            old_root,
+            new_root: update.global_root,
+        }))
+    }
+
+    /// Fetches the data of up to `cli.concurrency` updates at once,
+    /// but parses them strictly in chain order (which stateful
+    /// compression requires).
+    pub async fn process_logs(&mut self, logs: Vec<Log>) -> eyre::Result<()> {
+        let mut logs = logs;
+        if let Some(checkpoint) = self.resume_after {
+            logs.retain(|log| match (log.block_number, log.log_index) {
+                (Some(block_no), Some(log_index)) => (block_no, log_index) > checkpoint,
+                _ => true,
+            });
+        }
+
+        let fetcher = &self.fetcher;
+        let mut updates =
+            fetch_in_order(logs, self.cli.concurrency.get(), |log| fetcher.fetch(log));
+        while let Some(res) = updates.next().await {
+            let update = res?;
+            let log = &update.log;
+            if self.cli.save && !self.cli.offline {
+                fetcher.archive.save_log(log)?;
+            }
+
+            let cur_block_no = log.block_number.context("block not set")?;
+            let log_index = log.log_index.context("log has no index")?;
+            self.dumper.set_block_no(cur_block_no)?;
+            self.state.set_position((cur_block_no, log_index));
+            if self.cli.verify_root {
+                self.state.set_global_root(update.global_root);
             }
+            let lookup_size = {
+                let mut lookup = self.lookup.borrow_mut();
+                lookup.set_position((cur_block_no, log_index));
+                lookup.get_table_size()?
+            };
+            tracing::debug!(
+                "processing Ethereum block {} (Starknet {})...",
+                cur_block_no,
+                update.seq_no
+            );
+            self.dumper.cond_dump(&update.seq, update.seq_no)?;
+            let json = if self.cli.json {
+                Some(self.get_json_output(&update, cur_block_no).await?)
+            } else {
+                None
+            };
+            let source = UpdateSource {
+                block_no: cur_block_no,
+                tx_hash: Some(update.tx_hash),
+                blob_hashes: update.blob_hashes,
+            };
+            let seq_range = if self.cli.parse {
+                // dumping uncompressed sequences isn't supported while
+                // fetching to minimize disk requirements while processing
+                // statefully-compressed sequences (which must be dumped
+                // to allow continuing after restart)
+                let range = do_parse(
+                    self.lookup.clone(),
+                    &mut self.state,
+                    update.seq,
+                    update.format,
+                    update.version,
+                    false,
+                    None,
+                    json,
+                    self.dumper.make_dump_target("unc")?,
+                    Some(update.seq_no),
+                    Some(&source),
+                )?;
+                Some(range)
+            } else {
+                None
+            };
+
+            // the update is fully processed when its lookup table
+            // expansion is persisted, together with its record
+            let record = UpdateRecord {
+                block_no: cur_block_no,
+                log_index,
+                block_hash: log.block_hash.context("log has no block hash")?,
+                tx_hash: update.tx_hash,
+                lookup_size,
+                seq_range,
+                global_root: Some(update.global_root),
+                starknet_block_hash: Some(update.starknet_block_hash),
+            };
+            let mut lookup = self.lookup.borrow_mut();
+            let state = &mut self.state;
+            lookup.commit(|txn| {
+                History::record(txn, &record)?;
+                state.commit(txn)
+            })?;
+            fetcher.downloader.prune(&source.blob_hashes)?;
+            self.last_root = Some(update.global_root);
         }
 
-        Err(anyhow!("can't get logged tx {}", tx_hash))
+        Ok(())
     }
+}
 
-    fn cond_parse(&mut self, seq: Vec<BigUint>, seq_no: u64) -> eyre::Result<()> {
-        if self.cli.parse {
-            // dumping uncompressed sequences isn't supported while
-            // fetching to minimize disk requirements while processing
-            // statefully-compressed sequences (which must be dumped
-            // to allow continuing after restart)
-            do_parse(
-                self.lookup.clone(),
-                seq,
-                false,
-                false,
-                false,
-                self.cli.json,
-                self.dumper.make_dump_target("unc")?,
-                Some(seq_no),
-            )
-        } else {
+// The tries are only correct when maintained since Starknet genesis -
+// which the default start (the first blob update) isn't. `option`
+// names the command-line option requiring it.
+fn check_genesis(
+    state: &StateStore,
+    history: &History,
+    from_block_set: bool,
+    option: &str,
+) -> eyre::Result<()> {
+    match state.is_from_genesis()? {
+        Some(true) => Ok(()),
+        Some(false) => Err(anyhow!(
+            "{option} requires processing from Starknet genesis, but the database starts later"
+        )),
+        None if !history.get_preceding(None, 1)?.is_empty() => Err(anyhow!(
+            "{option} requires processing from Starknet genesis, but the database doesn't record where it starts"
+        )),
+        None if from_block_set => {
+            tracing::warn!(
+                "{option} only succeeds if --from-block has the first Starknet state update"
+            );
             Ok(())
         }
+        None => Err(anyhow!(
+            "{option} requires processing from Starknet genesis: set --from-block to the Ethereum block of its state update"
+        )),
     }
 }
 
@@ -405,6 +990,10 @@ async fn main() -> eyre::Result<()> {
         tracing::info!("command-line option json implies parse");
         cli.parse = true;
     }
+    if cli.verify_root && !cli.parse {
+        tracing::info!("command-line option verify-root implies parse");
+        cli.parse = true;
+    }
     if cli.annotate_only && (!cli.parse_local || !cli.dump || !cli.no_connect) {
         tracing::info!(
             "command-line option annotate-only implies options parse-local, dump and no-connect"
@@ -414,18 +1003,48 @@ async fn main() -> eyre::Result<()> {
         cli.no_connect = true;
     }
 
+    let network = config.get_network_profile()?;
     fs::create_dir_all(&config.cache_dir)?;
+    network.tag_cache_dir(&config.cache_dir)?;
 
-    let bare_lookup = Lookup::new(&config.db_file)?;
+    let db = Arc::new(Database::create(&config.db_file)?);
+    network.tag_db(&db)?;
+    let bare_lookup = Lookup::new(db.clone());
     let lookup = Rc::new(RefCell::new(bare_lookup));
+    let mut state = StateStore::new(db.clone());
+    if cli.verify_root {
+        state.enable_commitment();
+    }
+
+    if let Some(address) = cli.query {
+        query_contract(&state, &address, cli.storage_key.as_ref(), cli.at)?;
+        if let Some(key) = cli.storage_key.as_ref().filter(|_| cli.prove) {
+            let history = History::new(db.clone());
+            prove_storage(&state, &history, &address, key, cli.at)?;
+        }
+        return Ok(());
+    }
 
     if cli.parse_local {
+        let json = cli.json.then(|| {
+            if cli.json_format == JsonFormat::StateUpdate {
+                tracing::warn!(
+                    "state roots of dumped updates aren't known, saving their state diffs (.state_diff.json)"
+                );
+                JsonOutput::StateDiffInstead
+            } else {
+                JsonOutput::StateDiff
+            }
+        });
         parse_local(
             lookup.clone(),
+            &mut state,
+            &network.get_format_registry()?,
             &config.cache_dir,
             cli.annotate_only,
+            cli.annotation_format,
             cli.dump,
-            cli.json,
+            json,
         )?;
     }
 
@@ -434,23 +1053,133 @@ async fn main() -> eyre::Result<()> {
         return Ok(());
     }
 
+    // when offline, the provider isn't used, but it doesn't connect
+    // until asked for data, so there's no harm in creating it anyway
     let rpc_url = config.rpc_url.parse()?;
     let provider = ProviderBuilder::new().on_http(rpc_url);
 
     let block_count = cli.block_count.get();
     let single_shot = cli.single_shot;
-    let mut from_block = cli.from_block.get();
-    let mut to_block = from_block + block_count - 1;
-    let mut app = App::new(cli, config, provider, lookup.clone())?;
+    let offline = cli.offline;
+    let follow = cli.follow;
+    let poll_interval = Duration::from_secs(cli.poll_interval);
+    let history = History::new(db);
+    if cli.verify_root {
+        check_genesis(&state, &history, cli.from_block.is_some(), "--verify-root")?;
+    }
+    let (from_block, checkpoint) = match (cli.from_block, history.get_checkpoint(cli.parse)?) {
+        (Some(n), _) => (n.get(), None),
+        (None, Some(checkpoint)) => {
+            tracing::info!(
+                "resuming after block {} log {} (Starknet {:?})",
+                checkpoint.block_no,
+                checkpoint.log_index,
+                checkpoint.seq_range
+            );
+            (checkpoint.block_no, Some(checkpoint))
+        }
+        (None, None) => (network.first_blob_block, None),
+    };
+    let mut app = App::new(
+        cli,
+        config,
+        network,
+        provider,
+        lookup.clone(),
+        state,
+        history,
+        checkpoint,
+    )?;
+    if offline {
+        let last_block = app
+            .fetcher
+            .archive
+            .last_block()?
+            .context("no saved logs in cache directory")?;
+        let logs = app.get_logs(from_block, last_block).await?;
+        tracing::info!("got {} log(s)", logs.len());
+        app.process_logs(logs).await?;
+        tracing::info!("replayed up to {}", last_block);
+        return Ok(());
+    }
+
+    app.check_chain_id().await?;
+    let mut scanner = RangeScanner::new(from_block, block_count, MAX_BLOCK_COUNT);
+    if let Some(fork_block) = app.find_fork().await? {
+        app.roll_back(fork_block)?;
+        scanner.rewind(fork_block);
+    }
+
+    let mut head = app.get_head().await?;
     loop {
-        app.cycle(from_block, to_block).await?;
+        let (from_block, to_block) = match scanner.next_range(head) {
+            Some(range) => range,
+            None => {
+                head = app.get_head().await?;
+                if let Some(fork_block) = app.find_fork().await? {
+                    app.roll_back(fork_block)?;
+                    scanner.rewind(fork_block);
+                }
+
+                match scanner.next_range(head) {
+                    Some(range) => range,
+                    None => {
+                        if follow {
+                            tracing::debug!("waiting for blocks after {}", head);
+                            sleep(poll_interval).await;
+                            continue;
+                        }
+
+                        tracing::info!("reached chain head {}", head);
+                        return Ok(());
+                    }
+                }
+            }
+        };
+
+        let logs = match app.get_logs(from_block, to_block).await {
+            Ok(logs) => logs,
+            Err(e) if is_range_error(&e) => {
+                if scanner.reject() {
+                    tracing::warn!(
+                        "blocks {}-{} rejected, shrinking range: {:?}",
+                        from_block,
+                        to_block,
+                        e
+                    );
+                    continue;
+                } else {
+                    return Err(e);
+                }
+            }
+            Err(e) => match scanner.retry() {
+                Some(delay) => {
+                    tracing::warn!(
+                        "can't get logs {}-{}, retrying in {:?}: {:?}",
+                        from_block,
+                        to_block,
+                        delay,
+                        e
+                    );
+                    sleep(delay).await;
+                    continue;
+                }
+                None => return Err(e),
+            },
+        };
+
+        let log_count = logs.len();
+        if log_count > 0 {
+            tracing::info!("got {} log(s)", log_count);
+            app.process_logs(logs).await?;
+        }
+
+        scanner.advance(to_block, log_count);
         if single_shot {
             tracing::info!("done");
             return Ok(());
         }
 
         tracing::info!("last checked {}", to_block);
-        from_block = to_block + 1;
-        to_block = from_block + block_count - 1;
     }
 }
@@ -0,0 +1,617 @@
+// Before EIP-4844 (and for any future return to calldata), Starknet
+// state diffs are published as "continuous memory pages", registered
+// with the memory page fact registry in separate transactions and
+// linked to the state update through the SHARP verifier:
+//
+// - the state update tx emits LogStateTransitionFact(fact) just
+//   before LogStateUpdate,
+// - the verifier emits LogMemoryPagesHashes(fact, hashes) when the
+//   proof is verified,
+// - each hash is announced by LogMemoryPageFactContinuous(_, hash, _)
+//   of the tx registering the page.
+//
+// The first page is the main page (i.e. the program output); the state
+// diff is the concatenation of the rest.
+
+use alloy::{
+    consensus::Transaction as _,
+    primitives::{Address, B256, keccak256},
+    providers::Provider,
+    rpc::types::{Filter, Log},
+    sol_types::{SolCall, SolEvent},
+};
+use num_bigint::BigUint;
+use tokio::time::sleep;
+
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+
+use crate::error::{Error, Result};
+use crate::eth::{
+    GpsStatementVerifier::LogMemoryPagesHashes,
+    MemoryPageFactRegistry::{LogMemoryPageFactContinuous, registerContinuousMemoryPageCall},
+    StarknetCore::LogStateTransitionFact,
+};
+use crate::scan::{Backoff, RETRY_BASE_INTERVAL};
+
+/// Number of blocks checked by one `eth_getLogs` call when searching
+/// for the events linking a state update to its memory pages.
+const SEARCH_WINDOW: u64 = 500;
+/// How far back (in blocks) from the state update to search before
+/// giving up.
+const MAX_SEARCH_DEPTH: u64 = 20_000;
+
+pub struct MemoryPageFetcher {
+    /// Ethereum node, as reported by transport errors
+    rpc_origin: String,
+    /// emitter of LogMemoryPagesHashes
+    gps_verifier: Address,
+    /// emitter of LogMemoryPageFactContinuous, if known
+    memory_page_registry: Option<Address>,
+    window: u64,
+    max_depth: u64,
+    /// delay before the first retry of a failed page download
+    retry_interval: Duration,
+}
+
+impl MemoryPageFetcher {
+    /// Only the origin of `rpc_url` is kept (for errors), as its path
+    /// may contain an API key.
+    pub fn new(
+        rpc_url: &str,
+        gps_verifier: Address,
+        memory_page_registry: Option<Address>,
+    ) -> Self {
+        let rpc_origin = match reqwest::Url::parse(rpc_url) {
+            Ok(url) => url.origin().ascii_serialization(),
+            Err(_) => "Ethereum RPC".to_string(),
+        };
+        Self {
+            rpc_origin,
+            gps_verifier,
+            memory_page_registry,
+            window: SEARCH_WINDOW,
+            max_depth: MAX_SEARCH_DEPTH,
+            retry_interval: RETRY_BASE_INTERVAL,
+        }
+    }
+
+    /// Reconstructs the state diff words of the update logged by
+    /// `log` (which must be a `LogStateUpdate` event).
+    pub async fn fetch<P: Provider>(&self, provider: &P, log: &Log) -> Result<Vec<BigUint>> {
+        let block_no = log
+            .block_number
+            .ok_or_else(|| self.transport_error("state update log has no block number"))?;
+        let tx_hash = log
+            .transaction_hash
+            .ok_or_else(|| self.transport_error("state update log has no tx hash"))?;
+        let fact = self
+            .find_state_transition_fact(provider, log, tx_hash)
+            .await?;
+        let (pages_hashes, gps_block_no) = self
+            .find_pages_hashes(provider, fact, block_no)
+            .await?
+            .ok_or_else(|| Error::MissingPages {
+                tx_hash,
+                message: format!("memory pages hashes of fact {} not found", fact),
+            })?;
+        let data_hashes = pages_hashes.get(1..).unwrap_or_default();
+        let registrations = self
+            .find_registrations(provider, tx_hash, data_hashes, gps_block_no)
+            .await?;
+
+        let mut seq = Vec::new();
+        for page_hash in data_hashes.iter() {
+            let page_tx_hash = registrations
+                .get(page_hash)
+                .ok_or_else(|| Error::MissingPages {
+                    tx_hash,
+                    message: format!("memory page {} not registered", page_hash),
+                })?;
+            let mut values = self.get_page(provider, page_tx_hash, page_hash).await?;
+            seq.append(&mut values);
+        }
+
+        tracing::debug!(
+            "{} word(s) in {} memory page(s)",
+            seq.len(),
+            data_hashes.len()
+        );
+        Ok(seq)
+    }
+
+    fn transport_error(&self, message: impl Into<String>) -> Error {
+        Error::Transport {
+            url: self.rpc_origin.clone(),
+            message: message.into(),
+        }
+    }
+
+    async fn find_state_transition_fact<P: Provider>(
+        &self,
+        provider: &P,
+        log: &Log,
+        tx_hash: B256,
+    ) -> Result<B256> {
+        let missing = |message: &str| Error::MissingPages {
+            tx_hash,
+            message: message.to_string(),
+        };
+        let log_index = log
+            .log_index
+            .ok_or_else(|| self.transport_error("state update log has no index"))?;
+        let receipt = provider
+            .get_transaction_receipt(tx_hash)
+            .await
+            .map_err(|e| self.transport_error(format!("can't get receipt of {}: {}", tx_hash, e)))?
+            .ok_or_else(|| self.transport_error(format!("receipt of {} not found", tx_hash)))?;
+        let fact_log = receipt
+            .inner
+            .logs()
+            .iter()
+            .filter(|l| {
+                l.address() == log.address()
+                    && l.log_index.is_some_and(|i| i < log_index)
+                    && l.topic0() == Some(&LogStateTransitionFact::SIGNATURE_HASH)
+            })
+            .last()
+            .ok_or_else(|| missing("no state transition fact"))?;
+        let decoded = LogStateTransitionFact::decode_log(&fact_log.inner, true)
+            .map_err(|_| missing("invalid state transition fact"))?;
+        Ok(decoded.data.stateTransitionFact)
+    }
+
+    async fn find_pages_hashes<P: Provider>(
+        &self,
+        provider: &P,
+        fact: B256,
+        block_no: u64,
+    ) -> Result<Option<(Vec<B256>, u64)>> {
+        let mut found = None;
+        self.search_back(
+            provider,
+            Some(self.gps_verifier),
+            LogMemoryPagesHashes::SIGNATURE_HASH,
+            block_no,
+            |log| {
+                // the event isn't indexed, so other SHARP users' facts
+                // are interleaved with Starknet's
+                if let Ok(decoded) = LogMemoryPagesHashes::decode_log(&log.inner, true)
+                    && decoded.data.programOutputFact == fact
+                    && let Some(gps_block_no) = log.block_number
+                {
+                    found = Some((decoded.data.pagesHashes.clone(), gps_block_no));
+                    return true;
+                }
+
+                false
+            },
+        )
+        .await?;
+        Ok(found)
+    }
+
+    async fn find_registrations<P: Provider>(
+        &self,
+        provider: &P,
+        tx_hash: B256,
+        page_hashes: &[B256],
+        block_no: u64,
+    ) -> Result<HashMap<B256, B256>> {
+        let mut registrations = HashMap::new();
+        // the same page can be used more than once
+        let wanted: HashSet<&B256> = page_hashes.iter().collect();
+        if wanted.is_empty() {
+            return Ok(registrations);
+        }
+
+        self.search_back(
+            provider,
+            self.memory_page_registry,
+            LogMemoryPageFactContinuous::SIGNATURE_HASH,
+            block_no,
+            |log| {
+                if let Ok(decoded) = LogMemoryPageFactContinuous::decode_log(&log.inner, true) {
+                    let memory_hash = B256::from(decoded.data.memoryHash);
+                    if wanted.contains(&memory_hash)
+                        && let Some(page_tx_hash) = log.transaction_hash
+                    {
+                        // going backwards, so the latest registration
+                        // wins
+                        registrations.entry(memory_hash).or_insert(page_tx_hash);
+                    }
+                }
+
+                registrations.len() == wanted.len()
+            },
+        )
+        .await?;
+        if registrations.len() < wanted.len() {
+            return Err(Error::MissingPages {
+                tx_hash,
+                message: format!(
+                    "found only {} of {} memory page registrations",
+                    registrations.len(),
+                    wanted.len()
+                ),
+            });
+        }
+
+        Ok(registrations)
+    }
+
+    async fn get_page<P: Provider>(
+        &self,
+        provider: &P,
+        tx_hash: &B256,
+        page_hash: &B256,
+    ) -> Result<Vec<BigUint>> {
+        let integrity_error = |message: String| Error::PageIntegrity {
+            page_hash: *page_hash,
+            message,
+        };
+        let mut backoff = Backoff::new(self.retry_interval);
+        let tx = loop {
+            match provider.get_transaction_by_hash(*tx_hash).await {
+                Ok(tx) => break tx,
+                Err(e) => match backoff.retry() {
+                    Some(delay) => {
+                        tracing::warn!(
+                            "can't get memory page tx {}, retrying in {:?}: {:?}",
+                            tx_hash,
+                            delay,
+                            e
+                        );
+                        sleep(delay).await;
+                    }
+                    None => {
+                        return Err(self.transport_error(format!(
+                            "can't get memory page tx {}: {}",
+                            tx_hash, e
+                        )));
+                    }
+                },
+            }
+        }
+        .ok_or_else(|| self.transport_error(format!("memory page tx {} not found", tx_hash)))?;
+        let call =
+            registerContinuousMemoryPageCall::abi_decode(tx.inner.input(), true).map_err(|_| {
+                integrity_error(format!(
+                    "tx {} doesn't register memory page directly",
+                    tx_hash
+                ))
+            })?;
+        let mut raw = Vec::with_capacity(call.values.len() * 32);
+        for value in call.values.iter() {
+            raw.extend_from_slice(&value.to_be_bytes::<32>());
+        }
+
+        if keccak256(&raw) != *page_hash {
+            return Err(integrity_error(format!(
+                "page registered by {} doesn't match its hash",
+                tx_hash
+            )));
+        }
+
+        Ok(raw.chunks(32).map(BigUint::from_bytes_be).collect())
+    }
+
+    /// Calls `visit` on logs with event signature `signature` (emitted
+    /// by `address`, if given), newest first, starting at
+    /// `start_block` and going back until `visit` returns true (or
+    /// the maximum search depth is reached).
+    async fn search_back<P, F>(
+        &self,
+        provider: &P,
+        address: Option<Address>,
+        signature: B256,
+        start_block: u64,
+        mut visit: F,
+    ) -> Result<()>
+    where
+        P: Provider,
+        F: FnMut(&Log) -> bool,
+    {
+        let mut to_block = start_block;
+        loop {
+            let from_block = to_block.saturating_sub(self.window - 1);
+            let mut filter = Filter::new()
+                .event_signature(signature)
+                .from_block(from_block)
+                .to_block(to_block);
+            if let Some(address) = address {
+                filter = filter.address(address);
+            }
+            let logs = provider.get_logs(&filter).await.map_err(|e| {
+                self.transport_error(format!("can't get logs {}-{}: {}", from_block, to_block, e))
+            })?;
+            // the node should have filtered them already
+            let from_address = |log: &&Log| address.is_none_or(|a| log.address() == a);
+            for log in logs.iter().rev().filter(from_address) {
+                if visit(log) {
+                    return Ok(());
+                }
+            }
+
+            if (from_block == 0) || (start_block - from_block >= self.max_depth) {
+                return Ok(());
+            }
+
+            to_block = from_block - 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloy::{
+        primitives::{Address, B256, Bytes, U256, keccak256},
+        providers::ProviderBuilder,
+        rpc::types::Log,
+        sol_types::{SolCall, SolEvent},
+        transports::mock::Asserter,
+    };
+    use num_bigint::BigUint;
+    use serde_json::json;
+
+    use std::time::Duration;
+
+    use super::MemoryPageFetcher;
+    use crate::error::Error;
+    use crate::eth::{
+        GpsStatementVerifier::LogMemoryPagesHashes,
+        MemoryPageFactRegistry::{LogMemoryPageFactContinuous, registerContinuousMemoryPageCall},
+        StarknetCore::{LogStateTransitionFact, LogStateUpdate},
+    };
+
+    const CORE: Address = Address::repeat_byte(0xc0);
+    const VERIFIER: Address = Address::repeat_byte(0x9e);
+    const REGISTRY: Address = Address::repeat_byte(0x4e);
+    const UPDATE_TX: B256 = B256::repeat_byte(0x11);
+    const FACT: B256 = B256::repeat_byte(0xfa);
+
+    fn make_log<E: SolEvent>(event: &E, address: Address, block_no: u64, tx_hash: B256) -> Log {
+        Log {
+            inner: alloy::primitives::Log {
+                address,
+                data: event.encode_log_data(),
+            },
+            block_hash: Some(B256::with_last_byte(block_no as u8)),
+            block_number: Some(block_no),
+            block_timestamp: None,
+            transaction_hash: Some(tx_hash),
+            transaction_index: Some(0),
+            log_index: Some(0),
+            removed: false,
+        }
+    }
+
+    fn page_hash(values: &[u64]) -> B256 {
+        let mut raw = Vec::new();
+        for value in values.iter() {
+            raw.extend_from_slice(&U256::from(*value).to_be_bytes::<32>());
+        }
+        keccak256(&raw)
+    }
+
+    fn push_receipt(asserter: &Asserter, logs: &[Log]) {
+        asserter.push_success(&json!({
+            "type": "0x2",
+            "status": "0x1",
+            "cumulativeGasUsed": "0x1",
+            "logs": logs,
+            "logsBloom": format!("0x{}", "00".repeat(256)),
+            "transactionHash": UPDATE_TX,
+            "transactionIndex": "0x0",
+            "blockHash": B256::ZERO,
+            "blockNumber": "0x3e8",
+            "gasUsed": "0x1",
+            "effectiveGasPrice": "0x1",
+            "from": Address::ZERO,
+            "to": CORE,
+            "contractAddress": null,
+        }));
+    }
+
+    fn push_page_tx(asserter: &Asserter, tx_hash: B256, values: &[u64]) {
+        let call = registerContinuousMemoryPageCall {
+            startAddr: U256::ZERO,
+            values: values.iter().map(|v| U256::from(*v)).collect(),
+            z: U256::ZERO,
+            alpha: U256::ZERO,
+            prime: U256::ZERO,
+        };
+        asserter.push_success(&json!({
+            "type": "0x0",
+            "hash": tx_hash,
+            "nonce": "0x0",
+            "blockHash": B256::ZERO,
+            "blockNumber": "0x1",
+            "transactionIndex": "0x0",
+            "from": Address::ZERO,
+            "to": Address::ZERO,
+            "value": "0x0",
+            "gasPrice": "0x1",
+            "gas": "0x1",
+            "input": Bytes::from(call.abi_encode()),
+            "v": "0x1b",
+            "r": "0x1",
+            "s": "0x1",
+        }));
+    }
+
+    fn registration(memory_hash: B256, block_no: u64, tx_hash: B256) -> Log {
+        let event = LogMemoryPageFactContinuous {
+            factHash: B256::ZERO,
+            memoryHash: memory_hash.into(),
+            prime: U256::ZERO,
+        };
+        make_log(&event, REGISTRY, block_no, tx_hash)
+    }
+
+    // queues the responses leading to the pages of FACT: the state
+    // update receipt and the SHARP verifier logs
+    fn push_fact(asserter: &Asserter, pages_hashes: Vec<B256>) -> Log {
+        let update = LogStateUpdate {
+            globalRoot: U256::from(1),
+            blockNumber: Default::default(),
+            blockHash: U256::ZERO,
+        };
+        let mut update_log = make_log(&update, CORE, 1000, UPDATE_TX);
+        update_log.log_index = Some(2);
+        let fact_event = LogStateTransitionFact {
+            stateTransitionFact: FACT,
+        };
+        let mut fact_log = make_log(&fact_event, CORE, 1000, UPDATE_TX);
+        fact_log.log_index = Some(1);
+        push_receipt(asserter, &[fact_log, update_log.clone()]);
+
+        // another SHARP user's fact in the same window
+        let other = LogMemoryPagesHashes {
+            programOutputFact: B256::repeat_byte(0xee),
+            pagesHashes: vec![B256::ZERO],
+        };
+        let ours = LogMemoryPagesHashes {
+            programOutputFact: FACT,
+            pagesHashes: pages_hashes,
+        };
+        // and a later copy of Starknet's fact, with made-up pages,
+        // by a contract other than the verifier
+        let forged = LogMemoryPagesHashes {
+            programOutputFact: FACT,
+            pagesHashes: vec![B256::ZERO, B256::repeat_byte(0xbd)],
+        };
+        asserter.push_success(&vec![
+            make_log(&ours, VERIFIER, 990, B256::ZERO),
+            make_log(&other, VERIFIER, 995, B256::ZERO),
+            make_log(&forged, Address::repeat_byte(0xbd), 997, B256::ZERO),
+        ]);
+        update_log
+    }
+
+    fn make_fetcher() -> MemoryPageFetcher {
+        MemoryPageFetcher {
+            rpc_origin: "http://localhost:8545".to_string(),
+            gps_verifier: VERIFIER,
+            memory_page_registry: Some(REGISTRY),
+            window: 100,
+            max_depth: 300,
+            retry_interval: Duration::ZERO,
+        }
+    }
+
+    #[tokio::test]
+    async fn fetch_pages() {
+        let asserter = Asserter::new();
+        let provider = ProviderBuilder::new().on_mocked_client(asserter.clone());
+        let page_a = [1, 2, 3];
+        let page_b = [4];
+        let hash_a = page_hash(&page_a);
+        let hash_b = page_hash(&page_b);
+        let tx_a = B256::repeat_byte(0xa);
+        let tx_b = B256::repeat_byte(0xb);
+        // the main page isn't fetched, and a page can repeat
+        let log = push_fact(&asserter, vec![B256::ZERO, hash_a, hash_b, hash_a]);
+
+        // registrations: the latest one of a page wins, and the search
+        // continues to earlier windows until all pages are found
+        let mut foreign = registration(hash_b, 990, B256::repeat_byte(0xdd));
+        foreign.inner.address = Address::repeat_byte(0xbd);
+        asserter.push_success(&vec![
+            registration(hash_b, 950, B256::repeat_byte(0xee)),
+            registration(hash_b, 980, tx_b),
+            foreign,
+        ]);
+        asserter.push_success(&vec![registration(hash_a, 850, tx_a)]);
+        push_page_tx(&asserter, tx_a, &page_a);
+        push_page_tx(&asserter, tx_b, &page_b);
+        push_page_tx(&asserter, tx_a, &page_a);
+
+        let seq = make_fetcher().fetch(&provider, &log).await.unwrap();
+        let expected: Vec<BigUint> = [1u64, 2, 3, 4, 1, 2, 3]
+            .into_iter()
+            .map(BigUint::from)
+            .collect();
+        assert_eq!(seq, expected);
+        assert!(asserter.read_q().is_empty());
+    }
+
+    #[tokio::test]
+    async fn missing_registration() {
+        let asserter = Asserter::new();
+        let provider = ProviderBuilder::new().on_mocked_client(asserter.clone());
+        let log = push_fact(&asserter, vec![B256::ZERO, page_hash(&[1])]);
+        for _ in 0..4 {
+            asserter.push_success(&Vec::<Log>::new());
+        }
+
+        let err = make_fetcher().fetch(&provider, &log).await.unwrap_err();
+        let Error::MissingPages { tx_hash, message } = err else {
+            panic!("unexpected error {:?}", err);
+        };
+        assert_eq!(tx_hash, UPDATE_TX);
+        assert_eq!(message, "found only 0 of 1 memory page registrations");
+        assert!(asserter.read_q().is_empty());
+    }
+
+    #[tokio::test]
+    async fn page_hash_mismatch() {
+        let asserter = Asserter::new();
+        let provider = ProviderBuilder::new().on_mocked_client(asserter.clone());
+        let hash = page_hash(&[1, 2]);
+        let tx_hash = B256::repeat_byte(0xa);
+        let log = push_fact(&asserter, vec![B256::ZERO, hash]);
+        asserter.push_success(&vec![registration(hash, 980, tx_hash)]);
+        push_page_tx(&asserter, tx_hash, &[2, 1]);
+
+        let err = make_fetcher().fetch(&provider, &log).await.unwrap_err();
+        let Error::PageIntegrity { page_hash, message } = err else {
+            panic!("unexpected error {:?}", err);
+        };
+        assert_eq!(page_hash, hash);
+        assert_eq!(
+            message,
+            format!("page registered by {} doesn't match its hash", tx_hash)
+        );
+    }
+
+    #[tokio::test]
+    async fn transport_failure() {
+        let asserter = Asserter::new();
+        let provider = ProviderBuilder::new().on_mocked_client(asserter.clone());
+        let log = push_fact(&asserter, vec![B256::ZERO, page_hash(&[1])]);
+        asserter.push_failure_msg("connection reset");
+
+        let err = make_fetcher().fetch(&provider, &log).await.unwrap_err();
+        let Error::Transport { url, message } = err else {
+            panic!("unexpected error {:?}", err);
+        };
+        assert_eq!(url, "http://localhost:8545");
+        assert!(message.starts_with("can't get logs 891-990"), "{}", message);
+
+        let fetcher = MemoryPageFetcher::new(
+            "https://eth.example.com/v2/secret-key",
+            VERIFIER,
+            Some(REGISTRY),
+        );
+        assert_eq!(fetcher.rpc_origin, "https://eth.example.com");
+    }
+
+    #[tokio::test]
+    async fn retry_page_tx() {
+        let asserter = Asserter::new();
+        let provider = ProviderBuilder::new().on_mocked_client(asserter.clone());
+        let page = [5, 6];
+        let hash = page_hash(&page);
+        let tx_hash = B256::repeat_byte(0xa);
+        let log = push_fact(&asserter, vec![B256::ZERO, hash]);
+        asserter.push_success(&vec![registration(hash, 980, tx_hash)]);
+        asserter.push_failure_msg("connection reset");
+        asserter.push_failure_msg("connection reset");
+        push_page_tx(&asserter, tx_hash, &page);
+
+        let seq = make_fetcher().fetch(&provider, &log).await.unwrap();
+        assert_eq!(seq, vec![BigUint::from(5u32), BigUint::from(6u32)]);
+        assert!(asserter.read_q().is_empty());
+    }
+}
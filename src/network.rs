@@ -0,0 +1,203 @@
+use alloy::primitives::{Address, address};
+use eyre::{WrapErr, anyhow};
+use redb::{Database, ReadableTable, TableDefinition};
+use serde::Deserialize;
+
+use std::fs;
+use std::path::Path;
+
+use crate::da_format::{FormatEntry, FormatRegistry, FormatStart, FormatVersion};
+
+const META: TableDefinition<&str, u64> = TableDefinition::new("meta");
+const CHAIN_ID_KEY: &str = "chain_id";
+
+/// Name of the file tagging the cache directory with a chain id.
+const CHAIN_ID_FILE: &str = ".chain_id";
+
+/// Parameters of a Starknet deployment settling on an Ethereum chain.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct NetworkProfile {
+    /// Ethereum chain id
+    pub chain_id: u64,
+    /// address of the Starknet core contract
+    pub core_contract: Address,
+    /// address of the SHARP verifier, logging the memory pages of
+    /// state updates published in calldata
+    pub gps_verifier: Address,
+    /// address of the memory page fact registry; when not set,
+    /// registrations by any contract are accepted (their pages are
+    /// still checked against the hashes logged by the verifier)
+    #[serde(default)]
+    pub memory_page_registry: Option<Address>,
+    /// first Ethereum block with a state update published in blobs
+    /// (the default starting point)
+    pub first_blob_block: u64,
+    /// formats of published state updates, in chronological order
+    pub da_formats: Vec<FormatEntry>,
+}
+
+impl NetworkProfile {
+    pub fn mainnet() -> Self {
+        Self {
+            chain_id: 1,
+            core_contract: address!("0xc662c410C0ECf747543f5bA90660f6ABeBD9C8c4"),
+            gps_verifier: address!("0x47312450B3Ac8b5b8e247a6bB6d523e7605bDb60"),
+            memory_page_registry: Some(address!("0xFD14567eaf9ba941cB8c8a94eEC14831ca7fD1b4")),
+            first_blob_block: 19427723,
+            // updates before v0.9 also list constructor calldata of
+            // deployed contracts (pre_v0_9), but the Starknet block of
+            // that upgrade isn't known here - parsing them needs
+            // da_formats in the configuration
+            da_formats: vec![
+                FormatEntry::new(FormatStart::FromSeqNo(0), FormatVersion::PRE_V0_11),
+                FormatEntry::new(FormatStart::FromSeqNo(28000), FormatVersion::V0_13_1),
+                // first compressed blob
+                FormatEntry::new(FormatStart::FromBlock(21282183), FormatVersion::V0_13_3),
+            ],
+        }
+    }
+
+    pub fn sepolia() -> Self {
+        Self {
+            chain_id: 11155111,
+            core_contract: address!("0xE2Bb56ee936fd6433DC0F6e7e3b8365C906AA057"),
+            gps_verifier: address!("0x07ec0D28e50322Eb0C159B9090ecF3aeA8346DFe"),
+            // not checked against an explorer yet
+            memory_page_registry: Some(address!("0xdc1534eeBF8CEEe76E31C98F5f5e0F9979476c87")),
+            // Dencun activation - unverified stand-in for the block of
+            // Starknet Sepolia's first blob (which is somewhat later)
+            first_blob_block: 5187023,
+            // Starknet Sepolia started after v0.11
+            da_formats: vec![
+                FormatEntry::new(FormatStart::FromSeqNo(0), FormatVersion::V0_13_1),
+                // unverified estimate of the v0.13.3 upgrade, not the
+                // block of the first compressed blob - check_version
+                // warns about blobs on the wrong side of it
+                FormatEntry::new(FormatStart::FromBlock(7240000), FormatVersion::V0_13_3),
+            ],
+        }
+    }
+
+    pub fn get_format_registry(&self) -> eyre::Result<FormatRegistry> {
+        FormatRegistry::new(self.da_formats.clone())
+    }
+
+    /// Tags `db` with the chain id, or checks the existing tag.
+    pub fn tag_db(&self, db: &Database) -> eyre::Result<()> {
+        let txn = db.begin_write()?;
+        {
+            let mut meta = txn.open_table(META)?;
+            let opt_old = meta.get(CHAIN_ID_KEY)?.map(|found| found.value());
+            match opt_old {
+                Some(chain_id) => self.check_chain_id(chain_id, "database")?,
+                None => {
+                    meta.insert(CHAIN_ID_KEY, self.chain_id)?;
+                }
+            }
+        }
+        txn.commit()?;
+        Ok(())
+    }
+
+    /// Tags `cache_dir` with the chain id, or checks the existing tag.
+    pub fn tag_cache_dir(&self, cache_dir: &Path) -> eyre::Result<()> {
+        let path = cache_dir.join(CHAIN_ID_FILE);
+        if path.exists() {
+            let raw = fs::read_to_string(&path)?;
+            let chain_id = str::parse::<u64>(raw.trim())
+                .with_context(|| format!("invalid chain id in {:?}", path))?;
+            self.check_chain_id(chain_id, "cache directory")
+        } else {
+            fs::write(&path, format!("{}\n", self.chain_id))?;
+            Ok(())
+        }
+    }
+
+    /// Checks that `chain_id` (read from `origin`) is the chain id of
+    /// this network.
+    pub fn check_chain_id(&self, chain_id: u64, origin: &str) -> eyre::Result<()> {
+        if chain_id == self.chain_id {
+            Ok(())
+        } else {
+            Err(anyhow!(
+                "{} belongs to chain {}, expected {}",
+                origin,
+                chain_id,
+                self.chain_id
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use num_bigint::BigUint;
+
+    use std::fs;
+
+    use super::NetworkProfile;
+    use crate::annotation::Annotator;
+    use crate::compress::Compressor;
+    use crate::da_format::{FormatVersion, check_version};
+    use crate::encode::encode_state_diff;
+    use crate::parser::StateUpdateParser;
+    use crate::test_util::{make_contract_update, make_lookup, make_state_diff, memory_db};
+
+    #[test]
+    fn tag_db() {
+        let db = memory_db();
+        let mainnet = NetworkProfile::mainnet();
+        mainnet.tag_db(&db).unwrap();
+        mainnet.tag_db(&db).unwrap();
+        let err = NetworkProfile::sepolia().tag_db(&db).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "database belongs to chain 1, expected 11155111"
+        );
+    }
+
+    #[test]
+    fn tag_cache_dir() {
+        let cache_dir = std::env::temp_dir().join(format!("chain-id-{}", std::process::id()));
+        fs::create_dir_all(&cache_dir).unwrap();
+        let sepolia = NetworkProfile::sepolia();
+        sepolia.tag_cache_dir(&cache_dir).unwrap();
+        sepolia.tag_cache_dir(&cache_dir).unwrap();
+        assert!(NetworkProfile::mainnet().tag_cache_dir(&cache_dir).is_err());
+        fs::remove_dir_all(&cache_dir).unwrap();
+    }
+
+    #[test]
+    fn sepolia_switch() {
+        let contract_update = make_contract_update(0x1234, 5, None, &[(7, 8)]);
+        let state_diff = make_state_diff(vec![contract_update], Vec::new());
+        let registry = NetworkProfile::sepolia().get_format_registry().unwrap();
+        let lookup = make_lookup();
+        for (block_no, version) in [
+            (7239999, FormatVersion::V0_13_1),
+            (7240000, FormatVersion::V0_13_3),
+            (8000000, FormatVersion::V0_13_3),
+        ] {
+            let format = version.get_format();
+            let mut seq = encode_state_diff(&state_diff, format).unwrap();
+            if format.is_compressed() {
+                seq = Compressor::compress(&seq).unwrap();
+            }
+            // blob padding
+            seq.resize(seq.len() + 10, BigUint::ZERO);
+
+            // the registry decides, and the data agrees
+            assert_eq!(registry.get_version(block_no, None).unwrap(), version);
+            assert_eq!(check_version(version, &seq), None);
+            let (seq, _) = format.decompress(&seq).unwrap();
+            let parsed = StateUpdateParser::parse(
+                seq.into_iter(),
+                format,
+                lookup.clone(),
+                Annotator::none(),
+            )
+            .unwrap();
+            assert_eq!(parsed.contract_updates, state_diff.contract_updates);
+        }
+    }
+}
@@ -2,7 +2,9 @@ use eyre::anyhow;
 use num_bigint::BigUint;
 use num_traits::{ToPrimitive, Zero};
 
-use std::ops::{BitAnd, Shr};
+use std::ops::{BitAnd, Shl, Shr};
+
+use crate::felt::Felt;
 
 pub struct PackConst {
     pub top_mask: BigUint,
@@ -16,8 +18,8 @@ pub struct PackConst {
     pub update_count_shift: usize,
     pub short_update_count_mask: BigUint,
     pub short_flag_mask: BigUint,
-    pub one: BigUint,
-    pub two: BigUint,
+    pub one: Felt,
+    pub two: Felt,
 }
 
 impl PackConst {
@@ -59,6 +61,97 @@ impl PackConst {
             update_count.to_u64().expect("bitmasked"),
         ))
     }
+
+    /// Inverse of `unpack_contract_update`, preferring the short
+    /// format (where there is one).
+    pub fn pack_contract_update(
+        &self,
+        class_flag: bool,
+        nonce: u64,
+        update_count: u64,
+    ) -> eyre::Result<BigUint> {
+        let count = BigUint::from(update_count).shl(self.update_count_shift);
+        let short_flag = !self.short_flag_mask.is_zero()
+            && (count.clone().bitand(&self.short_update_count_mask) == count);
+        let (nonce_shift, update_count_mask) = if short_flag {
+            (self.short_nonce_shift, &self.short_update_count_mask)
+        } else {
+            (self.nonce_shift, &self.update_count_mask)
+        };
+        if count.clone().bitand(update_count_mask) != count {
+            return Err(anyhow!("Too many storage updates: {}", update_count));
+        }
+
+        let mut packed = count | BigUint::from(nonce).shl(nonce_shift);
+        if short_flag {
+            packed |= &self.short_flag_mask;
+        }
+        if class_flag {
+            if self.class_flag_mask.is_zero() {
+                return Err(anyhow!("Class flag not supported"));
+            }
+            packed |= &self.class_flag_mask;
+        }
+
+        Ok(packed)
+    }
+}
+
+pub mod pre_v0_11 {
+    use num_bigint::{BigUint, ToBigUint};
+
+    use std::ops::Shl;
+
+    use super::PackConst;
+    use crate::felt::Felt;
+
+    // same as v0_13_1 (which was in fact introduced by v0.11), just
+    // without the class flag
+    pub fn make_pack_const() -> PackConst {
+        let one: BigUint = 1u32.to_biguint().unwrap();
+        let top_mask_low: BigUint = one.clone().shl(128) - one.clone();
+        let nonce_mask_low: BigUint = one.clone().shl(64) - one.clone();
+        let update_count_mask = nonce_mask_low.clone();
+        PackConst {
+            top_mask: top_mask_low.shl(128),
+            short_top_mask: BigUint::ZERO,  // not used
+            class_flag_mask: BigUint::ZERO, // never matches
+            nonce_mask: nonce_mask_low.shl(64),
+            short_nonce_mask: BigUint::ZERO, // not used
+            nonce_shift: 64,
+            short_nonce_shift: 0, // not used
+            update_count_mask,
+            update_count_shift: 0,
+            short_update_count_mask: BigUint::ZERO, // not used
+            short_flag_mask: BigUint::ZERO,         // never matches
+            one: Felt::from(1),
+            two: Felt::from(2),
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use num_bigint::{BigUint, ToBigUint};
+
+        use std::ops::Shl;
+
+        use super::make_pack_const;
+
+        #[test]
+        fn no_class_flag() {
+            let pack_const = make_pack_const();
+            let one: BigUint = 1u32.to_biguint().unwrap();
+            let u = one.clone().shl(64) + one.clone().shl(1);
+            let (class_flag_bit, nonce, update_count) =
+                pack_const.unpack_contract_update(&u).unwrap();
+            assert!(!class_flag_bit);
+            assert_eq!(nonce, 1);
+            assert_eq!(update_count, 2);
+
+            let flagged = u + one.shl(128);
+            assert!(pack_const.unpack_contract_update(&flagged).is_err());
+        }
+    }
 }
 
 pub mod v0_13_1 {
@@ -67,10 +160,10 @@ pub mod v0_13_1 {
     use std::ops::Shl;
 
     use super::PackConst;
+    use crate::felt::Felt;
 
     pub fn make_pack_const() -> PackConst {
         let one: BigUint = 1u32.to_biguint().unwrap();
-        let two: BigUint = 2u32.to_biguint().unwrap();
         let top_mask_low: BigUint = one.clone().shl(127) - one.clone();
         let class_flag_mask = one.clone().shl(128);
         let nonce_mask_low: BigUint = one.clone().shl(64) - one.clone();
@@ -87,8 +180,8 @@ pub mod v0_13_1 {
             update_count_shift: 0,
             short_update_count_mask: BigUint::ZERO, // not used
             short_flag_mask: BigUint::ZERO,         // never matches
-            one,
-            two,
+            one: Felt::from(1),
+            two: Felt::from(2),
         }
     }
 
@@ -108,6 +201,7 @@ pub mod v0_13_1 {
             assert!(!class_flag_bit);
             assert_eq!(nonce, 1);
             assert_eq!(update_count, 1);
+            assert_eq!(pack_const.pack_contract_update(false, 1, 1).unwrap(), u);
         }
     }
 }
@@ -118,6 +212,7 @@ pub mod v0_13_3 {
     use std::ops::Shl;
 
     use super::PackConst;
+    use crate::felt::Felt;
 
     pub fn make_pack_const() -> PackConst {
         let one: BigUint = 1u32.to_biguint().unwrap();
@@ -144,9 +239,9 @@ pub mod v0_13_3 {
             // class hash (while the lowest bit is clear & number of
             // updates < 256)
             class_flag_mask: one.clone(),
-            short_flag_mask: two.clone(),
-            one,
-            two,
+            short_flag_mask: two,
+            one: Felt::from(1),
+            two: Felt::from(2),
         }
     }
 
@@ -166,6 +261,10 @@ pub mod v0_13_3 {
             assert_eq!(expected.0, class_flag);
             assert_eq!(expected.1, nonce);
             assert_eq!(expected.2, update_count);
+            let packed = pack_const
+                .pack_contract_update(class_flag, nonce, update_count)
+                .unwrap();
+            assert_eq!(packed, u);
         }
     }
 }
@@ -1,80 +1,195 @@
 use eyre::{ContextCompat, WrapErr, anyhow};
 use num_bigint::BigUint;
-use num_traits::{ToPrimitive, Zero};
+use num_traits::Zero;
+
+use serde::Serialize;
+use serde_json::json;
 
 use std::cell::RefCell;
-use std::io::Write;
+use std::fmt;
 use std::rc::Rc;
 
+use crate::annotation::{Annotation, Annotator, Role};
 use crate::blob_util::parse_usize;
+use crate::da_format::{DaFormat, DeploymentLayout, Sections};
+use crate::diag::{FailurePoint, Stage};
+use crate::error::{Error, Result};
+use crate::felt::Felt;
 use crate::lookup::Lookup;
-use crate::packing::PackConst;
 use crate::state_diff::{BlockRange, ClassDeclaration, ContractUpdate, StateDiff, StorageUpdate};
 
-enum LookupUsageState {
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub enum LookupUsageState {
     Off,
     One,
     Expand,
     On,
 }
 
-pub struct StateUpdateParser<I> {
+/// Parsing of state update sections, driven by `DaFormat`.
+pub trait SectionParser {
+    /// Returns the next word of the sequence, for sections not
+    /// parsed by the other methods.
+    fn next_word(&mut self, missing: &'static str) -> eyre::Result<BigUint>;
+
+    /// Returns the deployed contracts (each laid out as `layout`) as
+    /// contract updates without nonce or storage updates.
+    fn parse_deployments(&mut self, layout: DeploymentLayout) -> eyre::Result<Vec<ContractUpdate>>;
+
+    fn parse_contract_updates(&mut self) -> eyre::Result<Vec<ContractUpdate>>;
+
+    fn parse_class_declarations(&mut self) -> eyre::Result<Vec<ClassDeclaration>>;
+}
+
+pub struct StateUpdateParser<'a, I> {
     current: I,
     lookup_usage_state: LookupUsageState,
     lookup: Rc<RefCell<Lookup>>,
-    pack_const: PackConst,
-    anno_dump: Box<dyn Write>,
+    format: &'a dyn DaFormat,
+    annotator: Annotator,
     range: BlockRange,
+    // number of words read
+    offset: usize,
+    // set when the sequence ended prematurely
+    exhausted: bool,
+    section: &'static str,
+    contract_index: Option<usize>,
+    // items of the section that failed to parse
+    partial: Sections,
 }
 
-impl<I> StateUpdateParser<I>
+impl<'a, I> StateUpdateParser<'a, I>
 where
     I: Iterator<Item = BigUint>,
 {
     pub fn parse(
         iter: I,
-        unpacker: PackConst,
+        format: &'a dyn DaFormat,
         lookup: Rc<RefCell<Lookup>>,
-        anno_dump: Box<dyn Write>,
-    ) -> eyre::Result<StateDiff> {
+        annotator: Annotator,
+    ) -> Result<StateDiff> {
         let mut parser = Self {
             current: iter,
             lookup_usage_state: LookupUsageState::Off,
             lookup,
-            pack_const: unpacker,
-            anno_dump,
+            format,
+            annotator,
             range: Default::default(),
+            offset: 0,
+            exhausted: false,
+            section: "",
+            contract_index: None,
+            partial: Sections::default(),
         };
-        let contract_updates = parser.parse_contract_updates()?;
-        let class_declarations = parser.parse_class_declarations()?;
-        let n = parser.check_zero_tail()?;
-        Ok(StateDiff {
-            contract_updates,
-            class_declarations,
-            range: parser.range,
-            tail_size: n,
-        })
+        let mut sections = Sections::default();
+        let res = format
+            .parse_sections(&mut parser, &mut sections)
+            .and_then(|()| parser.check_zero_tail());
+        match res {
+            Ok(n) => Ok(StateDiff {
+                contract_updates: sections.contract_updates,
+                class_declarations: sections.class_declarations,
+                range: parser.range,
+                tail_size: n,
+            }),
+            Err(err) => {
+                let point = Box::new(parser.make_failure_point(sections));
+                let message = format!("{:#}", err);
+                let cause = err.downcast::<Error>().ok().map(Box::new);
+                Err(Error::Parse {
+                    point,
+                    message,
+                    cause,
+                })
+            }
+        }
     }
 
-    fn parse_contract_updates(&mut self) -> eyre::Result<Vec<ContractUpdate>> {
-        let raw_num_contracts: BigUint = self
-            .current
-            .next()
-            .context("Missing number of contract updates")?;
-        writeln!(self.anno_dump, "{}", raw_num_contracts)?;
-        let num_contracts =
-            parse_usize(&raw_num_contracts).context("Parsing number of contract updates")?;
-        (0..num_contracts)
-            .map(|i| {
-                self.parse_contract_update()
-                    .with_context(|| format!("contract {} of {}", i, num_contracts))
-            })
-            .collect()
+    // `sections` are the ones parsed before the failure
+    fn make_failure_point(&mut self, mut sections: Sections) -> FailurePoint {
+        let index = if self.exhausted || self.offset == 0 {
+            self.offset
+        } else {
+            self.offset - 1
+        };
+        let state = json!({
+            "section": self.section,
+            "contract": self.contract_index,
+            "lookup_state": self.lookup_usage_state,
+            "min_seq_no": self.range.min_seq_no,
+            "max_seq_no": self.range.max_seq_no,
+        });
+        let partial = std::mem::take(&mut self.partial);
+        sections.contract_updates.extend(partial.contract_updates);
+        sections
+            .class_declarations
+            .extend(partial.class_declarations);
+        let state_diff = StateDiff {
+            contract_updates: sections.contract_updates,
+            class_declarations: sections.class_declarations,
+            range: Default::default(),
+            tail_size: 0,
+        };
+        FailurePoint {
+            stage: Stage::Parsing,
+            index,
+            state,
+            partial: state_diff.to_json_parsed(),
+        }
+    }
+
+    // `left` is the number of words left in the deployments section;
+    // returns the number of words the deployment took
+    fn parse_deployment(
+        &mut self,
+        layout: DeploymentLayout,
+        left: usize,
+    ) -> eyre::Result<(Felt, Felt, usize)> {
+        let address = self.next_felt("Missing deployed contract address")?;
+        self.annotate(
+            Role::DeployedAddress,
+            &address,
+            Some(address),
+            Some(format_args!("a: {:#x}", address)),
+        )?;
+        let class_hash = self.next_felt("Missing deployed class hash")?;
+        self.annotate(
+            Role::DeployedClassHash,
+            &class_hash,
+            None,
+            Some(format_args!("h: {:#x}", class_hash)),
+        )?;
+        if layout == DeploymentLayout::Plain {
+            return Ok((address, class_hash, 2));
+        }
+
+        if left < 3 {
+            return Err(anyhow!("Missing constructor calldata length"));
+        }
+
+        let raw_len = self.next_raw("Missing constructor calldata length")?;
+        self.annotate(
+            Role::ConstructorCalldataLength,
+            &raw_len,
+            None,
+            Some(format_args!("calldata: {}", raw_len)),
+        )?;
+        let len = parse_usize(&raw_len).context("Parsing constructor calldata length")?;
+        if len > left - 3 {
+            return Err(anyhow!("Constructor calldata beyond deployments"));
+        }
+
+        // not part of the state diff
+        for _ in 0..len {
+            let arg = self.next_raw("Missing constructor argument")?;
+            self.annotate(Role::ConstructorArgument, &arg, None, None)?;
+        }
+
+        Ok((address, class_hash, 3 + len))
     }
 
     fn parse_contract_update(&mut self) -> eyre::Result<ContractUpdate> {
-        let address = self.current.next().context("Missing contract address")?;
-        writeln!(self.anno_dump, "a: {:#x}", address)?;
+        let address = self.next_felt("Missing contract address")?;
         if address.is_zero() {
             // majin-blob has a break on this condition, but hopefully
             // it doesn't happen on correct data...
@@ -83,17 +198,18 @@ where
 
         let addr = match self.lookup_usage_state {
             LookupUsageState::Off => {
-                if address == self.pack_const.one {
+                if address == self.format.pack_const().one {
                     self.lookup_usage_state = LookupUsageState::One;
                 }
                 address
             }
             LookupUsageState::One => {
-                if address == self.pack_const.two {
+                if address == self.format.pack_const().two {
                     // switching to stateful compression
                     self.lookup_usage_state = LookupUsageState::Expand;
                     address
-                } else if self.lookup.borrow().is_on()? && (address > self.pack_const.two) {
+                } else if self.lookup.borrow().is_on()? && (address > self.format.pack_const().two)
+                {
                     // even if a statefully-compressed block didn't
                     // change 0x2's storage (and therefore its state
                     // diff doesn't contain the 0x2 address), it
@@ -120,20 +236,33 @@ where
                 lookup.get(index)?
             }
         };
+        self.annotate(
+            Role::Address,
+            &address,
+            Some(addr),
+            Some(format_args!("a: {:#x}", address)),
+        )?;
 
-        let packed = self
-            .current
-            .next()
-            .context("Missing contract packed data")?;
-        let (class_flag, nonce, update_count) = self.pack_const.unpack_contract_update(&packed)?;
-        writeln!(
-            self.anno_dump,
-            "{:#b} -> n: {}, c: {}, f: {}",
-            packed, nonce, update_count, class_flag as i32
+        let packed = self.next_raw("Missing contract packed data")?;
+        let (class_flag, nonce, update_count) =
+            self.format.pack_const().unpack_contract_update(&packed)?;
+        self.annotate(
+            Role::Packed,
+            &packed,
+            None,
+            Some(format_args!(
+                "{:#b} -> n: {}, c: {}, f: {}",
+                packed, nonce, update_count, class_flag as i32
+            )),
         )?;
         let new_class_hash = if class_flag {
-            let hash = self.current.next().context("Missing new class hash")?;
-            writeln!(self.anno_dump, "h: {:#x}", hash)?;
+            let hash = self.next_felt("Missing new class hash")?;
+            self.annotate(
+                Role::ClassHash,
+                &hash,
+                None,
+                Some(format_args!("h: {:#x}", hash)),
+            )?;
             Some(hash)
         } else {
             None
@@ -145,13 +274,10 @@ where
                     .with_context(|| format!("storage update {} of {}", i, update_count))
             })
             .collect::<eyre::Result<Vec<_>>>()?;
-        match self.lookup_usage_state {
-            LookupUsageState::Expand => {
-                let mut lookup = self.lookup.borrow_mut();
-                lookup.expand()?;
-                self.lookup_usage_state = LookupUsageState::On;
-            }
-            _ => (),
+        if let LookupUsageState::Expand = self.lookup_usage_state {
+            let mut lookup = self.lookup.borrow_mut();
+            lookup.expand()?;
+            self.lookup_usage_state = LookupUsageState::On;
         }
 
         Ok(ContractUpdate {
@@ -159,16 +285,38 @@ where
             nonce,
             new_class_hash,
             storage_updates,
+            class_change: None,
         })
     }
 
     fn parse_storage_update(&mut self) -> eyre::Result<StorageUpdate> {
-        let mut key = self.current.next().context("Missing storage address")?;
-        writeln!(self.anno_dump, "k: {:#x}", key)?;
-        let value = self.current.next().context("Missing storage value")?;
-        writeln!(self.anno_dump, "v: {:#x}", value)?;
+        let raw_key = self.next_felt("Missing storage address")?;
+        let key = if let LookupUsageState::On = self.lookup_usage_state {
+            let lookup = self.lookup.borrow();
+            if raw_key >= lookup.global_start_index {
+                let index = raw_key.to_u64().context("Casting compressed key")?;
+                lookup.get(index)?
+            } else {
+                raw_key
+            }
+        } else {
+            raw_key
+        };
+        self.annotate(
+            Role::StorageKey,
+            &raw_key,
+            Some(key),
+            Some(format_args!("k: {:#x}", raw_key)),
+        )?;
+        let value = self.next_felt("Missing storage value")?;
+        self.annotate(
+            Role::StorageValue,
+            &value,
+            None,
+            Some(format_args!("v: {:#x}", value)),
+        )?;
         match self.lookup_usage_state {
-            LookupUsageState::Off => (),
+            LookupUsageState::Off | LookupUsageState::On => (),
             LookupUsageState::One => {
                 let seq_no = key.to_u64().context("Casting 0x1 key")?;
                 if let Some(old) = self.range.min_seq_no {
@@ -196,51 +344,172 @@ where
                     lookup.record(index, &key)?;
                 }
             }
-            LookupUsageState::On => {
-                let lookup = self.lookup.borrow();
-                if key >= lookup.global_start_index {
-                    let index = key.to_u64().context("Casting compressed key")?;
-                    key = lookup.get(index)?;
-                }
-            }
         }
 
         Ok(StorageUpdate { key, value })
     }
 
-    fn parse_class_declarations(&mut self) -> eyre::Result<Vec<ClassDeclaration>> {
-        let raw_num_decls: BigUint = self
-            .current
-            .next()
-            .context("Missing number of class declarations")?;
-        writeln!(self.anno_dump, "{}", raw_num_decls)?;
-        let num_decls =
-            parse_usize(&raw_num_decls).context("Parsing number of class declarations")?;
-        (0..num_decls)
-            .map(|i| {
-                self.parse_class_declaration()
-                    .with_context(|| format!("declaration {} of {}", i, num_decls))
-            })
-            .collect()
-    }
-
     fn parse_class_declaration(&mut self) -> eyre::Result<ClassDeclaration> {
-        let class_hash = self.current.next().context("Missing class hash")?;
-        let compiled_class_hash = self.current.next().context("Missing compiled class hash")?;
+        let class_hash = self.next_felt("Missing class hash")?;
+        self.annotate(Role::DeclaredClassHash, &class_hash, None, None)?;
+        let compiled_class_hash = self.next_felt("Missing compiled class hash")?;
+        self.annotate(Role::CompiledClassHash, &compiled_class_hash, None, None)?;
         Ok(ClassDeclaration {
             class_hash,
             compiled_class_hash,
         })
     }
 
+    fn next_raw(&mut self, missing: &'static str) -> eyre::Result<BigUint> {
+        let Some(el) = self.current.next() else {
+            self.exhausted = true;
+            return Err(anyhow!(missing));
+        };
+        self.offset += 1;
+        Ok(el)
+    }
+
+    fn next_felt(&mut self, missing: &'static str) -> eyre::Result<Felt> {
+        let el = self.next_raw(missing)?;
+        Felt::try_from(&el)
+    }
+
+    // annotates the last word read
+    fn annotate(
+        &mut self,
+        role: Role,
+        raw: &dyn fmt::LowerHex,
+        resolved: Option<Felt>,
+        text: Option<fmt::Arguments>,
+    ) -> eyre::Result<()> {
+        let annotation = Annotation {
+            offset: self.offset - 1,
+            raw,
+            role,
+            contract: self.contract_index,
+            lookup_state: self.lookup_usage_state,
+            resolved,
+        };
+        self.annotator.annotate(&annotation, text)
+    }
+
     fn check_zero_tail(&mut self) -> eyre::Result<usize> {
+        self.section = "tail";
         let mut n = 0;
         while let Some(el) = self.current.next() {
+            self.offset += 1;
             n += 1;
             if !el.is_zero() {
                 return Err(anyhow!("Extra tail"));
             }
+            self.annotate(Role::Padding, &el, None, None)?;
         }
         Ok(n)
     }
 }
+
+impl<I> SectionParser for StateUpdateParser<'_, I>
+where
+    I: Iterator<Item = BigUint>,
+{
+    fn next_word(&mut self, missing: &'static str) -> eyre::Result<BigUint> {
+        self.section = "other";
+        let word = self.next_raw(missing)?;
+        self.annotate(Role::Other, &word, None, Some(format_args!("{:#x}", word)))?;
+        Ok(word)
+    }
+
+    fn parse_deployments(&mut self, layout: DeploymentLayout) -> eyre::Result<Vec<ContractUpdate>> {
+        self.section = "deployments";
+        let raw_len = self.next_raw("Missing length of deployments")?;
+        self.annotate(
+            Role::DeploymentsLength,
+            &raw_len,
+            None,
+            Some(format_args!("{}", raw_len)),
+        )?;
+        // section length is in words, not deployments
+        let len = parse_usize(&raw_len).context("Parsing length of deployments")?;
+        if (layout == DeploymentLayout::Plain) && (len % 2 != 0) {
+            return Err(anyhow!("Odd length of deployments"));
+        }
+
+        let mut deployments = Vec::new();
+        let mut left = len;
+        while left > 0 {
+            match self.parse_deployment(layout, left) {
+                Ok((address, class_hash, n)) => {
+                    left -= n;
+                    deployments.push(ContractUpdate {
+                        address,
+                        nonce: 0,
+                        new_class_hash: Some(class_hash),
+                        storage_updates: Vec::new(),
+                        class_change: None,
+                    });
+                }
+                Err(err) => {
+                    let i = deployments.len();
+                    self.partial.contract_updates = deployments;
+                    return Err(
+                        err.wrap_err(format!("deployment {} ({} of {} words left)", i, left, len))
+                    );
+                }
+            }
+        }
+
+        Ok(deployments)
+    }
+
+    fn parse_contract_updates(&mut self) -> eyre::Result<Vec<ContractUpdate>> {
+        self.section = "contract updates";
+        let raw_num_contracts = self.next_raw("Missing number of contract updates")?;
+        self.annotate(
+            Role::ContractCount,
+            &raw_num_contracts,
+            None,
+            Some(format_args!("{}", raw_num_contracts)),
+        )?;
+        let num_contracts =
+            parse_usize(&raw_num_contracts).context("Parsing number of contract updates")?;
+        let mut contract_updates = Vec::new();
+        for i in 0..num_contracts {
+            self.contract_index = Some(i);
+            match self.parse_contract_update() {
+                Ok(contract_update) => contract_updates.push(contract_update),
+                Err(err) => {
+                    self.partial.contract_updates = contract_updates;
+                    return Err(err.wrap_err(format!("contract {} of {}", i, num_contracts)));
+                }
+            }
+        }
+
+        self.contract_index = None;
+        Ok(contract_updates)
+    }
+
+    fn parse_class_declarations(&mut self) -> eyre::Result<Vec<ClassDeclaration>> {
+        self.section = "class declarations";
+        let raw_num_decls = self.next_raw("Missing number of class declarations")?;
+        self.annotate(
+            Role::DeclarationCount,
+            &raw_num_decls,
+            None,
+            Some(format_args!("{}", raw_num_decls)),
+        )?;
+        let num_decls =
+            parse_usize(&raw_num_decls).context("Parsing number of class declarations")?;
+        let mut class_declarations = Vec::new();
+        for i in 0..num_decls {
+            match self.parse_class_declaration() {
+                Ok(class_declaration) => class_declarations.push(class_declaration),
+                Err(err) => {
+                    self.partial.class_declarations = class_declarations;
+                    return Err(err.wrap_err(format!("declaration {} of {}", i, num_decls)));
+                }
+            }
+        }
+
+        Ok(class_declarations)
+    }
+}
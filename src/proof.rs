@@ -0,0 +1,139 @@
+use serde_json::json;
+
+use crate::commitment::{StateCommitment, contract_state_hash};
+use crate::error::{Error, Result};
+use crate::felt::Felt;
+use crate::trie::{Node, TrieHash, verify_proof};
+
+/// Class hash, nonce and storage root of a contract - the preimage of
+/// its leaf in the contracts trie.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ContractState {
+    pub class_hash: Felt,
+    pub nonce: u64,
+    pub storage_root: Felt,
+}
+
+/// Merkle proof of a storage value (or of its absence), like a
+/// response of the `starknet_getStorageProof` JSON-RPC call for a
+/// single contract and key. Its root of trust is the global state
+/// root, published by `LogStateUpdate` events on Ethereum.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StorageProof {
+    pub address: Felt,
+    pub key: Felt,
+    /// roots of the global tries, combining into the global root
+    pub commitment: StateCommitment,
+    /// inner nodes of the contracts trie, from its root towards the
+    /// contract's leaf
+    pub contract_proof: Vec<Node>,
+    /// state of the contract, if it's in the contracts trie
+    pub contract_state: Option<ContractState>,
+    /// inner nodes of the contract's storage trie, from its root
+    /// towards the key's leaf
+    pub storage_proof: Vec<Node>,
+}
+
+impl StorageProof {
+    /// Checks the proof against `global_root` and returns the proven
+    /// storage value (zero when the key, or the whole contract, isn't
+    /// in the state). A successful check also proves
+    /// `contract_state`.
+    pub fn verify(&self, global_root: &Felt) -> Result<Felt> {
+        let computed = self.commitment.global_root();
+        if computed != *global_root {
+            let message = format!("tries have global root {:#x}", computed);
+            return Err(Error::InvalidProof(message));
+        }
+
+        let leaf = verify_proof(
+            TrieHash::Pedersen,
+            &self.commitment.contracts_root,
+            &self.address,
+            &self.contract_proof,
+        )?;
+        let storage_root = match (leaf.is_zero(), &self.contract_state) {
+            (true, None) => Felt::ZERO,
+            (false, Some(state)) => {
+                let hash = contract_state_hash(&state.class_hash, &state.storage_root, state.nonce);
+                if hash != leaf {
+                    let message = format!("contract state doesn't hash to leaf {:#x}", leaf);
+                    return Err(Error::InvalidProof(message));
+                }
+
+                state.storage_root
+            }
+            (true, Some(_)) => {
+                let message = format!("contract {:#x} not in the state", self.address);
+                return Err(Error::InvalidProof(message));
+            }
+            (false, None) => {
+                return Err(Error::InvalidProof("contract state missing".to_string()));
+            }
+        };
+        verify_proof(
+            TrieHash::Pedersen,
+            &storage_root,
+            &self.key,
+            &self.storage_proof,
+        )
+    }
+
+    /// Converts the proof to the result format of
+    /// `starknet_getStorageProof`. `block_hash` is the hash of the
+    /// Starknet block the proof is for, as published with the global
+    /// root by `LogStateUpdate`.
+    pub fn to_rpc_storage_proof(&self, block_hash: &Felt) -> serde_json::Value {
+        let contract_leaves_data: Vec<serde_json::Value> = self
+            .contract_state
+            .iter()
+            .map(|state| {
+                json!({
+                    "class_hash": state.class_hash,
+                    "nonce": Felt::from(state.nonce),
+                    "storage_root": state.storage_root,
+                })
+            })
+            .collect();
+        json!({
+            "classes_proof": [],
+            "contracts_proof": {
+                "nodes": to_rpc_nodes(&self.contract_proof, TrieHash::Pedersen),
+                "contract_leaves_data": contract_leaves_data,
+            },
+            "contracts_storage_proofs": [
+                to_rpc_nodes(&self.storage_proof, TrieHash::Pedersen),
+            ],
+            "global_roots": {
+                "contracts_tree_root": self.commitment.contracts_root,
+                "classes_tree_root": self.commitment.classes_root,
+                "block_hash": block_hash,
+            },
+        })
+    }
+}
+
+// makes a NODE_HASH_TO_NODE_MAPPING
+fn to_rpc_nodes(nodes: &[Node], hash: TrieHash) -> serde_json::Value {
+    let items: Vec<serde_json::Value> = nodes
+        .iter()
+        .map(|node| {
+            let rpc_node = match node {
+                Node::Binary { left, right } => json!({
+                    "left": left,
+                    "right": right,
+                }),
+                Node::Edge { child, path, len } => json!({
+                    "path": path,
+                    "length": len,
+                    "child": child,
+                }),
+            };
+            json!({
+                "node_hash": node.hash(hash),
+                "node": rpc_node,
+            })
+        })
+        .collect();
+    serde_json::Value::Array(items)
+}
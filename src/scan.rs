@@ -0,0 +1,324 @@
+use alloy::transports::{RpcError, TransportErrorKind};
+use eyre::anyhow;
+
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Upper bound of the `eth_getLogs` block range - beyond it, most
+/// providers reject the call anyway.
+pub const MAX_BLOCK_COUNT: u64 = 10_000;
+/// Number of logs returned by a single call above which the range is
+/// shrunk for the next call.
+const MAX_LOGS_PER_CALL: usize = 100;
+/// Number of consecutive transient failures of a call after which
+/// it's given up.
+const MAX_RETRIES: u32 = 5;
+/// Delay before the first retry of a call; doubled by each following
+/// one.
+pub const RETRY_BASE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Fragments of provider error messages saying that the range (or
+/// its result) is too large. There's no standard error code for that.
+const RANGE_ERROR_HINTS: [&str; 9] = [
+    "block range",
+    "range too",
+    "range is too",
+    "more than",
+    "response size",
+    "result size",
+    "too many results",
+    "limited to",
+    "limit exceeded",
+];
+/// Fragments of rate limit messages, which may otherwise look like
+/// range errors.
+const RATE_LIMIT_HINTS: [&str; 3] = ["rate", "too many requests", "quota"];
+
+/// How deep a block must be to have its state updates processed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Confirmation {
+    /// number of blocks on top of the processed one
+    Depth(u64),
+    /// the block must be finalized by the beacon chain
+    Finalized,
+}
+
+impl Confirmation {
+    /// Returns the newest block that may be processed, given the
+    /// newest block of the chain (or, for `Finalized`, the newest
+    /// finalized block).
+    pub fn apply_depth(&self, latest: u64) -> u64 {
+        match self {
+            Confirmation::Depth(n) => latest.saturating_sub(*n),
+            Confirmation::Finalized => latest,
+        }
+    }
+}
+
+impl FromStr for Confirmation {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "finalized" {
+            Ok(Confirmation::Finalized)
+        } else {
+            let n = str::parse::<u64>(s)
+                .map_err(|_| anyhow!("expected block count or \"finalized\", got {}", s))?;
+            Ok(Confirmation::Depth(n))
+        }
+    }
+}
+
+/// Tells whether `e`, returned by `eth_getLogs`, means that the block
+/// range or its result is too large - as opposed to e.g. a timeout or
+/// rate limit, after which the same range should be retried.
+pub fn is_range_error(e: &eyre::Report) -> bool {
+    let message = match e.downcast_ref::<RpcError<TransportErrorKind>>() {
+        Some(RpcError::ErrorResp(payload)) => payload.message.as_ref(),
+        Some(RpcError::Transport(TransportErrorKind::HttpError(http)))
+            if !http.is_rate_limit_err() && (http.status < 500) =>
+        {
+            http.body.as_str()
+        }
+        _ => return false,
+    };
+    let message = message.to_lowercase();
+    !RATE_LIMIT_HINTS.iter().any(|hint| message.contains(hint))
+        && RANGE_ERROR_HINTS.iter().any(|hint| message.contains(hint))
+}
+
+/// Exponential backoff between retries of a failing RPC call.
+pub struct Backoff {
+    base_interval: Duration,
+    /// consecutive failures
+    failures: u32,
+}
+
+impl Backoff {
+    pub fn new(base_interval: Duration) -> Self {
+        Self {
+            base_interval,
+            failures: 0,
+        }
+    }
+
+    /// Registers a transient failure. Returns the delay before the
+    /// retry, or `None` after too many consecutive failures.
+    pub fn retry(&mut self) -> Option<Duration> {
+        if self.failures == MAX_RETRIES {
+            return None;
+        }
+
+        let delay = self.base_interval * 2u32.pow(self.failures);
+        self.failures += 1;
+        Some(delay)
+    }
+
+    pub fn reset(&mut self) {
+        self.failures = 0;
+    }
+}
+
+/// Tracks the block range for consecutive `eth_getLogs` calls: the
+/// range grows while there are no logs and shrinks when the provider
+/// rejects it or it returns too many logs. Other failures are retried
+/// with the same range.
+pub struct RangeScanner {
+    next_block: u64,
+    block_count: u64,
+    max_block_count: u64,
+    /// failures of the current range
+    backoff: Backoff,
+}
+
+impl RangeScanner {
+    pub fn new(from_block: u64, block_count: u64, max_block_count: u64) -> Self {
+        let max_block_count = max_block_count.max(1);
+        Self {
+            next_block: from_block,
+            block_count: block_count.clamp(1, max_block_count),
+            max_block_count,
+            backoff: Backoff::new(RETRY_BASE_INTERVAL),
+        }
+    }
+
+    pub fn next_block(&self) -> u64 {
+        self.next_block
+    }
+
+    /// Returns the next range to check, given the current chain
+    /// head, or `None` when the whole chain has been checked.
+    pub fn next_range(&self, head: u64) -> Option<(u64, u64)> {
+        if self.next_block > head {
+            return None;
+        }
+
+        let to_block = (self.next_block + self.block_count - 1).min(head);
+        Some((self.next_block, to_block))
+    }
+
+    /// Moves past a successfully-checked range.
+    pub fn advance(&mut self, to_block: u64, log_count: usize) {
+        self.next_block = to_block + 1;
+        self.backoff.reset();
+        if log_count == 0 {
+            self.block_count = (self.block_count * 2).min(self.max_block_count);
+        } else if log_count > MAX_LOGS_PER_CALL {
+            self.shrink();
+        }
+    }
+
+    /// Moves back to `block_no` (if not there already), so that it's
+    /// checked again.
+    pub fn rewind(&mut self, block_no: u64) {
+        self.next_block = self.next_block.min(block_no);
+    }
+
+    /// Shrinks the range after the provider rejected it as too large.
+    /// Returns false when the range can't shrink any further.
+    pub fn reject(&mut self) -> bool {
+        if self.block_count == 1 {
+            false
+        } else {
+            self.backoff.reset();
+            self.shrink();
+            true
+        }
+    }
+
+    /// Registers a transient failure of the current range, which
+    /// should be retried as is. Returns the delay before the retry, or
+    /// `None` after too many consecutive failures.
+    pub fn retry(&mut self) -> Option<Duration> {
+        self.backoff.retry()
+    }
+
+    fn shrink(&mut self) {
+        self.block_count = (self.block_count / 2).max(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloy::transports::{HttpError, RpcError, TransportErrorKind};
+    use eyre::WrapErr;
+    use serde_json::json;
+
+    use std::time::Duration;
+
+    use super::{Confirmation, MAX_LOGS_PER_CALL, MAX_RETRIES, RangeScanner, is_range_error};
+
+    fn node_error(code: i64, message: &str) -> eyre::Report {
+        let payload = serde_json::from_value(json!({"code": code, "message": message})).unwrap();
+        let res: Result<(), _> = Err(RpcError::<TransportErrorKind>::ErrorResp(payload));
+        res.context("can't get logs").unwrap_err()
+    }
+
+    fn http_error(status: u16, body: &str) -> eyre::Report {
+        let e =
+            RpcError::<TransportErrorKind>::Transport(TransportErrorKind::HttpError(HttpError {
+                status,
+                body: body.to_string(),
+            }));
+        eyre::Report::new(e)
+    }
+
+    #[test]
+    fn confirmation() {
+        assert_eq!(
+            str::parse::<Confirmation>("finalized").unwrap(),
+            Confirmation::Finalized
+        );
+        let depth = str::parse::<Confirmation>("12").unwrap();
+        assert_eq!(depth, Confirmation::Depth(12));
+        assert_eq!(depth.apply_depth(100), 88);
+        assert_eq!(depth.apply_depth(10), 0);
+        assert!(str::parse::<Confirmation>("safe").is_err());
+    }
+
+    #[test]
+    fn grow_when_empty() {
+        let mut scanner = RangeScanner::new(100, 1, 8);
+        assert_eq!(scanner.next_range(1000), Some((100, 100)));
+        scanner.advance(100, 0);
+        assert_eq!(scanner.next_range(1000), Some((101, 102)));
+        scanner.advance(102, 0);
+        scanner.advance(106, 0);
+        scanner.advance(114, 0);
+        assert_eq!(scanner.next_range(1000), Some((115, 122)));
+        scanner.advance(122, 1);
+        assert_eq!(scanner.next_range(1000), Some((123, 130)));
+    }
+
+    #[test]
+    fn shrink_when_rejected() {
+        let mut scanner = RangeScanner::new(100, 4, 8);
+        assert!(scanner.reject());
+        assert_eq!(scanner.next_range(1000), Some((100, 101)));
+        assert!(scanner.reject());
+        assert_eq!(scanner.next_range(1000), Some((100, 100)));
+        assert!(!scanner.reject());
+    }
+
+    #[test]
+    fn range_errors() {
+        assert!(is_range_error(&node_error(
+            -32005,
+            "query returned more than 10000 results"
+        )));
+        assert!(is_range_error(&node_error(
+            -32602,
+            "Log response size exceeded. You can make eth_getLogs requests with up to a 2K block range"
+        )));
+        assert!(is_range_error(&node_error(
+            -32000,
+            "block range is too wide"
+        )));
+        assert!(is_range_error(&http_error(
+            400,
+            "eth_getLogs is limited to a 10,000 range"
+        )));
+
+        assert!(!is_range_error(&node_error(
+            -32005,
+            "project ID request rate exceeded"
+        )));
+        assert!(!is_range_error(&node_error(-32000, "header not found")));
+        assert!(!is_range_error(&http_error(429, "too many requests")));
+        assert!(!is_range_error(&http_error(502, "bad gateway")));
+        let timeout = TransportErrorKind::custom_str("operation timed out");
+        assert!(!is_range_error(&eyre::Report::new(timeout)));
+    }
+
+    #[test]
+    fn retry_with_backoff() {
+        let mut scanner = RangeScanner::new(100, 4, 8);
+        let delays: Vec<Duration> = std::iter::from_fn(|| scanner.retry()).collect();
+        assert_eq!(delays.len(), MAX_RETRIES as usize);
+        assert_eq!(delays[0], Duration::from_secs(1));
+        assert_eq!(delays[1], Duration::from_secs(2));
+        // the range stays
+        assert_eq!(scanner.next_range(1000), Some((100, 103)));
+
+        scanner.advance(103, 1);
+        assert_eq!(scanner.retry(), Some(Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn shrink_when_too_large() {
+        let mut scanner = RangeScanner::new(100, 8, 8);
+        scanner.advance(107, MAX_LOGS_PER_CALL + 1);
+        assert_eq!(scanner.next_range(1000), Some((108, 111)));
+    }
+
+    #[test]
+    fn stop_at_head() {
+        let mut scanner = RangeScanner::new(100, 8, 8);
+        assert_eq!(scanner.next_range(103), Some((100, 103)));
+        scanner.advance(103, 0);
+        assert_eq!(scanner.next_range(103), None);
+        assert_eq!(scanner.next_range(104), Some((104, 104)));
+        scanner.rewind(101);
+        assert_eq!(scanner.next_range(104), Some((101, 104)));
+    }
+}
@@ -1,30 +1,49 @@
-use num_bigint::{BigUint, ToBigUint};
 use num_traits::Zero;
-use serde::{Serialize, Serializer};
+use serde::Serialize;
 use serde_json::{Value, json};
 
-#[derive(Clone, Debug, Serialize)]
+use crate::error::{Error, Result};
+use crate::felt::Felt;
+
+/// Format of the JSON saved for parsed state updates.
+#[derive(Clone, Copy, Debug, Default, PartialEq, clap::ValueEnum)]
+pub enum JsonFormat {
+    /// state diff, with the details of contract classification
+    #[default]
+    StateDiff,
+    /// `STATE_UPDATE` of the Starknet JSON-RPC spec
+    StateUpdate,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
 pub struct StorageUpdate {
-    #[serde(serialize_with = "serialize_biguint")]
-    pub key: BigUint,
-    #[serde(serialize_with = "serialize_biguint")]
-    pub value: BigUint,
+    pub key: Felt,
+    pub value: Felt,
 }
 
-#[derive(Debug)]
+/// Meaning of a contract's new class hash, as determined by
+/// `ClassIndex`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ClassChange {
+    Deployed,
+    Replaced { previous_class_hash: Felt },
+}
+
+#[derive(Clone, Debug, PartialEq)]
 pub struct ContractUpdate {
-    pub address: BigUint,
+    pub address: Felt,
     pub nonce: u64,
-    pub new_class_hash: Option<BigUint>, // Some only if class updated
+    pub new_class_hash: Option<Felt>, // Some only if class updated
     pub storage_updates: Vec<StorageUpdate>,
+    /// None when the class isn't updated or contract classes aren't
+    /// tracked
+    pub class_change: Option<ClassChange>,
 }
 
-#[derive(Clone, Debug, Serialize)]
+#[derive(Clone, Debug, PartialEq, Serialize)]
 pub struct ClassDeclaration {
-    #[serde(serialize_with = "serialize_biguint")]
-    pub class_hash: BigUint,
-    #[serde(serialize_with = "serialize_biguint")]
-    pub compiled_class_hash: BigUint,
+    pub class_hash: Felt,
+    pub compiled_class_hash: Felt,
 }
 
 #[derive(Debug, Default)]
@@ -41,89 +60,238 @@ pub struct StateDiff {
     pub tail_size: usize,
 }
 
-// adapted from majin-blob
-fn serialize_biguint<S>(biguint: &BigUint, serializer: S) -> Result<S::Ok, S::Error>
-where
-    S: Serializer,
-{
-    let s = convert_biguint(biguint);
-    serializer.serialize_str(&s)
+/// JSON saved for a parsed state update.
+#[derive(Clone, Debug, PartialEq)]
+pub enum JsonOutput {
+    StateDiff,
+    StateUpdate(UpdateHeader),
+    /// state diff saved because a `STATE_UPDATE` was asked for, but
+    /// can't be made - under a distinct name, so that it isn't
+    /// mistaken for one
+    StateDiffInstead,
 }
 
-fn convert_biguint(biguint: &BigUint) -> String {
-    format!("0x{}", biguint.to_str_radix(16))
+impl JsonOutput {
+    /// Name of the file saving the JSON of an update covering
+    /// Starknet blocks `from_seq_no` to `to_seq_no`.
+    pub fn get_file_name(&self, from_seq_no: u64, to_seq_no: u64) -> String {
+        match self {
+            Self::StateDiffInstead => format!("{}-{}.state_diff.json", from_seq_no, to_seq_no),
+            _ => format!("{}-{}.json", from_seq_no, to_seq_no),
+        }
+    }
+}
+
+/// Fields of `STATE_UPDATE` not derived from the state diff, but from
+/// `LogStateUpdate` events - of the update and of the one before it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct UpdateHeader {
+    /// hash of the last Starknet block covered by the update
+    pub block_hash: Felt,
+    pub old_root: Felt,
+    pub new_root: Felt,
 }
 
 impl ContractUpdate {
     pub fn to_contract_storage_diff_item(&self) -> Value {
         json!({
-            "address": convert_biguint(&self.address),
+            "address": self.address,
             "storage_entries": self.storage_updates.clone(),
         })
     }
 
     pub fn to_deployed_contract_item(&self) -> Value {
-        let class_hash = self.new_class_hash.as_ref().map(|h| convert_biguint(h));
         json!({
-            "address": convert_biguint(&self.address),
-            "class_hash": class_hash,
+            "address": self.address,
+            "class_hash": self.new_class_hash,
+        })
+    }
+
+    pub fn to_rpc_replaced_class_item(&self) -> Value {
+        json!({
+            "contract_address": self.address,
+            "class_hash": self.new_class_hash,
+        })
+    }
+
+    pub fn to_replaced_class_item(&self, previous_class_hash: &Felt) -> Value {
+        json!({
+            "contract_address": self.address,
+            "class_hash": self.new_class_hash,
+            "previous_class_hash": previous_class_hash,
         })
     }
 
     pub fn to_nonce_item(&self) -> Value {
-        let n = self.nonce.to_biguint().unwrap();
         json!({
-            "contract_address": convert_biguint(&self.address),
-            "nonce": convert_biguint(&n),
+            "contract_address": self.address,
+            "nonce": Felt::from(self.nonce),
         })
     }
 }
 
 impl StateDiff {
-    // Not quite the "STATE_DIFF" RPC format, as 1) the source for
-    // "deprecated_declared_classes" isn't clear and 2) the
-    // distinction between "deployed_contracts" and "replaced_classes"
-    // apparently isn't represented in input data...
+    // Not quite the "STATE_DIFF" RPC format (see `to_rpc_state_diff`
+    // for that): "deprecated_declared_classes" is omitted and
+    // replaced classes include the previous class hash. Class changes
+    // `ClassIndex` hasn't classified (because processing didn't start
+    // at Starknet genesis) are listed separately, as
+    // "unclassified_class_changes".
     pub fn to_json_state_diff(&self) -> Value {
-        let storage_diffs: Vec<Value> = self
-            .contract_updates
-            .iter()
-            .filter(|cu| cu.storage_updates.len() > 0)
-            .map(|cu| cu.to_contract_storage_diff_item())
+        let mut deployed_contracts = Vec::new();
+        let mut replaced_classes = Vec::new();
+        let mut unclassified = Vec::new();
+        for cu in self.get_class_updates() {
+            match cu.class_change {
+                Some(ClassChange::Deployed) => {
+                    deployed_contracts.push(cu.to_deployed_contract_item());
+                }
+                Some(ClassChange::Replaced {
+                    previous_class_hash,
+                }) => {
+                    replaced_classes.push(cu.to_replaced_class_item(&previous_class_hash));
+                }
+                None => unclassified.push(cu.to_deployed_contract_item()),
+            }
+        }
+        let mut j = json!({
+            "storage_diffs": self.get_storage_diffs(),
+            "declared_classes": self.class_declarations.clone(),
+            "deployed_contracts": deployed_contracts,
+            "replaced_classes": replaced_classes,
+            "nonces": self.get_nonces(),
+        });
+        if !unclassified.is_empty() {
+            j["unclassified_class_changes"] = Value::Array(unclassified);
+        }
+
+        j
+    }
+
+    /// Converts a partially-parsed update for diagnostics. Its class
+    /// changes aren't classified yet, and are listed as
+    /// "updated_classes".
+    pub fn to_json_parsed(&self) -> Value {
+        let updated_classes: Vec<Value> = self
+            .get_class_updates()
+            .map(|cu| cu.to_deployed_contract_item())
             .collect();
-        let deployed_contracts: Vec<Value> = self
-            .contract_updates
+        json!({
+            "storage_diffs": self.get_storage_diffs(),
+            "declared_classes": self.class_declarations.clone(),
+            "updated_classes": updated_classes,
+            "nonces": self.get_nonces(),
+        })
+    }
+
+    /// Converts to the "STATE_DIFF" RPC format. Class declarations in
+    /// state updates are all of Sierra classes, so
+    /// "deprecated_declared_classes" is always empty. Fails if
+    /// `ClassIndex` hasn't classified a class change, which the
+    /// format has no place for.
+    pub fn to_rpc_state_diff(&self) -> Result<Value> {
+        let mut deployed_contracts = Vec::new();
+        let mut replaced_classes = Vec::new();
+        for cu in self.get_class_updates() {
+            match cu.class_change {
+                Some(ClassChange::Deployed) => {
+                    deployed_contracts.push(cu.to_deployed_contract_item());
+                }
+                Some(ClassChange::Replaced { .. }) => {
+                    replaced_classes.push(cu.to_rpc_replaced_class_item());
+                }
+                None => return Err(Error::Unclassified(cu.address)),
+            }
+        }
+        Ok(json!({
+            "storage_diffs": self.get_storage_diffs(),
+            "deprecated_declared_classes": [],
+            "declared_classes": self.class_declarations.clone(),
+            "deployed_contracts": deployed_contracts,
+            "replaced_classes": replaced_classes,
+            "nonces": self.get_nonces(),
+        }))
+    }
+
+    /// Converts to the "STATE_UPDATE" RPC format. For updates
+    /// covering more than one Starknet block, the result is the
+    /// cumulative update, comparable to `starknet_getStateUpdate`
+    /// only in its roots.
+    pub fn to_rpc_state_update(&self, header: &UpdateHeader) -> Result<Value> {
+        Ok(json!({
+            "block_hash": header.block_hash,
+            "old_root": header.old_root,
+            "new_root": header.new_root,
+            "state_diff": self.to_rpc_state_diff()?,
+        }))
+    }
+
+    pub fn to_json(&self, output: &JsonOutput) -> Result<Value> {
+        match output {
+            JsonOutput::StateDiff | JsonOutput::StateDiffInstead => Ok(self.to_json_state_diff()),
+            JsonOutput::StateUpdate(header) => self.to_rpc_state_update(header),
+        }
+    }
+
+    fn get_class_updates(&self) -> impl Iterator<Item = &ContractUpdate> {
+        self.contract_updates
             .iter()
             .filter(|cu| cu.new_class_hash.is_some())
-            .map(|cu| cu.to_deployed_contract_item())
-            .collect();
-        let nonces: Vec<Value> = self
-            .contract_updates
+    }
+
+    fn get_storage_diffs(&self) -> Vec<Value> {
+        self.contract_updates
+            .iter()
+            .filter(|cu| !cu.storage_updates.is_empty())
+            .map(|cu| cu.to_contract_storage_diff_item())
+            .collect()
+    }
+
+    fn get_nonces(&self) -> Vec<Value> {
+        self.contract_updates
             .iter()
             .filter(|cu| !cu.nonce.is_zero())
             .map(|cu| cu.to_nonce_item())
-            .collect();
-        json!({
-            "storage_diffs": storage_diffs,
-            "declared_classes": self.class_declarations.clone(),
-            "deployed_or_replaced": deployed_contracts,
-            "nonces": nonces,
-        })
+            .collect()
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use num_bigint::{BigUint, ToBigUint};
-    use num_traits::Num;
+    use serde_json::{Value, json};
+
+    use std::str::FromStr;
+
+    use super::{
+        ClassChange, ClassDeclaration, ContractUpdate, JsonOutput, StateDiff, StorageUpdate,
+        UpdateHeader,
+    };
+    use crate::error::Error;
+    use crate::felt::Felt;
 
-    use super::{ClassDeclaration, ContractUpdate, StorageUpdate};
+    // schemas of the Starknet JSON-RPC spec
+    const STATE_UPDATE_SCHEMA: &str = include_str!("../spec/state_update.json");
+
+    // The spec doesn't forbid properties it doesn't list, but output
+    // shouldn't have any.
+    fn forbid_additional_properties(schema: &mut Value) {
+        match schema {
+            Value::Object(map) => {
+                if map.contains_key("properties") {
+                    map.insert("additionalProperties".into(), Value::Bool(false));
+                }
+                map.values_mut().for_each(forbid_additional_properties);
+            }
+            Value::Array(items) => items.iter_mut().for_each(forbid_additional_properties),
+            _ => (),
+        }
+    }
 
     #[test]
     fn storage_update() {
         let su = StorageUpdate {
-            key: 1u32.to_biguint().unwrap(),
-            value: 20u32.to_biguint().unwrap(),
+            key: Felt::from(1),
+            value: Felt::from(20),
         };
         let j = serde_json::to_string(&su).unwrap();
         assert_eq!(j.to_string(), "{\"key\":\"0x1\",\"value\":\"0x14\"}");
@@ -132,13 +300,14 @@ mod tests {
     #[test]
     fn contract_update() {
         let cu = ContractUpdate {
-            address: 42u32.to_biguint().unwrap(),
+            address: Felt::from(42),
             nonce: Default::default(),
-            new_class_hash: Some(37u32.to_biguint().unwrap()),
+            new_class_hash: Some(Felt::from(37)),
             storage_updates: vec![StorageUpdate {
-                key: 1u32.to_biguint().unwrap(),
-                value: 20u32.to_biguint().unwrap(),
+                key: Felt::from(1),
+                value: Felt::from(20),
             }],
+            class_change: None,
         };
         let sdi = cu.to_contract_storage_diff_item();
         assert_eq!(
@@ -161,14 +330,12 @@ mod tests {
 
     #[test]
     fn class_declaration() {
-        let ch = BigUint::from_str_radix(
-            "36078334509b514626504edc9fb252328d1a240e4e948bef8d0c08dff45927f",
-            16,
-        )
-        .unwrap();
+        let ch =
+            Felt::from_str("0x36078334509b514626504edc9fb252328d1a240e4e948bef8d0c08dff45927f")
+                .unwrap();
         let cc = ClassDeclaration {
             class_hash: ch,
-            compiled_class_hash: 0u32.to_biguint().unwrap(),
+            compiled_class_hash: Felt::from(0),
         };
         let j = serde_json::to_string(&cc).unwrap();
         assert_eq!(
@@ -176,4 +343,143 @@ mod tests {
             "{\"class_hash\":\"0x36078334509b514626504edc9fb252328d1a240e4e948bef8d0c08dff45927f\",\"compiled_class_hash\":\"0x0\"}"
         );
     }
+
+    #[test]
+    fn rpc_state_update() {
+        let make_update =
+            |address: u64, nonce: u64, class: Option<(u64, Option<ClassChange>)>| ContractUpdate {
+                address: Felt::from(address),
+                nonce,
+                new_class_hash: class.map(|(class_hash, _)| Felt::from(class_hash)),
+                storage_updates: vec![StorageUpdate {
+                    key: Felt::from(address + 1),
+                    value: Felt::from(0),
+                }],
+                class_change: class.and_then(|(_, class_change)| class_change),
+            };
+        let replaced = ClassChange::Replaced {
+            previous_class_hash: Felt::from(99),
+        };
+        let state_diff = StateDiff {
+            contract_updates: vec![
+                make_update(1, 0, None),
+                make_update(0x10, 2, Some((0x100, Some(ClassChange::Deployed)))),
+                make_update(0x20, 0, Some((0x200, Some(replaced)))),
+                make_update(0x30, 1, Some((0x300, Some(ClassChange::Deployed)))),
+            ],
+            class_declarations: vec![ClassDeclaration {
+                class_hash: Felt::from(0x400),
+                compiled_class_hash: Felt::from(0x401),
+            }],
+            range: Default::default(),
+            tail_size: 0,
+        };
+        // felts with all 63 hex digits
+        let header = UpdateHeader {
+            block_hash: Felt::from_str(
+                "0x7d328a71faf48c5c3857e99f20a77b18522480956d1cd5bff1ff2df3c8b427b",
+            )
+            .unwrap(),
+            old_root: Felt::from_str(
+                "0x6f2ed5d3ed9f3b2bf6ee5a0e2b8da4e2e0a1b67d83b2a2f0dd8c3d1b6e5a4c9",
+            )
+            .unwrap(),
+            new_root: Felt::from_str(
+                "0x4d8a1f5e3c7b9a2d6f0e8c4b1a3d5f7e9c2b4a6d8f0e1c3b5a7d9f2e4c6b8a0",
+            )
+            .unwrap(),
+        };
+        let j = state_diff.to_rpc_state_update(&header).unwrap();
+
+        let mut schema = serde_json::from_str(STATE_UPDATE_SCHEMA).unwrap();
+        forbid_additional_properties(&mut schema);
+        let validator = jsonschema::validator_for(&schema).unwrap();
+        if let Err(err) = validator.validate(&j) {
+            panic!("{} not a valid STATE_UPDATE: {}", j, err);
+        }
+        assert_eq!(
+            j["block_hash"],
+            "0x7d328a71faf48c5c3857e99f20a77b18522480956d1cd5bff1ff2df3c8b427b"
+        );
+        assert_eq!(
+            j["old_root"],
+            "0x6f2ed5d3ed9f3b2bf6ee5a0e2b8da4e2e0a1b67d83b2a2f0dd8c3d1b6e5a4c9"
+        );
+        let diff = &j["state_diff"];
+        assert_eq!(diff["storage_diffs"].as_array().unwrap().len(), 4);
+        assert_eq!(diff["deprecated_declared_classes"], json!([]));
+        assert_eq!(
+            diff["deployed_contracts"],
+            json!([
+                {"address": "0x10", "class_hash": "0x100"},
+                {"address": "0x30", "class_hash": "0x300"},
+            ])
+        );
+        assert_eq!(
+            diff["replaced_classes"],
+            json!([{"contract_address": "0x20", "class_hash": "0x200"}])
+        );
+        assert_eq!(
+            diff["nonces"],
+            json!([
+                {"contract_address": "0x10", "nonce": "0x2"},
+                {"contract_address": "0x30", "nonce": "0x1"},
+            ])
+        );
+
+        // the schema does check felt formatting
+        let mut padded = j.clone();
+        padded["old_root"] = json!("0x0abc");
+        assert!(!validator.is_valid(&padded));
+        // and its width
+        padded["old_root"] = json!(format!("0x1{}", "0".repeat(63)));
+        assert!(!validator.is_valid(&padded));
+        // and rejects keys it doesn't define
+        let mut extended = j.clone();
+        extended["state_diff"]["deployed_or_replaced"] = json!([]);
+        assert!(!validator.is_valid(&extended));
+
+        // unclassified class changes fit nowhere
+        let mut unclassified = state_diff;
+        unclassified.contract_updates[3].class_change = None;
+        assert!(matches!(
+            unclassified.to_rpc_state_update(&header),
+            Err(Error::Unclassified(address)) if address == Felt::from(0x30)
+        ));
+    }
+
+    #[test]
+    fn state_diff_instead() {
+        let state_diff = StateDiff {
+            contract_updates: vec![ContractUpdate {
+                address: Felt::from(0x10),
+                nonce: 1,
+                new_class_hash: Some(Felt::from(0x100)),
+                storage_updates: Vec::new(),
+                class_change: Some(ClassChange::Deployed),
+            }],
+            class_declarations: Vec::new(),
+            range: Default::default(),
+            tail_size: 0,
+        };
+        let header = UpdateHeader {
+            block_hash: Felt::from(1),
+            old_root: Felt::from(2),
+            new_root: Felt::from(3),
+        };
+        let state_update = JsonOutput::StateUpdate(header);
+        assert_eq!(state_update.get_file_name(5, 7), "5-7.json");
+        assert_eq!(JsonOutput::StateDiff.get_file_name(5, 7), "5-7.json");
+
+        // without the previous root, the state diff doesn't take the
+        // place of the state update
+        let instead = JsonOutput::StateDiffInstead;
+        assert_eq!(instead.get_file_name(5, 7), "5-7.state_diff.json");
+        let j = state_diff.to_json(&instead).unwrap();
+        assert_eq!(j, state_diff.to_json_state_diff());
+        let schema = serde_json::from_str(STATE_UPDATE_SCHEMA).unwrap();
+        let validator = jsonschema::validator_for(&schema).unwrap();
+        assert!(!validator.is_valid(&j));
+        assert!(validator.is_valid(&state_diff.to_json(&state_update).unwrap()));
+    }
 }
@@ -0,0 +1,1145 @@
+use redb::{Database, ReadTransaction, TableDefinition, TableError, WriteTransaction};
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use crate::class_index::{ClassIndex, UpdatePosition, read_class_hash};
+use crate::commitment::{self, ContractLeaf, StateCommitment, read_commitment, read_storage_root};
+use crate::error::{Error, Result};
+use crate::felt::Felt;
+use crate::proof::{ContractState, StorageProof};
+use crate::state_diff::StateDiff;
+use crate::trie::{NODE_TABLE, TrieHash, prove, verify_proof};
+
+// (contract address, storage key, update position)
+type StorageKey = (
+    &'static [u8], /* Felt */
+    &'static [u8], /* Felt */
+    u64,
+    u64,
+);
+
+const STORAGE_TABLE: TableDefinition<StorageKey, &[u8] /* Felt */> =
+    TableDefinition::new("contract_storage");
+
+// (update position, contract address, storage key)
+type StorageChangeKey = (
+    u64,
+    u64,
+    &'static [u8], /* Felt */
+    &'static [u8], /* Felt */
+);
+
+// keys of STORAGE_TABLE in update order, for rollback
+const STORAGE_CHANGE_TABLE: TableDefinition<StorageChangeKey, ()> =
+    TableDefinition::new("contract_storage_change");
+
+// keyed by (contract address, update position)
+const NONCE_TABLE: TableDefinition<(&[u8] /* Felt */, u64, u64), u64> =
+    TableDefinition::new("contract_nonce");
+
+const NONCE_CHANGE_TABLE: TableDefinition<(u64, u64, &[u8] /* Felt */), ()> =
+    TableDefinition::new("contract_nonce_change");
+
+// keyed by (class hash, update position)
+const COMPILED_CLASS_TABLE: TableDefinition<(&[u8] /* Felt */, u64, u64), &[u8] /* Felt */> =
+    TableDefinition::new("compiled_class");
+
+const COMPILED_CLASS_CHANGE_TABLE: TableDefinition<(u64, u64, &[u8] /* Felt */), ()> =
+    TableDefinition::new("compiled_class_change");
+
+// Starknet blocks covered by applied updates, if known
+const UPDATE_RANGE_TABLE: TableDefinition<(u64, u64), Option<(u64, u64)>> =
+    TableDefinition::new("state_update_range");
+
+// positions of applied updates, keyed by the last Starknet block they
+// cover (when known)
+const UPDATE_BY_SEQ_NO_TABLE: TableDefinition<u64, (u64, u64)> =
+    TableDefinition::new("state_update_by_seq_no");
+
+const LAST_POSITION: UpdatePosition = (u64::MAX, u64::MAX);
+
+/// Point in the history of the state. State updates publish the
+/// cumulative changes of (potentially many) Starknet blocks, so the
+/// state is only known at their boundaries.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum StateAt {
+    /// after all processed updates
+    #[default]
+    Latest,
+    /// after the update covering the Starknet block
+    Starknet(u64),
+    /// after all updates from Ethereum blocks up to this one
+    Ethereum(u64),
+}
+
+/// Unparseable `StateAt`.
+#[derive(Debug, PartialEq, thiserror::Error)]
+pub enum ParseStateAtError {
+    #[error("expected Ethereum block number, got {0}")]
+    EthereumBlock(String),
+    #[error("expected block number or \"latest\", got {0}")]
+    StarknetBlock(String),
+}
+
+impl FromStr for StateAt {
+    type Err = ParseStateAtError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        if s == "latest" {
+            Ok(StateAt::Latest)
+        } else if let Some(tail) = s.strip_prefix("eth:") {
+            let n = str::parse::<u64>(tail)
+                .map_err(|_| ParseStateAtError::EthereumBlock(tail.to_string()))?;
+            Ok(StateAt::Ethereum(n))
+        } else {
+            let n = str::parse::<u64>(s)
+                .map_err(|_| ParseStateAtError::StarknetBlock(s.to_string()))?;
+            Ok(StateAt::Starknet(n))
+        }
+    }
+}
+
+/// Applied state update.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct UpdateRef {
+    pub position: UpdatePosition,
+    /// Starknet blocks covered by the update, if known
+    pub seq_range: Option<(u64, u64)>,
+}
+
+impl fmt::Display for UpdateRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (block_no, index) = self.position;
+        write!(f, "update at Ethereum block {} index {}", block_no, index)?;
+        match self.seq_range {
+            Some((from_seq_no, to_seq_no)) if from_seq_no == to_seq_no => {
+                write!(f, " (Starknet block {})", to_seq_no)
+            }
+            Some((from_seq_no, to_seq_no)) => {
+                write!(f, " (Starknet blocks {}-{})", from_seq_no, to_seq_no)
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+/// Value of the state, with the update that set it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Versioned<T> {
+    pub value: T,
+    pub update: UpdateRef,
+}
+
+/// Starknet state reconstructed from state updates: storage, nonce
+/// and class hash of every contract, and compiled class hashes of
+/// declared classes. Values are kept with the position of the update
+/// setting them, so that changes can be rolled back after a reorg.
+/// The state is only complete when all updates since the network's
+/// genesis have been processed (in order). Optionally, the store also
+/// maintains the state's Merkle-Patricia tries, whose roots can be
+/// checked against the global state root published with updates.
+pub struct StateStore {
+    class_index: ClassIndex,
+    commitment_enabled: bool,
+    // changes of the current update, persisted by commit
+    pending_contracts: Vec<(Felt, Option<Felt>)>,
+    pending_storage: Vec<(Felt, Felt, Felt)>,
+    pending_nonces: Vec<(Felt, u64)>,
+    pending_classes: Vec<(Felt, Felt)>,
+    applied: bool,
+    pending_range: Option<(u64, u64)>,
+    expected_root: Option<Felt>,
+    cur_position: Option<UpdatePosition>,
+    db: Arc<Database>,
+}
+
+impl StateStore {
+    pub fn new(db: Arc<Database>) -> Self {
+        Self {
+            class_index: ClassIndex::new(db.clone()),
+            commitment_enabled: false,
+            pending_contracts: Vec::new(),
+            pending_storage: Vec::new(),
+            pending_nonces: Vec::new(),
+            pending_classes: Vec::new(),
+            applied: false,
+            pending_range: None,
+            expected_root: None,
+            cur_position: None,
+            db,
+        }
+    }
+
+    /// Makes `commit` update the state's tries. Their roots are only
+    /// correct when all updates since the network's genesis have been
+    /// processed with the tries enabled.
+    pub fn enable_commitment(&mut self) {
+        self.commitment_enabled = true;
+    }
+
+    /// Returns whether processing started at the network's genesis
+    /// (which the tries require), or `None` if no update has been
+    /// processed.
+    pub fn is_from_genesis(&self) -> Result<Option<bool>> {
+        self.class_index.is_from_genesis()
+    }
+
+    /// Starts processing an update at `position`, discarding any
+    /// uncommitted changes.
+    pub fn set_position(&mut self, position: UpdatePosition) {
+        self.clear_pending();
+        self.class_index.set_position(position);
+        self.cur_position = Some(position);
+    }
+
+    /// Returns the latest value of `key` in the storage of the
+    /// contract at `address`, or `None` if no processed update has
+    /// written it (i.e. it's zero, unless updates are missing).
+    pub fn get_storage(&self, address: &Felt, key: &Felt) -> Result<Option<Felt>> {
+        let txn = self.db.begin_read()?;
+        let opt_last = read_storage(&txn, address, key, LAST_POSITION)?;
+        Ok(opt_last.map(|(_, value)| value))
+    }
+
+    /// Returns the latest nonce of the contract at `address`, or
+    /// `None` if no processed update has set it.
+    pub fn get_nonce(&self, address: &Felt) -> Result<Option<u64>> {
+        let txn = self.db.begin_read()?;
+        let opt_last = read_nonce(&txn, address, LAST_POSITION)?;
+        Ok(opt_last.map(|(_, nonce)| nonce))
+    }
+
+    /// Returns the latest class hash of the contract at `address`, or
+    /// `None` if it isn't deployed by processed updates.
+    pub fn get_class_hash(&self, address: &Felt) -> Result<Option<Felt>> {
+        self.class_index.get(address, None)
+    }
+
+    /// Returns the compiled class hash of the class declared with
+    /// `class_hash`, or `None` if it isn't declared by processed
+    /// updates.
+    pub fn get_compiled_class_hash(&self, class_hash: &Felt) -> Result<Option<Felt>> {
+        let txn = self.db.begin_read()?;
+        let opt_last = read_compiled_class_hash(&txn, class_hash, LAST_POSITION)?;
+        Ok(opt_last.map(|(_, compiled_class_hash)| compiled_class_hash))
+    }
+
+    /// Returns the state update `at` refers to (the last one included
+    /// in the state at that point), or `None` if there's none.
+    pub fn get_update_at(&self, at: StateAt) -> Result<Option<UpdateRef>> {
+        let txn = self.db.begin_read()?;
+        let before = resolve(&txn, at)?;
+        let table = match txn.open_table(UPDATE_RANGE_TABLE) {
+            Err(TableError::TableDoesNotExist(_)) => {
+                return Ok(None);
+            }
+            Err(err) => {
+                return Err(err.into());
+            }
+            Ok(table) => table,
+        };
+        match table.range(..before)?.next_back() {
+            Some(res) => {
+                let (key, value) = res?;
+                Ok(Some(UpdateRef {
+                    position: key.value(),
+                    seq_range: value.value(),
+                }))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the value of `key` in the storage of the contract at
+    /// `address`, as of `at`, with the update that wrote it.
+    pub fn get_storage_at(
+        &self,
+        address: &Felt,
+        key: &Felt,
+        at: StateAt,
+    ) -> Result<Option<Versioned<Felt>>> {
+        let txn = self.db.begin_read()?;
+        let before = resolve(&txn, at)?;
+        let opt_last = read_storage(&txn, address, key, before)?;
+        make_versioned(&txn, opt_last)
+    }
+
+    /// Returns the nonce of the contract at `address`, as of `at`,
+    /// with the update that set it.
+    pub fn get_nonce_at(&self, address: &Felt, at: StateAt) -> Result<Option<Versioned<u64>>> {
+        let txn = self.db.begin_read()?;
+        let before = resolve(&txn, at)?;
+        let opt_last = read_nonce(&txn, address, before)?;
+        make_versioned(&txn, opt_last)
+    }
+
+    /// Returns the class hash of the contract at `address`, as of
+    /// `at`, with the update that set it.
+    pub fn get_class_hash_at(
+        &self,
+        address: &Felt,
+        at: StateAt,
+    ) -> Result<Option<Versioned<Felt>>> {
+        let txn = self.db.begin_read()?;
+        let before = resolve(&txn, at)?;
+        let opt_last = read_class_hash(&txn, address, before)?;
+        make_versioned(&txn, opt_last)
+    }
+
+    /// Returns the compiled class hash of the class declared with
+    /// `class_hash`, as of `at`, with the update that declared it.
+    pub fn get_compiled_class_hash_at(
+        &self,
+        class_hash: &Felt,
+        at: StateAt,
+    ) -> Result<Option<Versioned<Felt>>> {
+        let txn = self.db.begin_read()?;
+        let before = resolve(&txn, at)?;
+        let opt_last = read_compiled_class_hash(&txn, class_hash, before)?;
+        make_versioned(&txn, opt_last)
+    }
+
+    /// Returns the roots of the state's tries as of `at`, with the
+    /// update that produced them, or `None` if no update has been
+    /// processed with the tries enabled.
+    pub fn get_commitment_at(&self, at: StateAt) -> Result<Option<Versioned<StateCommitment>>> {
+        let txn = self.db.begin_read()?;
+        let before = resolve(&txn, at)?;
+        let opt_last = read_commitment(&txn, before)?;
+        make_versioned(&txn, opt_last)
+    }
+
+    /// Returns a Merkle proof of the value of `key` in the storage of
+    /// the contract at `address`, as of `at`, with the update whose
+    /// global root it's anchored to - or `None` if no update has been
+    /// processed with the tries enabled.
+    pub fn get_storage_proof(
+        &self,
+        address: &Felt,
+        key: &Felt,
+        at: StateAt,
+    ) -> Result<Option<Versioned<StorageProof>>> {
+        let txn = self.db.begin_read()?;
+        let before = resolve(&txn, at)?;
+        let Some((position, commitment)) = read_commitment(&txn, before)? else {
+            return Ok(None);
+        };
+
+        let nodes = txn.open_table(NODE_TABLE)?;
+        let contracts_root = &commitment.contracts_root;
+        let contract_proof = prove(&nodes, contracts_root, address)?;
+        let leaf = verify_proof(TrieHash::Pedersen, contracts_root, address, &contract_proof)?;
+        let (contract_state, storage_proof) = if leaf.is_zero() {
+            (None, Vec::new())
+        } else {
+            let class_hash = read_class_hash(&txn, address, before)?;
+            let nonce = read_nonce(&txn, address, before)?;
+            let storage_root = read_storage_root(&txn, address, before)?;
+            let state = ContractState {
+                class_hash: class_hash.map(|(_, h)| h).unwrap_or_default(),
+                nonce: nonce.map(|(_, n)| n).unwrap_or_default(),
+                storage_root: storage_root.map(|(_, r)| r).unwrap_or_default(),
+            };
+            let storage_proof = prove(&nodes, &state.storage_root, key)?;
+            (Some(state), storage_proof)
+        };
+        let proof = StorageProof {
+            address: *address,
+            key: *key,
+            commitment,
+            contract_proof,
+            contract_state,
+            storage_proof,
+        };
+        make_versioned(&txn, Some((position, proof)))
+    }
+
+    /// Classifies class hash changes of `state_diff` (which must be
+    /// parsed from the update at the current position) and keeps all
+    /// its changes to be persisted by `commit`.
+    pub fn apply(&mut self, state_diff: &mut StateDiff) -> Result<()> {
+        let at_genesis = self
+            .pending_range
+            .is_some_and(|(from_seq_no, _)| from_seq_no == 0);
+        self.class_index.apply(state_diff, at_genesis)?;
+        for cu in state_diff.contract_updates.iter() {
+            self.pending_contracts.push((cu.address, cu.new_class_hash));
+            for su in cu.storage_updates.iter() {
+                self.pending_storage.push((cu.address, su.key, su.value));
+            }
+            // nonces are published for contracts whose nonce changed,
+            // and never return to zero
+            if cu.nonce != 0 {
+                self.pending_nonces.push((cu.address, cu.nonce));
+            }
+        }
+        self.applied = true;
+        for cd in state_diff.class_declarations.iter() {
+            self.pending_classes
+                .push((cd.class_hash, cd.compiled_class_hash));
+        }
+
+        Ok(())
+    }
+
+    /// Sets the Starknet blocks covered by the update at the current
+    /// position, allowing queries by Starknet block. Must precede
+    /// `apply`, which classifies class changes only when processing
+    /// started at the network's genesis.
+    pub fn set_seq_range(&mut self, seq_range: (u64, u64)) -> Result<()> {
+        if self.applied {
+            return Err(Error::Usage("update already applied".to_string()));
+        }
+
+        self.pending_range = Some(seq_range);
+        Ok(())
+    }
+
+    /// Sets the global state root published with the update at the
+    /// current position, making `commit` fail if the tries (when
+    /// enabled) don't have it.
+    pub fn set_global_root(&mut self, global_root: Felt) {
+        self.expected_root = Some(global_root);
+    }
+
+    /// Persists changes of the current update as part of `txn` -
+    /// which should also record the update's processing. Changes
+    /// already persisted (by previous processing of the same update)
+    /// are simply overwritten. Fails with `Error::RootMismatch` when
+    /// the updated tries don't have the expected global root.
+    pub fn commit(&mut self, txn: &WriteTransaction) -> Result<()> {
+        self.class_index.commit(txn)?;
+        if !std::mem::take(&mut self.applied) {
+            return Ok(());
+        }
+
+        let seq_range = self.pending_range.take();
+        if self.commitment_enabled {
+            let computed = self.update_commitment(txn)?.global_root();
+            if let Some(expected) = self.expected_root.take()
+                && computed != expected
+            {
+                return Err(Error::RootMismatch {
+                    position: self.get_cur_position()?,
+                    computed,
+                    expected,
+                });
+            }
+        }
+
+        let (block_no, index) = self.get_cur_position()?;
+        {
+            let mut ranges = txn.open_table(UPDATE_RANGE_TABLE)?;
+            ranges.insert((block_no, index), seq_range)?;
+            if let Some((_, to_seq_no)) = seq_range {
+                let mut positions = txn.open_table(UPDATE_BY_SEQ_NO_TABLE)?;
+                positions.insert(to_seq_no, (block_no, index))?;
+            }
+        }
+
+        let pending_storage = std::mem::take(&mut self.pending_storage);
+        if !pending_storage.is_empty() {
+            let mut table = txn.open_table(STORAGE_TABLE)?;
+            let mut changes = txn.open_table(STORAGE_CHANGE_TABLE)?;
+            for (address, key, value) in pending_storage.into_iter() {
+                let address = address.to_be_bytes();
+                let key = key.to_be_bytes();
+                let value = value.to_be_bytes();
+                table.insert(
+                    (address.as_slice(), key.as_slice(), block_no, index),
+                    value.as_slice(),
+                )?;
+                changes.insert((block_no, index, address.as_slice(), key.as_slice()), ())?;
+            }
+        }
+
+        let pending_nonces = std::mem::take(&mut self.pending_nonces);
+        if !pending_nonces.is_empty() {
+            let mut table = txn.open_table(NONCE_TABLE)?;
+            let mut changes = txn.open_table(NONCE_CHANGE_TABLE)?;
+            for (address, nonce) in pending_nonces.into_iter() {
+                let address = address.to_be_bytes();
+                table.insert((address.as_slice(), block_no, index), nonce)?;
+                changes.insert((block_no, index, address.as_slice()), ())?;
+            }
+        }
+
+        let pending_classes = std::mem::take(&mut self.pending_classes);
+        if !pending_classes.is_empty() {
+            let mut table = txn.open_table(COMPILED_CLASS_TABLE)?;
+            let mut changes = txn.open_table(COMPILED_CLASS_CHANGE_TABLE)?;
+            for (class_hash, compiled_class_hash) in pending_classes.into_iter() {
+                let class_hash = class_hash.to_be_bytes();
+                let value = compiled_class_hash.to_be_bytes();
+                table.insert((class_hash.as_slice(), block_no, index), value.as_slice())?;
+                changes.insert((block_no, index, class_hash.as_slice()), ())?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Removes changes of updates from Ethereum block `block_no`
+    /// onwards.
+    pub fn roll_back(&mut self, block_no: u64) -> Result<()> {
+        self.clear_pending();
+        self.class_index.roll_back(block_no)?;
+        let txn = self.db.begin_write()?;
+        let mut n = 0;
+        {
+            let mut changes = txn.open_table(STORAGE_CHANGE_TABLE)?;
+            let mut table = txn.open_table(STORAGE_TABLE)?;
+            let doomed = changes
+                .extract_from_if((block_no, 0, [].as_slice(), [].as_slice()).., |_, _| true)?;
+            for res in doomed {
+                let (key, _) = res?;
+                let (block_no, index, address, key) = key.value();
+                table.remove((address, key, block_no, index))?;
+                n += 1;
+            }
+        }
+        {
+            let mut changes = txn.open_table(NONCE_CHANGE_TABLE)?;
+            let mut table = txn.open_table(NONCE_TABLE)?;
+            let doomed = changes.extract_from_if((block_no, 0, [].as_slice()).., |_, _| true)?;
+            for res in doomed {
+                let (key, _) = res?;
+                let (block_no, index, address) = key.value();
+                table.remove((address, block_no, index))?;
+                n += 1;
+            }
+        }
+        {
+            let mut ranges = txn.open_table(UPDATE_RANGE_TABLE)?;
+            let mut positions = txn.open_table(UPDATE_BY_SEQ_NO_TABLE)?;
+            let doomed = ranges.extract_from_if((block_no, 0).., |_, _| true)?;
+            for res in doomed {
+                let (_, value) = res?;
+                if let Some((_, to_seq_no)) = value.value() {
+                    positions.remove(to_seq_no)?;
+                }
+            }
+        }
+        {
+            let mut changes = txn.open_table(COMPILED_CLASS_CHANGE_TABLE)?;
+            let mut table = txn.open_table(COMPILED_CLASS_TABLE)?;
+            let doomed = changes.extract_from_if((block_no, 0, [].as_slice()).., |_, _| true)?;
+            for res in doomed {
+                let (key, _) = res?;
+                let (block_no, index, class_hash) = key.value();
+                table.remove((class_hash, block_no, index))?;
+                n += 1;
+            }
+        }
+        n += commitment::roll_back(&txn, block_no)?;
+        txn.commit()?;
+
+        tracing::info!("{} state change(s) from block {} rolled back", n, block_no);
+        Ok(())
+    }
+
+    // updates the tries by the pending changes
+    fn update_commitment(&self, txn: &WriteTransaction) -> Result<StateCommitment> {
+        let position = self.get_cur_position()?;
+        // committed state, without the current update
+        let snapshot = self.db.begin_read()?;
+        let mut contracts = BTreeMap::new();
+        for (address, new_class_hash) in self.pending_contracts.iter() {
+            let class_hash = match new_class_hash {
+                Some(class_hash) => *class_hash,
+                None => read_class_hash(&snapshot, address, position)?
+                    .map(|(_, class_hash)| class_hash)
+                    .unwrap_or_default(),
+            };
+            let nonce = read_nonce(&snapshot, address, position)?
+                .map(|(_, nonce)| nonce)
+                .unwrap_or_default();
+            let leaf = ContractLeaf {
+                address: *address,
+                class_hash,
+                nonce,
+                storage_updates: Vec::new(),
+            };
+            contracts.insert(*address, leaf);
+        }
+        for (address, nonce) in self.pending_nonces.iter() {
+            if let Some(leaf) = contracts.get_mut(address) {
+                leaf.nonce = *nonce;
+            }
+        }
+        for (address, key, value) in self.pending_storage.iter() {
+            if let Some(leaf) = contracts.get_mut(address) {
+                leaf.storage_updates.push((*key, *value));
+            }
+        }
+        let contracts: Vec<ContractLeaf> = contracts
+            .into_values()
+            .map(|mut leaf| {
+                // the last write of a repeated key wins
+                leaf.storage_updates.reverse();
+                leaf.storage_updates.sort_by_key(|(key, _)| *key);
+                leaf.storage_updates.dedup_by_key(|(key, _)| *key);
+                leaf
+            })
+            .collect();
+
+        let mut classes = self.pending_classes.clone();
+        classes.reverse();
+        classes.sort_by_key(|(class_hash, _)| *class_hash);
+        classes.dedup_by_key(|(class_hash, _)| *class_hash);
+        commitment::update(&snapshot, txn, position, &contracts, &classes)
+    }
+
+    fn clear_pending(&mut self) {
+        self.pending_contracts.clear();
+        self.pending_storage.clear();
+        self.pending_nonces.clear();
+        self.pending_classes.clear();
+        self.applied = false;
+        self.pending_range = None;
+        self.expected_root = None;
+    }
+
+    // callers must start processing an update before applying it
+    fn get_cur_position(&self) -> Result<UpdatePosition> {
+        self.cur_position
+            .ok_or_else(|| Error::Usage("update position not set".to_string()))
+    }
+}
+
+// returns the position of the first update not included in the state
+// at `at`
+fn resolve(txn: &ReadTransaction, at: StateAt) -> Result<UpdatePosition> {
+    let seq_no = match at {
+        StateAt::Latest => {
+            return Ok(LAST_POSITION);
+        }
+        StateAt::Ethereum(block_no) => {
+            return Ok((block_no.saturating_add(1), 0));
+        }
+        StateAt::Starknet(seq_no) => seq_no,
+    };
+
+    let not_covered = || Error::BlockNotCovered(seq_no);
+    let positions = match txn.open_table(UPDATE_BY_SEQ_NO_TABLE) {
+        Err(TableError::TableDoesNotExist(_)) => {
+            return Err(not_covered());
+        }
+        Err(err) => {
+            return Err(err.into());
+        }
+        Ok(table) => table,
+    };
+    let (block_no, index) = match positions.range(seq_no..)?.next() {
+        Some(res) => {
+            let (_, value) = res?;
+            value.value()
+        }
+        None => {
+            return Err(not_covered());
+        }
+    };
+    match read_seq_range(txn, (block_no, index))? {
+        Some((from_seq_no, _)) if from_seq_no <= seq_no => Ok((block_no, index + 1)),
+        _ => Err(not_covered()),
+    }
+}
+
+fn to_felt(bytes: &[u8]) -> Result<Felt> {
+    let felt = Felt::from_be_slice(bytes).map_err(|e| redb::Error::Corrupted(e.to_string()))?;
+    Ok(felt)
+}
+
+// the read_* functions return the value set last before the update
+// at `before`, with the position of the update setting it
+
+fn read_storage(
+    txn: &ReadTransaction,
+    address: &Felt,
+    key: &Felt,
+    before: UpdatePosition,
+) -> Result<Option<(UpdatePosition, Felt)>> {
+    let table = match txn.open_table(STORAGE_TABLE) {
+        Err(TableError::TableDoesNotExist(_)) => {
+            return Ok(None);
+        }
+        Err(err) => {
+            return Err(err.into());
+        }
+        Ok(table) => table,
+    };
+    let address = address.to_be_bytes();
+    let key = key.to_be_bytes();
+    let (block_no, index) = before;
+    let start = (address.as_slice(), key.as_slice(), 0, 0);
+    let end = (address.as_slice(), key.as_slice(), block_no, index);
+    match table.range(start..end)?.next_back() {
+        Some(res) => {
+            let (key, value) = res?;
+            let (_, _, block_no, index) = key.value();
+            Ok(Some(((block_no, index), to_felt(value.value())?)))
+        }
+        None => Ok(None),
+    }
+}
+
+fn read_nonce(
+    txn: &ReadTransaction,
+    address: &Felt,
+    before: UpdatePosition,
+) -> Result<Option<(UpdatePosition, u64)>> {
+    let table = match txn.open_table(NONCE_TABLE) {
+        Err(TableError::TableDoesNotExist(_)) => {
+            return Ok(None);
+        }
+        Err(err) => {
+            return Err(err.into());
+        }
+        Ok(table) => table,
+    };
+    let address = address.to_be_bytes();
+    let (block_no, index) = before;
+    let start = (address.as_slice(), 0, 0);
+    let end = (address.as_slice(), block_no, index);
+    match table.range(start..end)?.next_back() {
+        Some(res) => {
+            let (key, value) = res?;
+            let (_, block_no, index) = key.value();
+            Ok(Some(((block_no, index), value.value())))
+        }
+        None => Ok(None),
+    }
+}
+
+fn read_compiled_class_hash(
+    txn: &ReadTransaction,
+    class_hash: &Felt,
+    before: UpdatePosition,
+) -> Result<Option<(UpdatePosition, Felt)>> {
+    let table = match txn.open_table(COMPILED_CLASS_TABLE) {
+        Err(TableError::TableDoesNotExist(_)) => {
+            return Ok(None);
+        }
+        Err(err) => {
+            return Err(err.into());
+        }
+        Ok(table) => table,
+    };
+    let class_hash = class_hash.to_be_bytes();
+    let (block_no, index) = before;
+    let start = (class_hash.as_slice(), 0, 0);
+    let end = (class_hash.as_slice(), block_no, index);
+    match table.range(start..end)?.next_back() {
+        Some(res) => {
+            let (key, value) = res?;
+            let (_, block_no, index) = key.value();
+            Ok(Some(((block_no, index), to_felt(value.value())?)))
+        }
+        None => Ok(None),
+    }
+}
+
+fn read_seq_range(txn: &ReadTransaction, position: UpdatePosition) -> Result<Option<(u64, u64)>> {
+    let table = match txn.open_table(UPDATE_RANGE_TABLE) {
+        Err(TableError::TableDoesNotExist(_)) => {
+            return Ok(None);
+        }
+        Err(err) => {
+            return Err(err.into());
+        }
+        Ok(table) => table,
+    };
+    let opt_range = table.get(position)?.and_then(|value| value.value());
+    Ok(opt_range)
+}
+
+// makes the answer of a point-in-time query
+fn make_versioned<T>(
+    txn: &ReadTransaction,
+    opt_last: Option<(UpdatePosition, T)>,
+) -> Result<Option<Versioned<T>>> {
+    match opt_last {
+        Some((position, value)) => {
+            let update = UpdateRef {
+                position,
+                seq_range: read_seq_range(txn, position)?,
+            };
+            Ok(Some(Versioned { value, update }))
+        }
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use redb::Database;
+
+    use super::{ParseStateAtError, StateAt, StateStore, UpdateRef};
+    use crate::commitment::{StateCommitment, class_leaf_hash, contract_state_hash};
+    use crate::error::{Error, Result};
+    use crate::felt::Felt;
+    use crate::state_diff::{ClassChange, ClassDeclaration, StateDiff};
+    use crate::test_util::{apply_and_commit, make_contract_update, make_state_diff, memory_db};
+    use crate::trie::{Node, TRIE_HEIGHT, TrieHash};
+
+    // updates a single contract, declaring its class (if any)
+    fn make_contract_diff(
+        address: u64,
+        nonce: u64,
+        class_hash: Option<u64>,
+        storage: &[(u64, u64)],
+    ) -> StateDiff {
+        let contract_update = make_contract_update(address, nonce, class_hash, storage);
+        let class_declarations = class_hash
+            .map(|class_hash| ClassDeclaration {
+                class_hash: Felt::from(class_hash),
+                compiled_class_hash: Felt::from(class_hash + 1),
+            })
+            .into_iter()
+            .collect();
+        make_state_diff(vec![contract_update], class_declarations)
+    }
+
+    fn try_process(
+        store: &mut StateStore,
+        db: &Database,
+        position: (u64, u64),
+        global_root: Option<Felt>,
+        diff: StateDiff,
+    ) -> Result<()> {
+        let mut state_diff = diff;
+        let apply = |store: &mut StateStore| {
+            store.set_position(position);
+            if let Some(global_root) = global_root {
+                store.set_global_root(global_root);
+            }
+            store.apply(&mut state_diff)
+        };
+        apply_and_commit(store, db, apply, StateStore::commit)
+    }
+
+    fn process(
+        store: &mut StateStore,
+        db: &Database,
+        position: (u64, u64),
+        seq_range: Option<(u64, u64)>,
+        diff: StateDiff,
+    ) {
+        let mut state_diff = diff;
+        let apply = |store: &mut StateStore| {
+            store.set_position(position);
+            if let Some(seq_range) = seq_range {
+                store.set_seq_range(seq_range)?;
+            }
+            store.apply(&mut state_diff)
+        };
+        apply_and_commit(store, db, apply, StateStore::commit).unwrap();
+    }
+
+    // root of a trie with a single leaf
+    fn single_leaf_root(hash: TrieHash, key: Felt, value: Felt) -> Felt {
+        let node = Node::Edge {
+            child: value,
+            path: key,
+            len: TRIE_HEIGHT as u8,
+        };
+        node.hash(hash)
+    }
+
+    #[test]
+    fn apply_and_roll_back() {
+        let db = memory_db();
+        let mut store = StateStore::new(db.clone());
+        let address = Felt::from(0x10);
+        assert_eq!(store.get_storage(&address, &Felt::from(1)).unwrap(), None);
+        assert_eq!(store.get_nonce(&address).unwrap(), None);
+
+        let first = make_contract_diff(0x10, 0, Some(0x100), &[(1, 11), (2, 12)]);
+        process(&mut store, &db, (10, 0), None, first);
+        let second = make_contract_diff(0x10, 1, None, &[(2, 22)]);
+        process(&mut store, &db, (10, 2), None, second);
+        assert_eq!(
+            store.get_storage(&address, &Felt::from(1)).unwrap(),
+            Some(Felt::from(11))
+        );
+        assert_eq!(
+            store.get_storage(&address, &Felt::from(2)).unwrap(),
+            Some(Felt::from(22))
+        );
+        assert_eq!(store.get_nonce(&address).unwrap(), Some(1));
+        assert_eq!(
+            store.get_class_hash(&address).unwrap(),
+            Some(Felt::from(0x100))
+        );
+        assert_eq!(
+            store.get_compiled_class_hash(&Felt::from(0x100)).unwrap(),
+            Some(Felt::from(0x101))
+        );
+
+        let third = make_contract_diff(0x10, 2, Some(0x200), &[(1, 0)]);
+        process(&mut store, &db, (11, 0), None, third);
+        assert_eq!(
+            store.get_storage(&address, &Felt::from(1)).unwrap(),
+            Some(Felt::from(0))
+        );
+        assert_eq!(
+            store.get_class_hash(&address).unwrap(),
+            Some(Felt::from(0x200))
+        );
+
+        store.roll_back(11).unwrap();
+        assert_eq!(
+            store.get_storage(&address, &Felt::from(1)).unwrap(),
+            Some(Felt::from(11))
+        );
+        assert_eq!(store.get_nonce(&address).unwrap(), Some(1));
+        assert_eq!(
+            store.get_class_hash(&address).unwrap(),
+            Some(Felt::from(0x100))
+        );
+        assert_eq!(
+            store.get_compiled_class_hash(&Felt::from(0x200)).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn genesis_range() {
+        let db = memory_db();
+        let mut store = StateStore::new(db.clone());
+        let mut state_diff = make_contract_diff(0x10, 0, Some(0x100), &[]);
+        store.set_position((10, 0));
+        store.set_seq_range((0, 0)).unwrap();
+        store.apply(&mut state_diff).unwrap();
+        assert_eq!(
+            state_diff.contract_updates[0].class_change,
+            Some(ClassChange::Deployed)
+        );
+        // too late to tell apply about genesis
+        let err = store.set_seq_range((0, 0)).unwrap_err();
+        assert!(matches!(err, Error::Usage(_)));
+    }
+
+    #[test]
+    fn query_history() {
+        let db = memory_db();
+        let mut store = StateStore::new(db.clone());
+        let address = Felt::from(0x10);
+        let key = Felt::from(1);
+        let first = make_contract_diff(0x10, 1, Some(0x100), &[(1, 11)]);
+        process(&mut store, &db, (10, 0), Some((100, 104)), first);
+        let second = make_contract_diff(0x10, 2, None, &[(1, 12)]);
+        process(&mut store, &db, (10, 1), Some((105, 105)), second);
+        // legacy update without the Starknet block range
+        let third = make_contract_diff(0x10, 3, None, &[(1, 13)]);
+        process(&mut store, &db, (12, 0), None, third);
+
+        let first_ref = UpdateRef {
+            position: (10, 0),
+            seq_range: Some((100, 104)),
+        };
+        let value = store
+            .get_storage_at(&address, &key, StateAt::Starknet(102))
+            .unwrap()
+            .unwrap();
+        assert_eq!(value.value, Felt::from(11));
+        assert_eq!(value.update, first_ref);
+        assert_eq!(
+            store.get_update_at(StateAt::Starknet(104)).unwrap(),
+            Some(first_ref)
+        );
+        let nonce = store
+            .get_nonce_at(&address, StateAt::Starknet(105))
+            .unwrap()
+            .unwrap();
+        assert_eq!(nonce.value, 2);
+        assert_eq!(
+            nonce.update.to_string(),
+            "update at Ethereum block 10 index 1 (Starknet block 105)"
+        );
+        let class_hash = store
+            .get_class_hash_at(&address, StateAt::Starknet(105))
+            .unwrap()
+            .unwrap();
+        assert_eq!(class_hash.update, first_ref);
+        assert!(matches!(
+            store.get_storage_at(&address, &key, StateAt::Starknet(99)),
+            Err(Error::BlockNotCovered(99))
+        ));
+        assert!(matches!(
+            store.get_storage_at(&address, &key, StateAt::Starknet(106)),
+            Err(Error::BlockNotCovered(106))
+        ));
+
+        let value = store
+            .get_storage_at(&address, &key, StateAt::Ethereum(11))
+            .unwrap()
+            .unwrap();
+        assert_eq!(value.value, Felt::from(12));
+        let value = store
+            .get_storage_at(&address, &key, StateAt::Latest)
+            .unwrap()
+            .unwrap();
+        assert_eq!(value.value, Felt::from(13));
+        assert_eq!(value.update.seq_range, None);
+        assert_eq!(
+            store
+                .get_storage_at(&address, &key, StateAt::Ethereum(9))
+                .unwrap(),
+            None
+        );
+
+        store.roll_back(10).unwrap();
+        assert_eq!(store.get_update_at(StateAt::Latest).unwrap(), None);
+        assert!(
+            store
+                .get_nonce_at(&address, StateAt::Starknet(102))
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn state_commitment() {
+        let db = memory_db();
+        let mut store = StateStore::new(db.clone());
+        store.enable_commitment();
+        let address = Felt::from(0x10);
+        let class_hash = Felt::from(0x100);
+        let storage_root = single_leaf_root(TrieHash::Pedersen, Felt::from(1), Felt::from(11));
+        let leaf = contract_state_hash(&class_hash, &storage_root, 1);
+        let contracts_root = single_leaf_root(TrieHash::Pedersen, address, leaf);
+        let leaf = class_leaf_hash(&Felt::from(0x101));
+        let classes_root = single_leaf_root(TrieHash::Poseidon, class_hash, leaf);
+        let first = make_contract_diff(0x10, 1, Some(0x100), &[(1, 11)]);
+        try_process(&mut store, &db, (10, 0), None, first).unwrap();
+        let commitment = store.get_commitment_at(StateAt::Latest).unwrap().unwrap();
+        assert_eq!(commitment.value.contracts_root, contracts_root);
+        assert_eq!(commitment.value.classes_root, classes_root);
+        let first_root = commitment.value.global_root();
+        assert_ne!(first_root, contracts_root);
+
+        // clearing the only storage slot empties the storage trie
+        let leaf = contract_state_hash(&class_hash, &Felt::ZERO, 2);
+        let contracts_root = single_leaf_root(TrieHash::Pedersen, address, leaf);
+        let second = make_contract_diff(0x10, 2, None, &[(1, 0)]);
+        let err = try_process(&mut store, &db, (11, 0), Some(first_root), second).unwrap_err();
+        assert!(matches!(
+            err,
+            Error::RootMismatch {
+                position: (11, 0),
+                ..
+            }
+        ));
+        // failed commit persists nothing
+        assert_eq!(
+            store.get_storage(&address, &Felt::from(1)).unwrap(),
+            Some(Felt::from(11))
+        );
+
+        let commitment = store.get_commitment_at(StateAt::Latest).unwrap().unwrap();
+        let second_root = StateCommitment {
+            contracts_root,
+            classes_root: commitment.value.classes_root,
+        }
+        .global_root();
+        let second = make_contract_diff(0x10, 2, None, &[(1, 0)]);
+        try_process(&mut store, &db, (11, 0), Some(second_root), second).unwrap();
+        let commitment = store.get_commitment_at(StateAt::Latest).unwrap().unwrap();
+        assert_eq!(commitment.value.global_root(), second_root);
+        assert_eq!(commitment.update.position, (11, 0));
+
+        store.roll_back(11).unwrap();
+        let commitment = store.get_commitment_at(StateAt::Latest).unwrap().unwrap();
+        assert_eq!(commitment.value.global_root(), first_root);
+    }
+
+    #[test]
+    fn storage_proofs() {
+        let db = memory_db();
+        let mut store = StateStore::new(db.clone());
+        store.enable_commitment();
+        let first = make_contract_diff(0x10, 1, Some(0x100), &[(1, 11), (2, 12)]);
+        process(&mut store, &db, (10, 0), Some((100, 100)), first);
+        let second = make_contract_diff(0x20, 0, Some(0x200), &[(1, 21)]);
+        process(&mut store, &db, (10, 1), Some((101, 101)), second);
+        let third = make_contract_diff(0x10, 2, None, &[(2, 13)]);
+        process(&mut store, &db, (11, 0), Some((102, 102)), third);
+
+        let address = Felt::from(0x10);
+        let cases = [
+            (0x10, 2, StateAt::Latest, 13),
+            (0x10, 2, StateAt::Starknet(101), 12),
+            (0x10, 3, StateAt::Latest, 0),
+            (0x20, 1, StateAt::Latest, 21),
+            (0x30, 1, StateAt::Latest, 0),
+        ];
+        for (address, key, at, value) in cases {
+            let versioned = store
+                .get_storage_proof(&Felt::from(address), &Felt::from(key), at)
+                .unwrap()
+                .unwrap();
+            let proof = versioned.value;
+            let commitment = store.get_commitment_at(at).unwrap().unwrap();
+            assert_eq!(versioned.update, commitment.update);
+            let global_root = commitment.value.global_root();
+            assert_eq!(proof.verify(&global_root).unwrap(), Felt::from(value));
+            assert_eq!(proof.contract_state.is_none(), address == 0x30);
+        }
+
+        let proof = store
+            .get_storage_proof(&address, &Felt::from(2), StateAt::Latest)
+            .unwrap()
+            .unwrap()
+            .value;
+        let global_root = proof.commitment.global_root();
+        let j = proof.to_rpc_storage_proof(&Felt::from(0xb10c));
+        assert_eq!(j["global_roots"]["block_hash"], "0xb10c");
+        assert_eq!(
+            j["contracts_proof"]["contract_leaves_data"][0]["nonce"],
+            "0x2"
+        );
+        assert_eq!(
+            j["contracts_storage_proofs"][0].as_array().unwrap().len(),
+            proof.storage_proof.len()
+        );
+        assert!(matches!(
+            proof.verify(&Felt::from(1)),
+            Err(Error::InvalidProof(_))
+        ));
+        let mut tampered = proof.clone();
+        if let Some(state) = tampered.contract_state.as_mut() {
+            state.nonce = 3;
+        }
+        assert!(matches!(
+            tampered.verify(&global_root),
+            Err(Error::InvalidProof(_))
+        ));
+        let mut tampered = proof.clone();
+        tampered.key = Felt::from(1);
+        assert!(tampered.verify(&global_root).is_err());
+    }
+
+    #[test]
+    fn state_at() {
+        assert_eq!(str::parse::<StateAt>("latest").unwrap(), StateAt::Latest);
+        assert_eq!(
+            str::parse::<StateAt>("eth:21000000").unwrap(),
+            StateAt::Ethereum(21000000)
+        );
+        assert_eq!(
+            str::parse::<StateAt>("1000").unwrap(),
+            StateAt::Starknet(1000)
+        );
+        assert_eq!(
+            str::parse::<StateAt>("eth:"),
+            Err(ParseStateAtError::EthereumBlock(String::new()))
+        );
+        assert_eq!(
+            str::parse::<StateAt>("earliest"),
+            Err(ParseStateAtError::StarknetBlock("earliest".to_string()))
+        );
+    }
+}
@@ -0,0 +1,88 @@
+use redb::{Database, WriteTransaction, backends::InMemoryBackend};
+
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::sync::Arc;
+
+use crate::error::Result;
+use crate::felt::Felt;
+use crate::lookup::{Lookup, START_INDEX};
+use crate::state_diff::{ClassDeclaration, ContractUpdate, StateDiff, StorageUpdate};
+
+/// Returns an empty database kept in memory.
+pub fn memory_db() -> Arc<Database> {
+    let db = Database::builder()
+        .create_with_backend(InMemoryBackend::new())
+        .unwrap();
+    Arc::new(db)
+}
+
+/// Returns an empty lookup table, processing an update from
+/// Ethereum block 10.
+pub fn make_lookup() -> Rc<RefCell<Lookup>> {
+    let mut lookup = Lookup::new(memory_db());
+    lookup.set_position((10, 0));
+    Rc::new(RefCell::new(lookup))
+}
+
+/// Returns a lookup table mapping `START_INDEX` to 0xabc (as recorded
+/// by an update from Ethereum block 10), processing an update from
+/// block 11.
+pub fn primed_lookup() -> Rc<RefCell<Lookup>> {
+    let mut lookup = Lookup::new(memory_db());
+    lookup.set_position((10, 0));
+    lookup.record(START_INDEX, &Felt::from(0xabc)).unwrap();
+    lookup.expand().unwrap();
+    lookup.commit(|_| Ok(())).unwrap();
+    lookup.set_position((11, 0));
+    Rc::new(RefCell::new(lookup))
+}
+
+pub fn make_contract_update(
+    address: u64,
+    nonce: u64,
+    class_hash: Option<u64>,
+    storage: &[(u64, u64)],
+) -> ContractUpdate {
+    let storage_updates = storage
+        .iter()
+        .map(|(key, value)| StorageUpdate {
+            key: Felt::from(*key),
+            value: Felt::from(*value),
+        })
+        .collect();
+    ContractUpdate {
+        address: Felt::from(address),
+        nonce,
+        new_class_hash: class_hash.map(Felt::from),
+        storage_updates,
+        class_change: None,
+    }
+}
+
+pub fn make_state_diff(
+    contract_updates: Vec<ContractUpdate>,
+    class_declarations: Vec<ClassDeclaration>,
+) -> StateDiff {
+    StateDiff {
+        contract_updates,
+        class_declarations,
+        range: Default::default(),
+        tail_size: 0,
+    }
+}
+
+/// Runs `apply` (which should start processing an update) on
+/// `target`, then `commit` in a write transaction of `db`.
+pub fn apply_and_commit<T>(
+    target: &mut T,
+    db: &Database,
+    apply: impl FnOnce(&mut T) -> Result<()>,
+    commit: impl FnOnce(&mut T, &WriteTransaction) -> Result<()>,
+) -> Result<()> {
+    apply(target)?;
+    let txn = db.begin_write()?;
+    commit(target, &txn)?;
+    txn.commit()?;
+    Ok(())
+}
@@ -1,104 +1,223 @@
-use num_bigint::{BigUint, ToBigUint};
+use num_bigint::BigUint;
 
 use std::str::FromStr;
 
 use crate::blob_util::FIELD_ELEMENTS_PER_BLOB;
+use crate::bls_field::Fr;
 
+/// primitive root of unity of order `FIELD_ELEMENTS_PER_BLOB`
+const GENERATOR: &str =
+    "39033254847818212395286706435128746857159659164139250548781411570340225835782";
+
+/// Converts blobs (i.e. evaluations of a polynomial on the roots of
+/// unity, in bit-reversed order) to the polynomial's coefficients,
+/// and back.
 #[derive(Clone)]
-struct TransConst {
-    pub bls_modulus: BigUint,
-    pub generator: BigUint,
-    pub two: BigUint,
+pub struct Transformer {
+    // powers of the root of unity, up to half the domain
+    twiddles: Vec<Fr>,
+    // powers of the inverse root of unity, up to half the domain
+    inv_twiddles: Vec<Fr>,
+    // inverse of the domain size
+    size_inv: Fr,
 }
 
-impl Default for TransConst {
+impl Default for Transformer {
     fn default() -> Self {
-        let bls_modulus =
-            "52435875175126190479447740508185965837690552500527637822603658699938581184513";
-        let generator =
-            "39033254847818212395286706435128746857159659164139250548781411570340225835782";
-        Self {
-            bls_modulus: BigUint::from_str(bls_modulus).unwrap(),
-            generator: BigUint::from_str(generator).unwrap(),
-            two: 2u32.to_biguint().unwrap(),
-        }
+        Self::new()
     }
 }
 
-#[derive(Clone)]
-pub struct Transformer {
-    big_const: TransConst,
-    points: Vec<BigUint>,
-}
-
 impl Transformer {
     pub fn new() -> Self {
-        let big_const = Default::default();
-        let points = (0..FIELD_ELEMENTS_PER_BLOB)
-            .map(|i| {
-                let s = i as u16;
-                let r = s.reverse_bits();
-                Self::gen_exp_mod(&big_const, r / 16)
-            })
-            .collect();
-        Self { big_const, points }
+        let generator = Fr::from_biguint(&BigUint::from_str(GENERATOR).unwrap()).unwrap();
+        let twiddles = make_powers(generator);
+        let inv_twiddles = make_powers(generator.invert());
+        let size_inv = Fr::from_u64(FIELD_ELEMENTS_PER_BLOB as u64).invert();
+        Self {
+            twiddles,
+            inv_twiddles,
+            size_inv,
+        }
     }
 
-    fn gen_exp_mod(big_const: &TransConst, exponent: u16) -> BigUint {
-        let exp = exponent.to_biguint().unwrap();
-        big_const.generator.modpow(&exp, &big_const.bls_modulus)
+    pub fn transform(&self, arr: &[BigUint]) -> Vec<BigUint> {
+        let mut vals = arr.iter().map(Fr::from_biguint_mod).collect::<Vec<_>>();
+        self.ifft(&mut vals);
+        vals.iter().map(Fr::to_biguint).collect()
     }
 
-    pub fn transform(&self, arr: &Vec<BigUint>) -> Vec<BigUint> {
-        self.ifft(arr, &self.points)
+    /// Inverse of `transform`: evaluates the polynomial with
+    /// coefficients `coeffs`, producing a blob.
+    pub fn evaluate(&self, coeffs: &[BigUint]) -> Vec<BigUint> {
+        let mut vals = coeffs.iter().map(Fr::from_biguint_mod).collect::<Vec<_>>();
+        self.fft(&mut vals);
+        vals.iter().map(Fr::to_biguint).collect()
     }
 
-    fn ifft(&self, arr: &Vec<BigUint>, xs: &Vec<BigUint>) -> Vec<BigUint> {
-        // Base case: return immediately if the array length is 1
-        if arr.len() == 1 {
-            return arr.clone();
+    /// In-place iterative (Gentleman-Sande) forward FFT of
+    /// `FIELD_ELEMENTS_PER_BLOB` elements, from natural to
+    /// bit-reversed order.
+    pub fn fft(&self, vals: &mut [Fr]) {
+        let n = vals.len();
+        assert_eq!(n, FIELD_ELEMENTS_PER_BLOB);
+
+        let mut half = n / 2;
+        while half >= 1 {
+            let stride = n / (2 * half);
+            for start in (0..n).step_by(2 * half) {
+                for j in 0..half {
+                    let u = vals[start + j];
+                    let v = vals[start + j + half];
+                    vals[start + j] = u + v;
+                    vals[start + j + half] = (u - v) * self.twiddles[j * stride];
+                }
+            }
+
+            half /= 2;
         }
+    }
 
-        let n = arr.len() / 2;
-        let mut res0 = Vec::with_capacity(n);
-        let mut res1 = Vec::with_capacity(n);
-        let mut new_xs = Vec::with_capacity(n);
-
-        for i in (0..2 * n).step_by(2) {
-            let a = &arr[i];
-            let b = &arr[i + 1];
-            let x = &xs[i];
-
-            res0.push(self.div_mod((a + b).into(), self.big_const.two.clone()));
-            // Handle subtraction to avoid underflow
-            let diff = if b > a {
-                self.big_const.bls_modulus.clone() - (b - a)
-            } else {
-                a - b
-            };
-            res1.push(self.div_mod(diff, self.big_const.two.clone() * x));
-
-            let sq: BigUint = (x * x).into();
-            new_xs.push(sq % self.big_const.bls_modulus.clone());
+    /// In-place iterative (Cooley-Tukey) inverse FFT of
+    /// `FIELD_ELEMENTS_PER_BLOB` elements. Bit-reversed input order
+    /// is what the algorithm needs anyway, and the output is in
+    /// natural order.
+    pub fn ifft(&self, vals: &mut [Fr]) {
+        let n = vals.len();
+        assert_eq!(n, FIELD_ELEMENTS_PER_BLOB);
+
+        let mut half = 1;
+        while half < n {
+            let stride = n / (2 * half);
+            for start in (0..n).step_by(2 * half) {
+                for j in 0..half {
+                    let u = vals[start + j];
+                    let v = vals[start + j + half] * self.inv_twiddles[j * stride];
+                    vals[start + j] = u + v;
+                    vals[start + j + half] = u - v;
+                }
+            }
+
+            half *= 2;
         }
 
-        // Recursive calls
-        let merged_res0 = self.ifft(&res0, &new_xs);
-        let merged_res1 = self.ifft(&res1, &new_xs);
+        for val in vals.iter_mut() {
+            *val = *val * self.size_inv;
+        }
+    }
+}
 
-        // Merging the results
-        let mut merged = Vec::with_capacity(arr.len());
-        // FIXME
-        for i in 0..n {
-            merged.push(merged_res0[i].clone());
-            merged.push(merged_res1[i].clone());
+fn make_powers(base: Fr) -> Vec<Fr> {
+    let mut powers = Vec::with_capacity(FIELD_ELEMENTS_PER_BLOB / 2);
+    let mut w = Fr::ONE;
+    for _ in 0..FIELD_ELEMENTS_PER_BLOB / 2 {
+        powers.push(w);
+        w = w * base;
+    }
+
+    powers
+}
+
+#[cfg(test)]
+mod tests {
+    use num_bigint::{BigUint, ToBigUint};
+
+    use std::str::FromStr;
+
+    use super::{GENERATOR, Transformer};
+    use crate::blob_util::FIELD_ELEMENTS_PER_BLOB;
+
+    // the original, recursive BigUint implementation, kept as a
+    // reference
+    struct RecursiveTransformer {
+        bls_modulus: BigUint,
+        two: BigUint,
+        points: Vec<BigUint>,
+    }
+
+    impl RecursiveTransformer {
+        fn new() -> Self {
+            let bls_modulus = BigUint::from_str(
+                "52435875175126190479447740508185965837690552500527637822603658699938581184513",
+            )
+            .unwrap();
+            let generator = BigUint::from_str(GENERATOR).unwrap();
+            let points = (0..FIELD_ELEMENTS_PER_BLOB)
+                .map(|i| {
+                    let s = i as u16;
+                    let r = s.reverse_bits();
+                    let exp = (r / 16).to_biguint().unwrap();
+                    generator.modpow(&exp, &bls_modulus)
+                })
+                .collect();
+            Self {
+                bls_modulus,
+                two: 2u32.to_biguint().unwrap(),
+                points,
+            }
+        }
+
+        fn ifft(&self, arr: &[BigUint], xs: &[BigUint]) -> Vec<BigUint> {
+            if arr.len() == 1 {
+                return arr.to_vec();
+            }
+
+            let n = arr.len() / 2;
+            let mut res0 = Vec::with_capacity(n);
+            let mut res1 = Vec::with_capacity(n);
+            let mut new_xs = Vec::with_capacity(n);
+            for i in (0..2 * n).step_by(2) {
+                let a = &arr[i];
+                let b = &arr[i + 1];
+                let x = &xs[i];
+                res0.push(self.div_mod(a + b, self.two.clone()));
+                let diff = if b > a {
+                    self.bls_modulus.clone() - (b - a)
+                } else {
+                    a - b
+                };
+                res1.push(self.div_mod(diff, self.two.clone() * x));
+                let sq: BigUint = x * x;
+                new_xs.push(sq % self.bls_modulus.clone());
+            }
+
+            let merged_res0 = self.ifft(&res0, &new_xs);
+            let merged_res1 = self.ifft(&res1, &new_xs);
+            let mut merged = Vec::with_capacity(arr.len());
+            for i in 0..n {
+                merged.push(merged_res0[i].clone());
+                merged.push(merged_res1[i].clone());
+            }
+            merged
+        }
+
+        fn div_mod(&self, a: BigUint, b: BigUint) -> BigUint {
+            let e = self.bls_modulus.clone() - self.two.clone();
+            let pow = b.modpow(&e, &self.bls_modulus);
+            a * pow % self.bls_modulus.clone()
         }
-        merged
     }
 
-    fn div_mod(&self, a: BigUint, b: BigUint) -> BigUint {
-        let e = self.big_const.bls_modulus.clone() - self.big_const.two.clone();
-        let pow = b.modpow(&e, &self.big_const.bls_modulus);
-        a * pow % self.big_const.bls_modulus.clone()
+    #[test]
+    fn same_as_recursive() {
+        let reference = RecursiveTransformer::new();
+        // deterministic pseudo-random (mostly full-width) elements
+        let mut state = 0x9e3779b97f4a7c15u64;
+        let arr = (0..FIELD_ELEMENTS_PER_BLOB)
+            .map(|_| {
+                let mut bytes = [0u8; 32];
+                for chunk in bytes.chunks_mut(8) {
+                    state ^= state << 13;
+                    state ^= state >> 7;
+                    state ^= state << 17;
+                    chunk.copy_from_slice(&state.to_be_bytes());
+                }
+                BigUint::from_bytes_be(&bytes) % &reference.bls_modulus
+            })
+            .collect::<Vec<_>>();
+        let expected = reference.ifft(&arr, &reference.points);
+        let transformer = Transformer::new();
+        assert_eq!(transformer.transform(&arr), expected);
+        assert_eq!(transformer.evaluate(&expected), arr);
     }
 }
@@ -0,0 +1,550 @@
+use alloy::primitives::U256;
+use redb::{ReadableTable, Table, TableDefinition};
+use starknet_crypto::{pedersen_hash, poseidon_hash};
+
+use crate::error::{Error, Result};
+use crate::felt::Felt;
+
+/// Height of Starknet's Merkle-Patricia tries, whose keys (contract
+/// addresses, storage keys and class hashes) have 251 bits.
+pub const TRIE_HEIGHT: usize = 251;
+
+// inner nodes of all tries, keyed by their hash
+pub(crate) const NODE_TABLE: TableDefinition<&[u8] /* Felt */, &[u8] /* Node */> =
+    TableDefinition::new("trie_node");
+
+const BINARY_TAG: u8 = 0;
+const EDGE_TAG: u8 = 1;
+
+/// Hash function of a trie.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TrieHash {
+    /// contracts and contract storage tries
+    Pedersen,
+    /// classes trie
+    Poseidon,
+}
+
+impl TrieHash {
+    pub fn hash(&self, a: &Felt, b: &Felt) -> Felt {
+        let a = to_core(a);
+        let b = to_core(b);
+        let h = match self {
+            TrieHash::Pedersen => pedersen_hash(&a, &b),
+            TrieHash::Poseidon => poseidon_hash(a, b),
+        };
+        from_core(h)
+    }
+}
+
+pub(crate) fn to_core(felt: &Felt) -> starknet_crypto::Felt {
+    starknet_crypto::Felt::from_bytes_be(&felt.to_be_bytes())
+}
+
+pub(crate) fn from_core(felt: starknet_crypto::Felt) -> Felt {
+    Felt::from_be_bytes(felt.to_bytes_be()).expect("field element not below the Stark prime")
+}
+
+/// Inner node of a trie.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Node {
+    Binary {
+        left: Felt,
+        right: Felt,
+    },
+    /// path of `len` bits (most significant first) from the node to
+    /// `child`
+    Edge {
+        child: Felt,
+        path: Felt,
+        len: u8,
+    },
+}
+
+impl Node {
+    pub fn hash(&self, hash: TrieHash) -> Felt {
+        match self {
+            Node::Binary { left, right } => hash.hash(left, right),
+            Node::Edge { child, path, len } => {
+                let h = to_core(&hash.hash(child, path)) + u64::from(*len);
+                from_core(h)
+            }
+        }
+    }
+
+    fn to_bytes(self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(1 + 32 + 32 + 1);
+        match self {
+            Node::Binary { left, right } => {
+                bytes.push(BINARY_TAG);
+                bytes.extend_from_slice(&left.to_be_bytes());
+                bytes.extend_from_slice(&right.to_be_bytes());
+            }
+            Node::Edge { child, path, len } => {
+                bytes.push(EDGE_TAG);
+                bytes.extend_from_slice(&child.to_be_bytes());
+                bytes.extend_from_slice(&path.to_be_bytes());
+                bytes.push(len);
+            }
+        }
+        bytes
+    }
+
+    fn from_bytes(hash: &Felt, bytes: &[u8]) -> Result<Self> {
+        let read_felt = |pos: usize| -> Result<Felt> {
+            let felt = Felt::from_be_slice(&bytes[pos..pos + 32])
+                .map_err(|e| redb::Error::Corrupted(e.to_string()))?;
+            Ok(felt)
+        };
+        match (bytes.first(), bytes.len()) {
+            (Some(&BINARY_TAG), 65) => Ok(Node::Binary {
+                left: read_felt(1)?,
+                right: read_felt(33)?,
+            }),
+            (Some(&EDGE_TAG), 66) => Ok(Node::Edge {
+                child: read_felt(1)?,
+                path: read_felt(33)?,
+                len: bytes[65],
+            }),
+            _ => {
+                let message = format!("trie node {:#x} has invalid format", hash);
+                Err(redb::Error::Corrupted(message).into())
+            }
+        }
+    }
+}
+
+/// Reads the inner node with `hash`, which must exist.
+pub(crate) fn read_node<T>(nodes: &T, hash: &Felt) -> Result<Node>
+where
+    T: ReadableTable<&'static [u8], &'static [u8]>,
+{
+    let key = hash.to_be_bytes();
+    match nodes.get(key.as_slice())? {
+        Some(value) => Node::from_bytes(hash, value.value()),
+        None => {
+            let message = format!("trie node {:#x} not found", hash);
+            Err(redb::Error::Corrupted(message).into())
+        }
+    }
+}
+
+/// Returns the inner nodes on the path from `root` towards the leaf
+/// at `key`, ending early when the leaf isn't in the trie.
+pub(crate) fn prove<T>(nodes: &T, root: &Felt, key: &Felt) -> Result<Vec<Node>>
+where
+    T: ReadableTable<&'static [u8], &'static [u8]>,
+{
+    let key = to_u256(key);
+    let mut proof = Vec::new();
+    let mut hash = *root;
+    let mut height = TRIE_HEIGHT;
+    while height > 0 && !hash.is_zero() {
+        let node = read_node(nodes, &hash)?;
+        proof.push(node);
+        match step(&node, height, &key).map_err(redb::Error::Corrupted)? {
+            Some((child, child_height)) => {
+                hash = child;
+                height = child_height;
+            }
+            None => break,
+        }
+    }
+
+    Ok(proof)
+}
+
+/// Checks that `proof` (made by `prove`) is a path from `root`
+/// towards the leaf at `key`, and returns the leaf's value - zero
+/// when the proof shows the leaf isn't in the trie.
+pub fn verify_proof(hash: TrieHash, root: &Felt, key: &Felt, proof: &[Node]) -> Result<Felt> {
+    let invalid = |message: String| Error::InvalidProof(message);
+    let key = to_u256(key);
+    let mut nodes = proof.iter();
+    let mut expected = *root;
+    let mut height = TRIE_HEIGHT;
+    while height > 0 && !expected.is_zero() {
+        let Some(node) = nodes.next() else {
+            return Err(invalid(format!("path ends at height {}", height)));
+        };
+        if node.hash(hash) != expected {
+            let message = format!("node at height {} isn't {:#x}", height, expected);
+            return Err(invalid(message));
+        }
+
+        match step(node, height, &key).map_err(invalid)? {
+            Some((child, child_height)) => {
+                expected = child;
+                height = child_height;
+            }
+            None => {
+                expected = Felt::ZERO;
+                break;
+            }
+        }
+    }
+    if nodes.next().is_some() {
+        return Err(invalid("nodes past the end of the path".to_string()));
+    }
+
+    Ok(expected)
+}
+
+// follows `node` (at `height` > 0) towards the leaf at `key`,
+// returning the child reached and its height, or `None` when the path
+// diverges from the key
+fn step(
+    node: &Node,
+    height: usize,
+    key: &U256,
+) -> std::result::Result<Option<(Felt, usize)>, String> {
+    match node {
+        Node::Binary { left, right } => {
+            let child = if key.bit(height - 1) { right } else { left };
+            Ok(Some((*child, height - 1)))
+        }
+        Node::Edge { child, path, len } => {
+            let len = *len as usize;
+            if len == 0 || len > height {
+                return Err(format!("trie edge of length {} at height {}", len, height));
+            }
+
+            let mask = (U256::from(1) << len) - U256::from(1);
+            let path = to_u256(path);
+            if path > mask {
+                return Err(format!("trie edge path longer than {} bits", len));
+            }
+
+            if (*key >> (height - len)) & mask == path {
+                Ok(Some((*child, height - len)))
+            } else {
+                Ok(None)
+            }
+        }
+    }
+}
+
+fn to_u256(felt: &Felt) -> U256 {
+    U256::from_be_bytes(felt.to_be_bytes())
+}
+
+fn from_u256(n: U256) -> Felt {
+    Felt::from_be_bytes(n.to_be_bytes()).expect("trie path too long")
+}
+
+// subtree being updated
+enum Sub {
+    Empty,
+    /// leaf value, or hash of a stored inner node
+    Node(Felt),
+    /// edge not (yet) stored
+    Edge {
+        path: U256,
+        len: usize,
+        child: Felt,
+    },
+}
+
+/// Updates tries stored in `NODE_TABLE`. Nodes are never removed -
+/// they may still be part of other tries (or of older versions of
+/// the updated one).
+pub(crate) struct TrieWriter<'a, 'txn> {
+    nodes: &'a mut Table<'txn, &'static [u8], &'static [u8]>,
+    hash: TrieHash,
+}
+
+impl<'a, 'txn> TrieWriter<'a, 'txn> {
+    pub fn new(nodes: &'a mut Table<'txn, &'static [u8], &'static [u8]>, hash: TrieHash) -> Self {
+        Self { nodes, hash }
+    }
+
+    /// Sets leaves of the trie with `root` (zero for an empty trie)
+    /// and returns its new root. `updates` must be sorted by key,
+    /// without repeated keys; zero values remove leaves.
+    pub fn update(&mut self, root: &Felt, updates: &[(Felt, Felt)]) -> Result<Felt> {
+        let updates: Vec<(U256, Felt)> = updates
+            .iter()
+            .map(|(key, value)| (to_u256(key), *value))
+            .collect();
+        let sub = self.load(root);
+        let sub = self.update_sub(sub, TRIE_HEIGHT, &updates)?;
+        self.materialize(sub)
+    }
+
+    fn update_sub(&mut self, sub: Sub, height: usize, updates: &[(U256, Felt)]) -> Result<Sub> {
+        if updates.is_empty() {
+            return Ok(sub);
+        }
+
+        if height == 0 {
+            let (_, value) = updates[updates.len() - 1];
+            return Ok(self.load(&value));
+        }
+
+        let (left, right) = self.split(sub, height)?;
+        let bit = height - 1;
+        let mid = updates.partition_point(|(key, _)| !key.bit(bit));
+        let left = self.update_sub(left, height - 1, &updates[..mid])?;
+        let right = self.update_sub(right, height - 1, &updates[mid..])?;
+        self.join(left, right, height)
+    }
+
+    fn load(&self, hash: &Felt) -> Sub {
+        if hash.is_zero() {
+            Sub::Empty
+        } else {
+            Sub::Node(*hash)
+        }
+    }
+
+    // returns the children of a subtree at `height` > 0
+    fn split(&self, sub: Sub, height: usize) -> Result<(Sub, Sub)> {
+        let (path, len, child) = match sub {
+            Sub::Empty => {
+                return Ok((Sub::Empty, Sub::Empty));
+            }
+            Sub::Node(hash) => match read_node(self.nodes, &hash)? {
+                Node::Binary { left, right } => {
+                    return Ok((self.load(&left), self.load(&right)));
+                }
+                Node::Edge { child, path, len } => (to_u256(&path), len as usize, child),
+            },
+            Sub::Edge { path, len, child } => (path, len, child),
+        };
+        if len == 0 || len > height {
+            let message = format!("trie edge of length {} at height {}", len, height);
+            return Err(redb::Error::Corrupted(message).into());
+        }
+
+        let rest = if len == 1 {
+            self.load(&child)
+        } else {
+            Sub::Edge {
+                path: path & ((U256::from(1) << (len - 1)) - U256::from(1)),
+                len: len - 1,
+                child,
+            }
+        };
+        if path.bit(len - 1) {
+            Ok((Sub::Empty, rest))
+        } else {
+            Ok((rest, Sub::Empty))
+        }
+    }
+
+    // makes a subtree at `height` > 0 from its children
+    fn join(&mut self, left: Sub, right: Sub, height: usize) -> Result<Sub> {
+        match (left, right) {
+            (Sub::Empty, Sub::Empty) => Ok(Sub::Empty),
+            (sub, Sub::Empty) => self.extend(sub, false, height - 1),
+            (Sub::Empty, sub) => self.extend(sub, true, height - 1),
+            (left, right) => {
+                let left = self.materialize(left)?;
+                let right = self.materialize(right)?;
+                let hash = self.store(Node::Binary { left, right })?;
+                Ok(Sub::Node(hash))
+            }
+        }
+    }
+
+    // prepends `bit` to the path leading to `sub` (at `height`)
+    fn extend(&self, sub: Sub, bit: bool, height: usize) -> Result<Sub> {
+        let (path, len, child) = match sub {
+            Sub::Empty => unreachable!("extending empty subtree"),
+            Sub::Edge { path, len, child } => (path, len, child),
+            Sub::Node(hash) if height > 0 => match read_node(self.nodes, &hash)? {
+                Node::Edge { child, path, len } => (to_u256(&path), len as usize, child),
+                Node::Binary { .. } => (U256::ZERO, 0, hash),
+            },
+            Sub::Node(hash) => (U256::ZERO, 0, hash),
+        };
+        let path = if bit {
+            path | (U256::from(1) << len)
+        } else {
+            path
+        };
+        Ok(Sub::Edge {
+            path,
+            len: len + 1,
+            child,
+        })
+    }
+
+    fn materialize(&mut self, sub: Sub) -> Result<Felt> {
+        match sub {
+            Sub::Empty => Ok(Felt::ZERO),
+            Sub::Node(hash) => Ok(hash),
+            Sub::Edge { path, len, child } => self.store(Node::Edge {
+                child,
+                path: from_u256(path),
+                len: len as u8,
+            }),
+        }
+    }
+
+    fn store(&mut self, node: Node) -> Result<Felt> {
+        let hash = node.hash(self.hash);
+        let key = hash.to_be_bytes();
+        self.nodes
+            .insert(key.as_slice(), node.to_bytes().as_slice())?;
+        Ok(hash)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::{NODE_TABLE, Node, TRIE_HEIGHT, TrieHash, TrieWriter, prove, verify_proof};
+    use crate::felt::Felt;
+    use crate::test_util::memory_db;
+
+    fn edge(hash: TrieHash, child: Felt, path: Felt, len: u8) -> Felt {
+        Node::Edge { child, path, len }.hash(hash)
+    }
+
+    #[test]
+    fn small_tries() {
+        let db = memory_db();
+        let txn = db.begin_write().unwrap();
+        let mut nodes = txn.open_table(NODE_TABLE).unwrap();
+        let mut writer = TrieWriter::new(&mut nodes, TrieHash::Pedersen);
+        let hash = TrieHash::Pedersen;
+
+        let one = writer
+            .update(&Felt::ZERO, &[(Felt::from(5), Felt::from(7))])
+            .unwrap();
+        let expected = edge(hash, Felt::from(7), Felt::from(5), TRIE_HEIGHT as u8);
+        assert_eq!(one, expected);
+
+        // keys 4 and 5 differ in the last bit
+        let two = writer
+            .update(&one, &[(Felt::from(4), Felt::from(9))])
+            .unwrap();
+        let binary = hash.hash(&Felt::from(9), &Felt::from(7));
+        let expected = edge(hash, binary, Felt::from(2), TRIE_HEIGHT as u8 - 1);
+        assert_eq!(two, expected);
+
+        let removed = writer.update(&two, &[(Felt::from(4), Felt::ZERO)]).unwrap();
+        assert_eq!(removed, one);
+        let empty = writer.update(&one, &[(Felt::from(5), Felt::ZERO)]).unwrap();
+        assert_eq!(empty, Felt::ZERO);
+    }
+
+    // root of the trie with `leaves`, computed from scratch
+    fn compute_root(hash: TrieHash, leaves: &BTreeMap<Felt, Felt>) -> Felt {
+        fn subtree(
+            hash: TrieHash,
+            leaves: &[(Felt, Felt)],
+            height: usize,
+        ) -> Option<(Felt, u64, u8)> {
+            if leaves.is_empty() {
+                return None;
+            }
+
+            if height == 0 {
+                return Some((leaves[0].1, 0, 0));
+            }
+
+            let bit = height - 1;
+            let mid = leaves.partition_point(|(key, _)| !key.to_biguint().bit(bit as u64));
+            let left = subtree(hash, &leaves[..mid], height - 1);
+            let right = subtree(hash, &leaves[mid..], height - 1);
+            let finish = |(child, path, len): (Felt, u64, u8)| {
+                if len == 0 {
+                    child
+                } else {
+                    edge(hash, child, Felt::from(path), len)
+                }
+            };
+            match (left, right) {
+                (Some((child, path, len)), None) => Some((child, path, len + 1)),
+                (None, Some((child, path, len))) => Some((child, path | (1 << len), len + 1)),
+                (Some(left), Some(right)) => Some((hash.hash(&finish(left), &finish(right)), 0, 0)),
+                (None, None) => None,
+            }
+        }
+
+        let leaves: Vec<(Felt, Felt)> = leaves
+            .iter()
+            .filter(|(_, value)| !value.is_zero())
+            .map(|(key, value)| (*key, *value))
+            .collect();
+        match subtree(hash, &leaves, TRIE_HEIGHT) {
+            Some((child, path, len)) if len > 0 => edge(hash, child, Felt::from(path), len),
+            Some((child, _, _)) => child,
+            None => Felt::ZERO,
+        }
+    }
+
+    #[test]
+    fn incremental_updates() {
+        let db = memory_db();
+        let txn = db.begin_write().unwrap();
+        let mut nodes = txn.open_table(NODE_TABLE).unwrap();
+        let hash = TrieHash::Poseidon;
+        let mut writer = TrieWriter::new(&mut nodes, hash);
+
+        // keys below 2^64, so that compute_root can track paths in u64
+        let batches: Vec<Vec<(u64, u64)>> = vec![
+            vec![(1, 10), (2, 20), (3, 30), (1 << 40, 40)],
+            vec![(2, 0), (7, 70), (1 << 63, 80)],
+            vec![(1, 11), (3, 0), (1 << 40, 0), (1 << 41, 90)],
+            vec![(1, 0), (7, 0), (1 << 41, 0), (1 << 63, 0)],
+        ];
+        let mut root = Felt::ZERO;
+        let mut leaves = BTreeMap::new();
+        for batch in batches {
+            let updates: Vec<(Felt, Felt)> = batch
+                .into_iter()
+                .map(|(key, value)| (Felt::from(key), Felt::from(value)))
+                .collect();
+            leaves.extend(updates.iter().cloned());
+            root = writer.update(&root, &updates).unwrap();
+            assert_eq!(root, compute_root(hash, &leaves));
+        }
+        assert_eq!(root, Felt::ZERO);
+    }
+
+    #[test]
+    fn proofs() {
+        let db = memory_db();
+        let txn = db.begin_write().unwrap();
+        let mut nodes = txn.open_table(NODE_TABLE).unwrap();
+        let hash = TrieHash::Pedersen;
+        let mut writer = TrieWriter::new(&mut nodes, hash);
+        let updates: Vec<(Felt, Felt)> = [(1, 10), (2, 20), (3, 30), (1 << 40, 40)]
+            .into_iter()
+            .map(|(key, value)| (Felt::from(key), Felt::from(value)))
+            .collect();
+        let root = writer.update(&Felt::ZERO, &updates).unwrap();
+
+        for (key, value) in updates.iter() {
+            let proof = prove(&nodes, &root, key).unwrap();
+            assert_eq!(verify_proof(hash, &root, key, &proof).unwrap(), *value);
+        }
+        // absent keys, diverging from an edge and below a binary node
+        for key in [Felt::from(1 << 20), Felt::from(0)] {
+            let proof = prove(&nodes, &root, &key).unwrap();
+            assert!(!proof.is_empty());
+            assert_eq!(verify_proof(hash, &root, &key, &proof).unwrap(), Felt::ZERO);
+        }
+        let proof = prove(&nodes, &Felt::ZERO, &Felt::from(1)).unwrap();
+        assert!(proof.is_empty());
+
+        let key = Felt::from(2);
+        let proof = prove(&nodes, &root, &key).unwrap();
+        assert!(verify_proof(TrieHash::Poseidon, &root, &key, &proof).is_err());
+        assert!(verify_proof(hash, &root, &key, &proof[..proof.len() - 1]).is_err());
+        let mut tampered = proof.clone();
+        // keys 2 and 3 differ in the last bit
+        let Some(Node::Binary { left, .. }) = tampered.last_mut() else {
+            panic!("unexpected proof {:?}", proof);
+        };
+        *left = Felt::from(21);
+        assert!(verify_proof(hash, &root, &key, &tampered).is_err());
+        let mut longer = proof.clone();
+        longer.push(proof[0]);
+        assert!(verify_proof(hash, &root, &key, &longer).is_err());
+    }
+}